@@ -0,0 +1,153 @@
+//! How the listings of a type differ between two repositories.
+
+use crate::{FileInfo, KnownFiles};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// How the files of one type differ between the source and the destination.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Comparison {
+    /// Files in the source that the destination lacks.
+    pub missing: Vec<FileInfo>,
+    /// Files in both, with the size of the source and the destination copy.
+    pub mismatched: Vec<(FileInfo, u64)>,
+    /// Files in the destination that are not in the source.
+    pub extra: Vec<FileInfo>,
+    /// Files present with the same size in both.
+    pub matching: Vec<FileInfo>,
+}
+
+impl Comparison {
+    /// Drops the files whose names do not satisfy `keep`, from both sides.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.missing.retain(|file| keep(&file.name));
+        self.mismatched.retain(|(file, _)| keep(&file.name));
+        self.extra.retain(|file| keep(&file.name));
+        self.matching.retain(|file| keep(&file.name));
+    }
+
+    /// Drops the files whose sizes do not satisfy `keep`, from both sides.
+    /// Mismatched files go by their size in the source.
+    pub fn retain_sizes(&mut self, keep: impl Fn(u64) -> bool) {
+        self.missing.retain(|file| keep(file.size));
+        self.mismatched.retain(|(file, _)| keep(file.size));
+        self.extra.retain(|file| keep(file.size));
+        self.matching.retain(|file| keep(file.size));
+    }
+}
+
+/// Compares the listings of a type in the source and the destination. The
+/// missing, mismatched and extra files come sorted by name.
+pub fn compare(source: Vec<FileInfo>, dest: Vec<FileInfo>) -> Comparison {
+    let source_map: HashMap<String, u64> = source
+        .into_iter()
+        .map(|item| (item.name, item.size))
+        .collect();
+    let dest_map: HashMap<String, u64> = dest
+        .into_iter()
+        .map(|item| (item.name, item.size))
+        .collect();
+
+    let mut comparison = Comparison::default();
+    for (name, &size) in &source_map {
+        let info = FileInfo {
+            name: name.clone(),
+            size,
+        };
+        match dest_map.get(name) {
+            Some(&dest_size) if dest_size != size => comparison.mismatched.push((info, dest_size)),
+            Some(_) => comparison.matching.push(info),
+            None => comparison.missing.push(info),
+        }
+    }
+    for (name, &size) in &dest_map {
+        if !source_map.contains_key(name) {
+            comparison.extra.push(FileInfo {
+                name: name.clone(),
+                size,
+            });
+        }
+    }
+    comparison.missing.sort_by(|a, b| a.name.cmp(&b.name));
+    comparison
+        .mismatched
+        .sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    comparison.extra.sort_by(|a, b| a.name.cmp(&b.name));
+    comparison
+}
+
+/// Compares the listing of a type in the source with what the destination
+/// is known to have, as [`compare`] does, without a second listing in
+/// memory.
+pub fn compare_known(source: Vec<FileInfo>, dest: &KnownFiles) -> Comparison {
+    let mut seen = vec![false; dest.len()];
+    let mut comparison = Comparison::default();
+    for file in source {
+        let Some(i) = dest.position(&file.name) else {
+            comparison.missing.push(file);
+            continue;
+        };
+        seen[i] = true;
+        match dest.size_at(i) {
+            dest_size if dest_size != file.size => comparison.mismatched.push((file, dest_size)),
+            _ => comparison.matching.push(file),
+        }
+    }
+    comparison.extra = (0..dest.len())
+        .filter(|&i| !seen[i])
+        .map(|i| dest.at(i))
+        .collect();
+    comparison.missing.sort_by(|a, b| a.name.cmp(&b.name));
+    comparison
+        .mismatched
+        .sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    comparison.extra.sort_by(|a, b| a.name.cmp(&b.name));
+    comparison
+}
+
+/// How far the destination is behind the source.
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Divergence {
+    pub types: Vec<TypeDivergence>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TypeDivergence {
+    pub file_type: &'static str,
+    /// Files missing from the destination or with another size there, and
+    /// their size in the source.
+    pub behind_files: u64,
+    pub behind_bytes: u64,
+    /// Files only in the destination.
+    pub extra_files: u64,
+    pub extra_bytes: u64,
+}
+
+impl Divergence {
+    pub fn behind_files(&self) -> u64 {
+        self.types.iter().map(|t| t.behind_files).sum()
+    }
+
+    pub fn behind_bytes(&self) -> u64 {
+        self.types.iter().map(|t| t.behind_bytes).sum()
+    }
+
+    pub fn extra_files(&self) -> u64 {
+        self.types.iter().map(|t| t.extra_files).sum()
+    }
+}
+
+/// How far the destination is behind for `file_type`, as `comparison` finds.
+pub fn divergence(file_type: &'static str, comparison: &Comparison) -> TypeDivergence {
+    let behind = comparison
+        .missing
+        .iter()
+        .chain(comparison.mismatched.iter().map(|(file, _)| file));
+    TypeDivergence {
+        file_type,
+        behind_files: behind.clone().count() as u64,
+        behind_bytes: behind.map(|file| file.size).sum(),
+        extra_files: comparison.extra.len() as u64,
+        extra_bytes: comparison.extra.iter().map(|file| file.size).sum(),
+    }
+}
@@ -0,0 +1,202 @@
+//! A compact set of the files of one type that a repository is known to
+//! have, for listings of millions of packs.
+//!
+//! Restic names its files after the SHA-256 of their contents, so each one
+//! is kept as the 32 bytes of its ID and its size, sorted by ID: 40 bytes a
+//! file, where a listing takes a string and a hash map entry for each. The
+//! few names that are not IDs are kept as they are.
+
+use crate::FileInfo;
+
+const ID_LEN: usize = 32;
+
+type Id = [u8; ID_LEN];
+
+/// The files of one type that a repository is known to have.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KnownFiles {
+    /// Files named by their ID, sorted by it.
+    ids: Vec<(Id, u64)>,
+    /// Files with other names, sorted by name.
+    others: Vec<FileInfo>,
+}
+
+impl KnownFiles {
+    pub fn len(&self) -> usize {
+        self.ids.len() + self.others.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The size of `name`, if it is known.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.position(name).map(|i| self.size_at(i))
+    }
+
+    /// Adds `name`, or changes its size.
+    pub fn insert(&mut self, name: &str, size: u64) {
+        match parse_id(name) {
+            Some(id) => match self.ids.binary_search_by(|(known, _)| known.cmp(&id)) {
+                Ok(i) => self.ids[i].1 = size,
+                Err(i) => self.ids.insert(i, (id, size)),
+            },
+            None => match self
+                .others
+                .binary_search_by(|file| file.name.as_str().cmp(name))
+            {
+                Ok(i) => self.others[i].size = size,
+                Err(i) => self.others.insert(
+                    i,
+                    FileInfo {
+                        name: name.to_string(),
+                        size,
+                    },
+                ),
+            },
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        match self.position(name) {
+            Some(i) if i < self.ids.len() => {
+                self.ids.remove(i);
+            }
+            Some(i) => {
+                self.others.remove(i - self.ids.len());
+            }
+            None => {}
+        }
+    }
+
+    /// The files, as a listing would have them.
+    pub fn iter(&self) -> impl Iterator<Item = FileInfo> + '_ {
+        self.ids
+            .iter()
+            .map(|(id, size)| FileInfo {
+                name: format_id(id),
+                size: *size,
+            })
+            .chain(self.others.iter().cloned())
+    }
+
+    /// The set in the form [`KnownFiles::from_bytes`] reads: the number of
+    /// IDs, each ID with its size, then each other name with its length and
+    /// its size, all numbers little-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.ids.len() * (ID_LEN + 8));
+        bytes.extend_from_slice(&(self.ids.len() as u64).to_le_bytes());
+        for (id, size) in &self.ids {
+            bytes.extend_from_slice(id);
+            bytes.extend_from_slice(&size.to_le_bytes());
+        }
+        for file in &self.others {
+            bytes.extend_from_slice(&(file.name.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(file.name.as_bytes());
+            bytes.extend_from_slice(&file.size.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reads what [`KnownFiles::to_bytes`] wrote, or `None` if `bytes` are
+    /// something else.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut rest = bytes;
+        let count = u64::from_le_bytes(take(&mut rest, 8)?.try_into().ok()?);
+        let mut known = KnownFiles::default();
+        for _ in 0..count {
+            let id = take(&mut rest, ID_LEN)?.try_into().ok()?;
+            let size = u64::from_le_bytes(take(&mut rest, 8)?.try_into().ok()?);
+            known.ids.push((id, size));
+        }
+        while !rest.is_empty() {
+            let len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().ok()?);
+            let name = std::str::from_utf8(take(&mut rest, len as usize)?).ok()?;
+            let size = u64::from_le_bytes(take(&mut rest, 8)?.try_into().ok()?);
+            known.others.push(FileInfo {
+                name: name.to_string(),
+                size,
+            });
+        }
+        let sorted = known.ids.is_sorted_by(|a, b| a.0 < b.0)
+            && known.others.is_sorted_by(|a, b| a.name < b.name);
+        sorted.then_some(known)
+    }
+
+    /// Where `name` is, counting the IDs first and then the other names.
+    pub(crate) fn position(&self, name: &str) -> Option<usize> {
+        match parse_id(name) {
+            Some(id) => self.ids.binary_search_by(|(known, _)| known.cmp(&id)).ok(),
+            None => self
+                .others
+                .binary_search_by(|file| file.name.as_str().cmp(name))
+                .ok()
+                .map(|i| self.ids.len() + i),
+        }
+    }
+
+    pub(crate) fn size_at(&self, position: usize) -> u64 {
+        match self.ids.get(position) {
+            Some((_, size)) => *size,
+            None => self.others[position - self.ids.len()].size,
+        }
+    }
+
+    /// The file at `position`, as [`KnownFiles::position`] counts.
+    pub(crate) fn at(&self, position: usize) -> FileInfo {
+        match self.ids.get(position) {
+            Some((id, size)) => FileInfo {
+                name: format_id(id),
+                size: *size,
+            },
+            None => self.others[position - self.ids.len()].clone(),
+        }
+    }
+}
+
+impl FromIterator<FileInfo> for KnownFiles {
+    fn from_iter<I: IntoIterator<Item = FileInfo>>(files: I) -> Self {
+        let mut known = KnownFiles::default();
+        for file in files {
+            match parse_id(&file.name) {
+                Some(id) => known.ids.push((id, file.size)),
+                None => known.others.push(file),
+            }
+        }
+        known.ids.sort_by_key(|(id, _)| *id);
+        known.ids.dedup_by(|a, b| a.0 == b.0);
+        known.others.sort_by(|a, b| a.name.cmp(&b.name));
+        known.others.dedup_by(|a, b| a.name == b.name);
+        known
+    }
+}
+
+/// The first `len` bytes of `rest`, which is left with the others.
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (taken, left) = rest.split_at_checked(len)?;
+    *rest = left;
+    Some(taken)
+}
+
+/// The ID that `name` spells in lowercase hex, as restic writes them.
+fn parse_id(name: &str) -> Option<Id> {
+    let digit = |c: u8| match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    };
+    let hex = name.as_bytes();
+    if hex.len() != ID_LEN * 2 {
+        return None;
+    }
+    let mut id = [0; ID_LEN];
+    for (byte, pair) in id.iter_mut().zip(hex.chunks_exact(2)) {
+        *byte = digit(pair[0])? << 4 | digit(pair[1])?;
+    }
+    Some(id)
+}
+
+fn format_id(id: &Id) -> String {
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
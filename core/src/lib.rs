@@ -0,0 +1,69 @@
+//! Compares the listings of Restic repositories and plans the syncs between
+//! them, without any I/O.
+//!
+//! `restic-sync` lists both repositories over HTTP and leaves the rest to
+//! this crate: [`parse_listing`] reads what a REST server answers for a
+//! type, [`compare`] tells which files the destination lacks, has with
+//! another size, or has on its own, and [`plan`] and [`divergence`] turn that
+//! into what a sync would do or how far a mirror is behind. [`KnownFiles`]
+//! keeps a listing in 40 bytes a file, for [`compare_known`]. Nothing here
+//! needs a network, a file system or an async runtime, so the crate also
+//! builds for `wasm32-unknown-unknown`, e.g. for a web dashboard that fetches
+//! the listings in the browser:
+//!
+//! ```
+//! use restic_sync_core::{compare, divergence, parse_listing};
+//!
+//! let source = parse_listing(r#"[{"name": "1c27", "size": 512}, {"name": "8e0d", "size": 128}]"#)?;
+//! let mirror = parse_listing(r#"[{"name": "1c27", "size": 512}]"#)?;
+//! let behind = divergence("snapshots", &compare(source, mirror));
+//! assert_eq!((behind.behind_files, behind.behind_bytes), (1, 128));
+//! # Ok::<(), serde_json::Error>(())
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+mod compare;
+mod known;
+mod plan;
+
+pub use compare::{Comparison, Divergence, TypeDivergence, compare, compare_known, divergence};
+pub use known::KnownFiles;
+pub use plan::{FileAction, PlannedAction, PlannedFile, SyncPlan, TypePlan, plan};
+
+/// The version of the JSON formats of `restic-sync schema`, which serialized
+/// plans carry.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The directories of a repository that hold files named by their hash, in
+/// the order they are synced.
+pub const FILE_TYPES: &[&str] = &["data", "keys", "locks", "snapshots", "index"];
+
+/// A file of a repository listing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Reads the listing of a type, as REST servers answer with version 2 of
+/// the API (`Accept: application/vnd.x.restic.rest.v2`).
+pub fn parse_listing(json: &str) -> serde_json::Result<Vec<FileInfo>> {
+    serde_json::from_str(json)
+}
+
+/// `bytes` in binary units, e.g. "1.5 GiB".
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
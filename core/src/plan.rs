@@ -0,0 +1,195 @@
+//! What a sync is going to do, worked out before doing it.
+//!
+//! [`plan`] turns the [`Comparison`] of a type into the uploads and
+//! deletions of a [`TypePlan`], and a [`SyncPlan`] holds those of every
+//! type. Plans serialize with serde, so they can be shown, stored or
+//! approved before a sync applies them. Serialized plans carry the
+//! [`FORMAT_VERSION`], as described by `restic-sync schema plan`, and newer
+//! ones are refused.
+
+use crate::{Comparison, FORMAT_VERSION, FileInfo, format_bytes};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What a sync does with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileAction {
+    /// Copies the file from the source to the destination.
+    Upload,
+    /// Deletes the file from the destination, with `--prune`.
+    Delete,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "PlanFile", try_from = "PlanFile")]
+pub struct SyncPlan {
+    /// Whether the config file is copied, or compared with the one the
+    /// destination has.
+    pub config: bool,
+    /// The directories, in the order they are synced.
+    pub types: Vec<TypePlan>,
+}
+
+impl SyncPlan {
+    pub fn upload_files(&self) -> u64 {
+        self.types.iter().map(|t| t.uploads.len() as u64).sum()
+    }
+
+    pub fn upload_bytes(&self) -> u64 {
+        self.types
+            .iter()
+            .flat_map(|t| &t.uploads)
+            .map(|file| file.size)
+            .sum()
+    }
+
+    pub fn delete_files(&self) -> u64 {
+        self.types.iter().map(|t| t.deletes.len() as u64).sum()
+    }
+
+    /// Whether executing the plan would change nothing.
+    pub fn is_empty(&self) -> bool {
+        self.types
+            .iter()
+            .all(|t| t.uploads.is_empty() && t.deletes.is_empty())
+    }
+}
+
+impl fmt::Display for SyncPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files to upload ({}), {} files to delete",
+            self.upload_files(),
+            format_bytes(self.upload_bytes()),
+            self.delete_files()
+        )
+    }
+}
+
+/// A [`SyncPlan`] as it is serialized.
+#[derive(Serialize, Deserialize)]
+struct PlanFile {
+    version: u32,
+    config: bool,
+    types: Vec<TypePlan>,
+}
+
+impl From<SyncPlan> for PlanFile {
+    fn from(plan: SyncPlan) -> Self {
+        PlanFile {
+            version: FORMAT_VERSION,
+            config: plan.config,
+            types: plan.types,
+        }
+    }
+}
+
+impl TryFrom<PlanFile> for SyncPlan {
+    type Error = String;
+
+    fn try_from(file: PlanFile) -> Result<Self, Self::Error> {
+        if file.version > FORMAT_VERSION {
+            return Err(format!(
+                "the plan has format version {}, newer than the {} this version of restic-sync reads",
+                file.version, FORMAT_VERSION
+            ));
+        }
+        Ok(SyncPlan {
+            config: file.config,
+            types: file.types,
+        })
+    }
+}
+
+/// The files of one type to upload and delete.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypePlan {
+    pub file_type: String,
+    /// Files the destination lacks or has with another size.
+    pub uploads: Vec<PlannedFile>,
+    /// Files only the destination has, with `--prune`; sized as there.
+    pub deletes: Vec<PlannedFile>,
+    /// Files an append-only destination has with another size, which are
+    /// left alone and reported as errors.
+    pub kept: Vec<PlannedFile>,
+    /// Files the destination has already, and their size.
+    pub unchanged_files: u64,
+    pub unchanged_bytes: u64,
+}
+
+impl TypePlan {
+    /// The uploads, then the deletions.
+    pub fn into_actions(self) -> impl Iterator<Item = PlannedAction> {
+        let file_type = self.file_type;
+        let uploads = self
+            .uploads
+            .into_iter()
+            .map(|file| (FileAction::Upload, file));
+        let deletes = self
+            .deletes
+            .into_iter()
+            .map(|file| (FileAction::Delete, file));
+        uploads
+            .chain(deletes)
+            .map(move |(action, file)| PlannedAction {
+                action,
+                file_type: file_type.clone(),
+                name: file.name,
+                size: file.size,
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedFile {
+    pub name: String,
+    pub size: u64,
+}
+
+/// An upload or deletion of a plan, one at a time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlannedAction {
+    pub action: FileAction,
+    pub file_type: String,
+    pub name: String,
+    pub size: u64,
+}
+
+/// The files of `comparison` that a sync uploads to and deletes from the
+/// destination, and those it has to keep although they differ.
+pub fn plan(file_type: &str, comparison: Comparison, prune: bool, append_only: bool) -> TypePlan {
+    let mut uploads = comparison.missing;
+    let mut kept = Vec::new();
+    for (info, dest_size) in comparison.mismatched {
+        if append_only {
+            warn!(
+                "[{}] {} has size {} in the destination but {} in the source; cannot overwrite in append-only mode",
+                file_type, info.name, dest_size, info.size
+            );
+            kept.push(info);
+            continue;
+        }
+        uploads.push(info);
+    }
+    let deletes = if prune { comparison.extra } else { Vec::new() };
+    let planned = |files: Vec<FileInfo>| {
+        files
+            .into_iter()
+            .map(|file| PlannedFile {
+                name: file.name,
+                size: file.size,
+            })
+            .collect()
+    };
+    TypePlan {
+        file_type: file_type.to_string(),
+        unchanged_files: comparison.matching.len() as u64,
+        unchanged_bytes: comparison.matching.iter().map(|file| file.size).sum(),
+        uploads: planned(uploads),
+        deletes: planned(deletes),
+        kept: planned(kept),
+    }
+}
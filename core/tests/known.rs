@@ -0,0 +1,53 @@
+//! The compact sets of files a destination is known to have.
+
+use restic_sync_core::{FileInfo, KnownFiles, compare, compare_known};
+
+fn file(name: &str, size: u64) -> FileInfo {
+    FileInfo {
+        name: name.to_string(),
+        size,
+    }
+}
+
+fn id(byte: u8) -> String {
+    format!("{:02x}", byte).repeat(32)
+}
+
+#[test]
+fn known_files_survive_their_bytes() {
+    let mut known: KnownFiles = [file(&id(2), 20), file(&id(1), 10), file("config", 5)]
+        .into_iter()
+        .collect();
+    known.insert(&id(3), 30);
+    known.insert("not-an-id", 7);
+    known.remove(&id(2));
+
+    let read = KnownFiles::from_bytes(&known.to_bytes()).unwrap();
+
+    assert_eq!(read, known);
+    assert_eq!(read.get(&id(1)), Some(10));
+    assert_eq!(read.get(&id(2)), None);
+    assert_eq!(read.get("config"), Some(5));
+    assert_eq!(
+        read.iter().collect::<Vec<_>>(),
+        [
+            file(&id(1), 10),
+            file(&id(3), 30),
+            file("config", 5),
+            file("not-an-id", 7)
+        ]
+    );
+    assert_eq!(KnownFiles::from_bytes(&known.to_bytes()[..20]), None);
+}
+
+#[test]
+fn comparing_with_known_files_is_comparing_listings() {
+    let source = vec![file(&id(1), 10), file(&id(2), 20), file("lock", 1)];
+    let dest = vec![file(&id(2), 21), file(&id(3), 30), file("lock", 1)];
+
+    let known = compare_known(source.clone(), &dest.iter().cloned().collect());
+
+    let mut listed = compare(source, dest);
+    listed.matching.sort_by(|a, b| a.name.cmp(&b.name));
+    assert_eq!(known, listed);
+}
@@ -0,0 +1,44 @@
+//! Plans worked out from listings alone.
+
+use restic_sync_core::{FileInfo, PlannedFile, compare, plan};
+
+fn listing(files: &[(&str, u64)]) -> Vec<FileInfo> {
+    files
+        .iter()
+        .map(|&(name, size)| FileInfo {
+            name: name.to_string(),
+            size,
+        })
+        .collect()
+}
+
+fn planned(name: &str, size: u64) -> PlannedFile {
+    PlannedFile {
+        name: name.to_string(),
+        size,
+    }
+}
+
+#[test]
+fn plan_uploads_what_differs_and_prunes_what_is_extra() {
+    let source = listing(&[("aa", 1), ("bb", 2), ("cc", 3)]);
+    let dest = listing(&[("bb", 2), ("cc", 4), ("dd", 5)]);
+
+    let data = plan("data", compare(source, dest), true, false);
+
+    assert_eq!(data.uploads, [planned("aa", 1), planned("cc", 3)]);
+    assert_eq!(data.deletes, [planned("dd", 5)]);
+    assert_eq!((data.unchanged_files, data.unchanged_bytes), (1, 2));
+}
+
+#[test]
+fn append_only_destinations_keep_what_differs() {
+    let source = listing(&[("aa", 1), ("cc", 3)]);
+    let dest = listing(&[("cc", 4), ("dd", 5)]);
+
+    let data = plan("data", compare(source, dest), false, true);
+
+    assert_eq!(data.uploads, [planned("aa", 1)]);
+    assert_eq!(data.kept, [planned("cc", 3)]);
+    assert!(data.deletes.is_empty());
+}
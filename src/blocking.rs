@@ -0,0 +1,56 @@
+//! Running syncs from code that is not async.
+//!
+//! The functions here start a Tokio runtime of their own and block until
+//! the sync is done, so programs and scripts can use the library without
+//! depending on Tokio. Like `reqwest::blocking`, they panic when called
+//! from within an async runtime; async code calls [`crate::SyncClient`]
+//! directly.
+
+use crate::{SyncPlan, SyncReport};
+use anyhow::{Context, Result};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+/// Runs each of `jobs` once, or on its `--cron` schedule until Ctrl-C is
+/// pressed, as [`crate::sync`] does.
+pub fn sync(jobs: Vec<crate::SyncClient>) -> Result<()> {
+    runtime()?.block_on(crate::sync(jobs))
+}
+
+/// A [`crate::SyncClient`] together with the runtime its runs block on.
+#[derive(Debug, Clone)]
+pub struct SyncClient {
+    client: crate::SyncClient,
+    runtime: Arc<Runtime>,
+}
+
+impl SyncClient {
+    pub fn new(client: crate::SyncClient) -> Result<Self> {
+        Ok(SyncClient {
+            client,
+            runtime: Arc::new(runtime()?),
+        })
+    }
+
+    /// Runs a single sync, as [`crate::SyncClient::run`].
+    pub fn run(&self) -> Result<SyncReport> {
+        self.runtime.block_on(self.client.run())
+    }
+
+    /// Works out what a run would do, as [`crate::SyncClient::plan`].
+    pub fn plan(&self) -> Result<SyncPlan> {
+        self.runtime.block_on(self.client.plan())
+    }
+
+    /// Applies `plan`, as [`crate::SyncClient::execute`].
+    pub fn execute(&self, plan: &SyncPlan) -> Result<SyncReport> {
+        self.runtime.block_on(self.client.execute(plan))
+    }
+}
+
+fn runtime() -> Result<Runtime> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start the async runtime")
+}
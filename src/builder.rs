@@ -0,0 +1,327 @@
+//! Building sync jobs in code.
+//!
+//! [`SyncJobBuilder`] sets the options that a job of the config file would,
+//! with typed values, and checks the combinations that the command line
+//! rejects once [`SyncJobBuilder::build`] is called.
+
+use crate::{SyncClient, SyncOptions, Verification};
+use anyhow::{Result, anyhow, bail};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Builds a [`SyncClient`] from the repository URLs and whichever options
+/// differ from those of `restic-sync sync`.
+///
+/// ```no_run
+/// use restic_sync::SyncJobBuilder;
+///
+/// # async fn example() -> anyhow::Result<()> {
+/// let client = SyncJobBuilder::new("https://backup.example.com/main/", "https://mirror.example.com/main/")
+///     .source_basic_auth("sync", "secret")
+///     .dest_token("mirror-token")
+///     .exclude_types(["locks"])
+///     .prune(true)
+///     .post_hook("systemctl start backup-report")
+///     .build()?;
+/// client.run().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SyncJobBuilder {
+    options: SyncOptions,
+    name: Option<String>,
+    only_types: Vec<String>,
+    exclude_types: Vec<String>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl SyncJobBuilder {
+    /// Mirrors `source` to `dest`, with every other option at its default.
+    pub fn new(source: &str, dest: &str) -> Self {
+        SyncJobBuilder {
+            options: SyncOptions::new(source, dest),
+            name: None,
+            only_types: Vec::new(),
+            exclude_types: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    /// Names the job in log messages, as the jobs of a config file are.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Logs in to the source with a user name and password, instead of any
+    /// token.
+    pub fn source_basic_auth(mut self, user: &str, password: &str) -> Self {
+        let source = &mut self.options.connection.source_opts;
+        source.user = Some(user.to_string());
+        source.password = Some(password.to_string().into());
+        source.token = None;
+        self
+    }
+
+    /// Logs in to the destination with a user name and password, instead of
+    /// any token.
+    pub fn dest_basic_auth(mut self, user: &str, password: &str) -> Self {
+        let dest = &mut self.options.connection.dest_opts;
+        dest.user = Some(user.to_string());
+        dest.password = Some(password.to_string().into());
+        dest.token = None;
+        self
+    }
+
+    /// Sends `token` to the source as a bearer token.
+    pub fn source_token(mut self, token: &str) -> Self {
+        let source = &mut self.options.connection.source_opts;
+        source.token = Some(token.to_string().into());
+        source.user = None;
+        source.password = None;
+        self
+    }
+
+    /// Sends `token` to the destination as a bearer token.
+    pub fn dest_token(mut self, token: &str) -> Self {
+        let dest = &mut self.options.connection.dest_opts;
+        dest.token = Some(token.to_string().into());
+        dest.user = None;
+        dest.password = None;
+        self
+    }
+
+    /// Only syncs these of `config`, `data`, `keys`, `locks`, `snapshots`
+    /// and `index`, as `--only-types`.
+    pub fn only_types<I: IntoIterator<Item = S>, S: Into<String>>(mut self, types: I) -> Self {
+        self.only_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Leaves these file types alone, as `--exclude-types`.
+    pub fn exclude_types<I: IntoIterator<Item = S>, S: Into<String>>(mut self, types: I) -> Self {
+        self.exclude_types = types.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Only syncs the files matching `pattern`, as `--include`.
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(pattern.to_string());
+        self
+    }
+
+    /// Leaves the files matching `pattern` alone, as `--exclude`.
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// How many of the jobs given to [`crate::sync`] with this one first run
+    /// at the same time, as `--parallel-jobs`.
+    pub fn parallel_jobs(mut self, jobs: u32) -> Self {
+        self.options.parallel_jobs = jobs;
+        self
+    }
+
+    /// Deletes destination files that the source does not have, as `--prune`.
+    pub fn prune(mut self, prune: bool) -> Self {
+        self.options.prune = prune;
+        self
+    }
+
+    /// Never deletes or overwrites destination files, as `--dest-append-only`.
+    pub fn dest_append_only(mut self, append_only: bool) -> Self {
+        self.options.dest_append_only = append_only;
+        self
+    }
+
+    /// Only reports how far the destination is behind, as `--monitor`.
+    pub fn monitor(mut self, monitor: bool) -> Self {
+        self.options.monitor = monitor;
+        self
+    }
+
+    /// Fails if the destination repository does not exist instead of
+    /// creating it, as `--no-create-dest`.
+    pub fn no_create_dest(mut self, no_create_dest: bool) -> Self {
+        self.options.no_create_dest = no_create_dest;
+        self
+    }
+
+    /// Checks every copied file with `verification`, as `--verification`.
+    pub fn verification(mut self, verification: Verification) -> Self {
+        self.options = self.options.with_verification(verification);
+        self
+    }
+
+    /// Checks the copied files of `file_type` with `verification`, as
+    /// `--type-verification`.
+    pub fn type_verification(mut self, file_type: &str, verification: Verification) -> Self {
+        self.options = self.options.with_type_verification(file_type, verification);
+        self
+    }
+
+    /// Runs `command` in a shell before each sync, which is aborted if it
+    /// fails, as `--pre-hook`.
+    pub fn pre_hook(mut self, command: &str) -> Self {
+        self.options.pre_hook = Some(command.to_string());
+        self
+    }
+
+    /// Runs `command` after each sync, as `--post-hook`.
+    pub fn post_hook(mut self, command: &str) -> Self {
+        self.options.post_hook = Some(command.to_string());
+        self
+    }
+
+    /// Runs `command` after each failed sync, as `--failure-hook`.
+    pub fn failure_hook(mut self, command: &str) -> Self {
+        self.options.failure_hook = Some(command.to_string());
+        self
+    }
+
+    /// Records run results in `path` for `restic-sync healthcheck`, as
+    /// `--status-file`.
+    pub fn status_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.status_file = Some(path.into());
+        self
+    }
+
+    /// Keeps state between runs in `dir`, as `--state-dir`.
+    pub fn state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.state_dir = Some(dir.into());
+        self
+    }
+
+    /// Lists the destination once and then trusts the listing kept in the
+    /// state directory, as `--cache-dest-listing`.
+    pub fn cache_dest_listing(mut self, cache: bool) -> Self {
+        self.options.cache_dest_listing = cache;
+        self
+    }
+
+    /// Checks the cached destination listing against a fresh one after
+    /// `runs` runs used it, as `--reconcile-every`.
+    pub fn reconcile_every(mut self, runs: u32) -> Self {
+        self.options.reconcile_every = Some(runs);
+        self
+    }
+
+    /// Checks the cached destination listing against a fresh one once it is
+    /// older than `age`, as `--reconcile-after`.
+    pub fn reconcile_after(mut self, age: Duration) -> Self {
+        self.options.reconcile_after = Some(age);
+        self
+    }
+
+    /// Checks the cached destination listing against a fresh one in every
+    /// run, as `--reconcile`.
+    pub fn reconcile(mut self, reconcile: bool) -> Self {
+        self.options.reconcile = reconcile;
+        self
+    }
+
+    /// Continues an interrupted run from its journal in the state directory,
+    /// as `--resume`.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.options.resume = resume;
+        self
+    }
+
+    /// Skips the data files while the source snapshots and index stay the
+    /// same, as `--fast-path`.
+    pub fn fast_path(mut self, fast_path: bool) -> Self {
+        self.options.fast_path = fast_path;
+        self
+    }
+
+    /// Writes the outcome of each run to `path` in the Prometheus text
+    /// format, as `--metrics-file`.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options.metrics_file = Some(path.into());
+        self
+    }
+
+    /// Runs the sync on this schedule when given to [`crate::sync`], as
+    /// `--cron`.
+    #[cfg(feature = "cron")]
+    pub fn cron(mut self, schedule: &str) -> Self {
+        self.options.cron = Some(schedule.to_string());
+        self
+    }
+
+    /// The options as they were set, for [`SyncClient::new`] or to inspect.
+    pub fn options(self) -> Result<SyncOptions> {
+        let mut options = self.options;
+        if options.prune && options.dest_append_only {
+            bail!("A job cannot prune an append-only destination");
+        }
+        if options.monitor && (options.prune || options.no_create_dest) {
+            bail!("A monitoring job cannot prune, nor require the destination to exist");
+        }
+        let stateful = options.cache_dest_listing || options.resume || options.fast_path;
+        if stateful && (options.monitor || options.state_dir.is_none()) {
+            bail!("Caching listings, resuming and the fast path need a state directory, and no monitoring");
+        }
+        let reconciles = options.reconcile || options.reconcile_every.is_some() || options.reconcile_after.is_some();
+        if reconciles && !options.cache_dest_listing {
+            bail!("Only a cached destination listing can be checked against a fresh one");
+        }
+        if options.reconcile_every == Some(0) {
+            bail!("A cached listing cannot be checked every 0 runs");
+        }
+        if options.parallel_jobs == 0 {
+            bail!("At least one job must run at a time");
+        }
+        options
+            .filter
+            .set_types(&self.only_types, &self.exclude_types)
+            .map_err(|e| anyhow!("Invalid file types: {e}"))?;
+        for pattern in &self.include {
+            options
+                .filter
+                .add_include(pattern)
+                .map_err(|e| anyhow!("Invalid include pattern `{pattern}`: {e}"))?;
+        }
+        for pattern in &self.exclude {
+            options
+                .filter
+                .add_exclude(pattern)
+                .map_err(|e| anyhow!("Invalid exclude pattern `{pattern}`: {e}"))?;
+        }
+        Ok(options)
+    }
+
+    /// Checks the options and sets up the job.
+    pub fn build(self) -> Result<SyncClient> {
+        let name = self.name.clone();
+        let client = SyncClient::new(self.options()?)?;
+        Ok(match name {
+            Some(name) => client.with_name(name),
+            None => client,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debugging_a_job_does_not_show_its_credentials() {
+        let builder = SyncJobBuilder::new("https://backup.example/main/", "https://mirror.example/main/")
+            .source_basic_auth("sync", "source-hunter2")
+            .dest_token("dest-hunter2");
+        let options = builder.clone().options().unwrap();
+        let client = builder.build().unwrap();
+
+        for debug in [format!("{options:?}"), format!("{client:?}")] {
+            assert!(!debug.contains("hunter2"), "{debug}");
+            assert!(debug.contains("***"), "{debug}");
+        }
+    }
+}
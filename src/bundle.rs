@@ -0,0 +1,210 @@
+//! Bundles that carry a sync between networks that no host reaches both
+//! of, for `plan export` and `plan apply`.
+//!
+//! A bundle is a directory laid out like a repository: the `config`, and
+//! each file that the destination lacks as `<type>/<name>`, with
+//! `plan.json`, the plan they are for, written last so that an export that
+//! did not finish is never applied. Applying a bundle is a sync from it, as
+//! the [`Transport`] of the source, that executes its plan.
+//!
+//! What the destination already has comes from a [`Listing`] written where
+//! it is reachable, with `plan listing`; without one, the export plans to
+//! copy everything.
+
+use crate::{FILE_TYPES, FileInfo, SyncPlan, Transport, schema};
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use reqwest::header::{CONTENT_TYPE, HeaderValue};
+use reqwest::{Method, Request, Response, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const PLAN_FILE: &str = "plan.json";
+
+/// A bundle directory.
+#[derive(Debug, Clone)]
+pub struct Bundle {
+    dir: PathBuf,
+    /// The URL of the bundle as the source of a sync, `http://bundle`
+    /// followed by `dir`.
+    url: Url,
+}
+
+impl Bundle {
+    /// The bundle in `dir`, which need not exist yet.
+    pub fn new(dir: &Path) -> Result<Self> {
+        let invalid = || format!("Invalid bundle directory {}", dir.display());
+        let dir = std::path::absolute(dir).with_context(invalid)?;
+        let Ok(path) = Url::from_directory_path(&dir) else {
+            bail!(invalid());
+        };
+        // The HTTP client only sends to web URLs, even through a transport.
+        let url = Url::parse(&format!("http://bundle{}", path.path())).with_context(invalid)?;
+        Ok(Bundle { dir, url })
+    }
+
+    /// The URL of the bundle as the source of a sync, which its transport
+    /// answers.
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// Starts an export into the bundle, forgetting the plan of an earlier
+    /// one but keeping its files for the new plan to reuse.
+    pub fn start(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create bundle directory {}", self.dir.display()))?;
+        match std::fs::remove_file(self.dir.join(PLAN_FILE)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e)
+                .with_context(|| format!("Failed to remove the plan in {}", self.dir.display())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the bundle has the file at `path` with `size` bytes, from an
+    /// earlier export.
+    pub fn has(&self, path: &str, size: u64) -> bool {
+        std::fs::metadata(self.dir.join(path)).is_ok_and(|file| file.len() == size)
+    }
+
+    /// Stores `bytes` as the file at `path`, whole or not at all.
+    pub fn write(&self, path: &str, bytes: &[u8]) -> Result<()> {
+        let target = self.dir.join(path);
+        let dir = target.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(dir)?;
+        // Hidden, so that it is not listed until it is complete.
+        let partial = dir.join(format!(
+            ".{}.partial",
+            target.file_name().unwrap_or_default().display()
+        ));
+        std::fs::write(&partial, bytes)
+            .and_then(|()| std::fs::rename(&partial, &target))
+            .with_context(|| {
+                format!(
+                    "Failed to write {} to the bundle in {}",
+                    path,
+                    self.dir.display()
+                )
+            })
+    }
+
+    /// Stores the plan, which completes the export.
+    pub fn write_plan(&self, plan: &SyncPlan) -> Result<()> {
+        self.write(PLAN_FILE, serde_json::to_string_pretty(plan)?.as_bytes())
+    }
+
+    pub fn read_plan(&self) -> Result<SyncPlan> {
+        let path = self.dir.join(PLAN_FILE);
+        let plan = std::fs::read(&path).with_context(|| {
+            format!(
+                "No plan in {}; the export into it did not finish",
+                self.dir.display()
+            )
+        })?;
+        serde_json::from_slice(&plan).with_context(|| format!("Invalid plan in {}", path.display()))
+    }
+
+    /// The listing of a type directory, as a REST server answers it.
+    fn list(&self, dir: &Path) -> Result<Vec<FileInfo>> {
+        let mut files = Vec::new();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(files),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') && entry.file_type()?.is_file() {
+                files.push(FileInfo {
+                    name,
+                    size: entry.metadata()?.len(),
+                });
+            }
+        }
+        Ok(files)
+    }
+}
+
+fn respond(status: StatusCode, body: Vec<u8>) -> Response {
+    let mut response = http::Response::new(body);
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/vnd.x.restic.rest.v2"),
+    );
+    response.into()
+}
+
+/// Answers the reads of a sync from the bundle, which is read-only.
+#[async_trait]
+impl Transport for Bundle {
+    async fn send(&self, request: Request) -> Result<Response> {
+        if request.method() != Method::GET {
+            return Ok(respond(StatusCode::METHOD_NOT_ALLOWED, Vec::new()));
+        }
+        let Some(relative) = request.url().path().strip_prefix(self.url.path()) else {
+            return Ok(respond(StatusCode::NOT_FOUND, Vec::new()));
+        };
+        let file_type = relative.split('/').next().unwrap_or_default();
+        if relative != "config" && !FILE_TYPES.contains(&file_type) {
+            return Ok(respond(StatusCode::NOT_FOUND, Vec::new()));
+        }
+        if relative.ends_with('/') {
+            let files = self.list(&self.dir.join(file_type))?;
+            return Ok(respond(StatusCode::OK, serde_json::to_vec(&files)?));
+        }
+        match std::fs::read(self.dir.join(relative)) {
+            Ok(bytes) => Ok(respond(StatusCode::OK, bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(respond(StatusCode::NOT_FOUND, Vec::new()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// What a destination has, by type, for `plan export` to leave out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Listing {
+    version: u32,
+    types: BTreeMap<String, Vec<FileInfo>>,
+}
+
+impl Default for Listing {
+    fn default() -> Self {
+        Listing {
+            version: schema::VERSION,
+            types: BTreeMap::new(),
+        }
+    }
+}
+
+impl Listing {
+    pub fn add(&mut self, file_type: &str, files: Vec<FileInfo>) {
+        self.types.insert(file_type.to_string(), files);
+    }
+
+    /// The files of `file_type`, none if the listing does not have the type.
+    pub fn files(&self, file_type: &str) -> Vec<FileInfo> {
+        self.types.get(file_type).cloned().unwrap_or_default()
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let listing = std::fs::read(path).with_context(|| {
+            format!("Failed to read the destination listing {}", path.display())
+        })?;
+        let listing: Listing = serde_json::from_slice(&listing)
+            .with_context(|| format!("Invalid destination listing {}", path.display()))?;
+        if listing.version > schema::VERSION {
+            bail!(
+                "The destination listing {} has format version {}, newer than the {} this version of restic-sync reads",
+                path.display(),
+                listing.version,
+                schema::VERSION
+            );
+        }
+        Ok(listing)
+    }
+}
@@ -0,0 +1,202 @@
+//! Circuit breaker for endpoints that keep failing.
+//!
+//! After a number of consecutive failed requests (connection errors or 5xx
+//! responses) the circuit opens and requests to that endpoint fail
+//! immediately for a cool-down window. The first request after the window is
+//! let through as a probe: if it succeeds the circuit closes again, otherwise
+//! another window starts. The state is kept for the lifetime of the process,
+//! so scheduled runs during the window fail fast instead of retrying against
+//! a server that is down.
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use http::Extensions;
+use log::{info, warn};
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe request is in flight after the cool-down. Until it is
+    /// answered, every other request is refused.
+    HalfOpen,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Arc::new(Mutex::new(State::Closed { failures: 0 })),
+        }
+    }
+
+    /// Decides whether a request may be sent, returning how long the circuit
+    /// stays open otherwise. The request is the probe if it comes with a
+    /// [`Probe`].
+    fn admit(&self) -> Result<Option<Probe<'_>>, Duration> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(None),
+            State::Open { until } => {
+                let now = Instant::now();
+                if now < until {
+                    Err(until - now)
+                } else {
+                    *state = State::HalfOpen;
+                    Ok(Some(Probe(self)))
+                }
+            }
+            State::HalfOpen => Err(Duration::ZERO),
+        }
+    }
+
+    fn record(&self, host: &str, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state = match (&*state, failed) {
+            (State::HalfOpen, false) => {
+                info!("{host} is responding again; closing circuit");
+                State::Closed { failures: 0 }
+            }
+            (_, false) => State::Closed { failures: 0 },
+            (State::HalfOpen, true) => {
+                warn!(
+                    "{host} is still failing; keeping circuit open for {}",
+                    humantime::format_duration(self.cooldown)
+                );
+                self.open()
+            }
+            (State::Closed { failures }, true) if failures + 1 >= self.threshold => {
+                warn!(
+                    "{host} failed {} times in a row; opening circuit for {}",
+                    failures + 1,
+                    humantime::format_duration(self.cooldown)
+                );
+                self.open()
+            }
+            (State::Closed { failures }, true) => State::Closed {
+                failures: failures + 1,
+            },
+            (State::Open { until }, true) => State::Open { until: *until },
+        };
+    }
+
+    fn open(&self) -> State {
+        State::Open {
+            until: Instant::now() + self.cooldown,
+        }
+    }
+}
+
+/// The probe request in flight. If it is dropped before its answer is
+/// recorded, e.g. because the run was cancelled, the circuit goes back to
+/// open with its cool-down over, so that the next request probes instead.
+struct Probe<'a>(&'a CircuitBreaker);
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        if let State::HalfOpen = *state {
+            *state = State::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for CircuitBreaker {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let host = req.url().host_str().unwrap_or_default().to_string();
+        let probe = match self.admit() {
+            Ok(probe) => probe,
+            Err(remaining) => {
+                return Err(reqwest_middleware::Error::Middleware(anyhow!(
+                    "Circuit breaker is open for {host} after repeated failures; next attempt in {}",
+                    humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+                )));
+            }
+        };
+
+        let res = next.run(req, extensions).await;
+        let failed = match &res {
+            Ok(resp) => resp.status().is_server_error(),
+            Err(_) => true,
+        };
+        self.record(&host, failed);
+        drop(probe);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::{InjectedTransport, Transport};
+    use reqwest::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Fails the first request, leaves the second unanswered and answers
+    /// the rest.
+    #[derive(Default)]
+    struct Flaky(AtomicUsize);
+
+    #[async_trait]
+    impl Transport for Flaky {
+        async fn send(&self, _request: Request) -> anyhow::Result<Response> {
+            let status = match self.0.fetch_add(1, Ordering::Relaxed) {
+                0 => StatusCode::INTERNAL_SERVER_ERROR,
+                1 => std::future::pending().await,
+                _ => StatusCode::OK,
+            };
+            Ok(http::Response::builder()
+                .status(status)
+                .body(Vec::new())?
+                .into())
+        }
+    }
+
+    #[tokio::test]
+    async fn a_dropped_probe_lets_the_next_request_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        let client = reqwest_middleware::ClientBuilder::new(reqwest::Client::new())
+            .with(breaker.clone())
+            .with(InjectedTransport(Arc::new(Flaky::default())))
+            .build();
+        let get = || client.get("http://backup.example/config").send();
+
+        let first = get().await.unwrap();
+        assert_eq!(first.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(matches!(*breaker.state.lock().unwrap(), State::Open { .. }));
+
+        let probe = tokio::time::timeout(Duration::from_millis(50), get()).await;
+        assert!(probe.is_err(), "the probe should have been left unanswered");
+
+        let next = get().await.unwrap();
+        assert_eq!(next.status(), StatusCode::OK);
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            State::Closed { failures: 0 }
+        ));
+    }
+}
@@ -0,0 +1,1367 @@
+//! The one-off subcommands that inspect or maintain repositories without
+//! running a full sync.
+
+use crate::bundle::{Bundle, Listing};
+use crate::crypto;
+use crate::endpoint::{self, EndpointOptions, OtherArgs};
+#[cfg(feature = "notifications")]
+use crate::notify::Notifiers;
+use crate::redact::{Redacted, redact_url};
+use crate::repo::{InjectedTransport, Repo};
+use crate::snapshot::{self, Index};
+use crate::state::{History, HistoryQuery, RunRecord, State, Watermark};
+use crate::filter::FileFilter;
+use crate::{
+    CancellationToken, ConnectionArgs, Error, FILE_TYPES, PairArgs, PlannedFile, RepoPair, Side, SyncClient, SyncOptions,
+    SyncPlan, compare_type, delete_file, download, format_bytes, init_dest, list_files, request_id, sha256_hex,
+    sync_config, sync_file, Verification,
+};
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, Utc};
+use log::info;
+use reqwest::StatusCode;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    repos: PairArgs,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(flatten)]
+    filter: FileFilter,
+
+    /// How thoroughly to check the files both repositories have
+    #[arg(long, env = "REST_SYNC_VERIFY_MODE", value_enum, default_value_t = VerifyMode::Size)]
+    mode: VerifyMode,
+
+    /// Number of randomly chosen files to download and hash with `--mode sample`
+    #[arg(long, env = "REST_SYNC_VERIFY_SAMPLE", default_value_t = 100)]
+    sample: usize,
+}
+
+/// What `verify` checks beyond every source file being in the destination.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum VerifyMode {
+    /// Compare file sizes
+    Size,
+    /// Also download a random sample of the destination files and check
+    /// their SHA-256 against their names
+    Sample,
+    /// Download and hash every file in the destination
+    Deep,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct DiffArgs {
+    #[command(flatten)]
+    repos: PairArgs,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(flatten)]
+    filter: FileFilter,
+
+    /// Include the files that `sync --prune` would delete
+    #[arg(long, default_value_t = false)]
+    prune: bool,
+
+    /// Treat the destination as append-only, as with `sync --dest-append-only`
+    #[arg(long, env = "REST_SYNC_DEST_APPEND_ONLY", default_value_t = false, conflicts_with = "prune")]
+    dest_append_only: bool,
+
+    /// Print the differences as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct StatsArgs {
+    /// The repository (SRC) or the two to compare (SRC DST)
+    #[command(flatten)]
+    repos: PairArgs,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct CompareArgs {
+    #[command(flatten)]
+    repos: PairArgs,
+
+    /// Second mirror URL, instead of --other
+    #[arg(value_name = "OTHER")]
+    other_url: Option<String>,
+
+    /// Second mirror Restic REST repository URL, compared with the source
+    /// and the destination
+    #[arg(long, env = "REST_SYNC_OTHER")]
+    other: Option<String>,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(flatten)]
+    other_opts: OtherArgs,
+
+    #[command(flatten)]
+    filter: FileFilter,
+
+    /// Print the disagreements as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct InitArgs {
+    /// Destination repository URL, instead of --dest
+    #[arg(value_name = "DEST")]
+    dst: Option<String>,
+
+    /// Destination Restic REST repository URL
+    #[arg(long, env = "REST_SYNC_DEST")]
+    dest: Option<String>,
+
+    /// Repository to copy the config file from, or to check an existing
+    /// config file in the destination against
+    #[arg(long, env = "REST_SYNC_SOURCE")]
+    source: Option<String>,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+#[command(mut_arg("json", |arg| arg.help("Print the results as JSON").long_help(None)))]
+pub struct CheckConfigArgs {
+    /// The options of the sync to check, whose `--json` prints the results
+    /// as JSON
+    #[command(flatten)]
+    sync: SyncOptions,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PruneLocksArgs {
+    #[command(flatten)]
+    repos: PairArgs,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// Delete the locks older than this from the repository chosen with
+    /// --from, rather than the destination locks that are not in the source;
+    /// needs the restic password to read the lock times
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    older_than: Option<Duration>,
+
+    /// Repository to delete old locks from
+    #[arg(long, value_enum, default_value_t = Side::Dest, requires = "older_than")]
+    from: Side,
+
+    #[command(flatten)]
+    key: RepoKeyArgs,
+
+    /// Only list the locks that would be deleted
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct CopySnapshotArgs {
+    /// ID of the snapshot to copy, or a unique prefix of it
+    #[arg(value_name = "SNAPSHOT")]
+    snapshot: String,
+
+    #[command(flatten)]
+    repos: PairArgs,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    /// The password needed to read the snapshot's trees and the index
+    #[command(flatten)]
+    key: RepoKeyArgs,
+
+    /// Only list the files that would be copied
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct HistoryArgs {
+    /// Run to show in full, by the ID it has in the log, instead of listing
+    /// the latest runs
+    #[arg(value_name = "RUN_ID")]
+    run_id: Option<String>,
+
+    /// State directory of the syncs, as given to `sync --state-dir`
+    #[arg(long, env = "REST_SYNC_STATE_DIR", value_name = "DIR")]
+    state_dir: PathBuf,
+
+    /// Only list the runs of this job of the config file
+    #[arg(long, value_name = "NAME")]
+    job: Option<String>,
+
+    /// Only list the runs that uploaded or deleted files
+    #[arg(long, default_value_t = false)]
+    changed: bool,
+
+    /// Number of runs to list, newest first
+    #[arg(long, short = 'n', default_value_t = 20)]
+    limit: usize,
+
+    /// List how far each destination has caught up with its source,
+    /// instead of the runs
+    #[arg(long, default_value_t = false, conflicts_with = "run_id")]
+    watermarks: bool,
+
+    /// Print the runs as JSON
+    #[arg(long, default_value_t = false)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PlanArgs {
+    #[command(subcommand)]
+    command: PlanCommand,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+enum PlanCommand {
+    /// Download the files the destination lacks into a bundle with the plan
+    /// to upload them, where only the source is reachable
+    Export(Box<PlanExportArgs>),
+    /// Sync the destination from a bundle as its plan says, where only the
+    /// destination is reachable
+    Apply(Box<PlanApplyArgs>),
+    /// Print what the destination has, for `plan export --dest-listing`
+    Listing(Box<PlanListingArgs>),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PlanExportArgs {
+    /// Source repository URL, instead of --source
+    #[arg(value_name = "SRC")]
+    src: Option<String>,
+
+    /// Source Restic REST repository URL
+    #[arg(long, env = "REST_SYNC_SOURCE")]
+    source: Option<String>,
+
+    /// Directory to write the bundle to, which keeps the files of an
+    /// earlier export that the new plan uploads too
+    #[arg(long, value_name = "DIR")]
+    bundle: PathBuf,
+
+    /// What the destination has, as `plan listing` prints it; without it,
+    /// the plan uploads every file
+    #[arg(long, value_name = "FILE")]
+    dest_listing: Option<PathBuf>,
+
+    /// Plan to delete the files that only the destination has
+    #[arg(long, default_value_t = false, requires = "dest_listing")]
+    prune: bool,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(flatten)]
+    filter: FileFilter,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+#[command(
+    mut_arg("src", |arg| arg.value_name("DEST").help("Destination repository URL, instead of --dest")),
+    mut_arg("dst", |arg| arg.hide(true)),
+    mut_arg("source", |arg| arg.hide(true))
+)]
+pub struct PlanApplyArgs {
+    /// Directory of the bundle that `plan export` wrote
+    #[arg(long, value_name = "DIR")]
+    bundle: PathBuf,
+
+    /// The options of the sync from the bundle, which takes the place of
+    /// the source: the destination is the only repository URL
+    #[command(flatten)]
+    sync: SyncOptions,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct PlanListingArgs {
+    /// Destination repository URL, instead of --dest
+    #[arg(value_name = "DEST")]
+    dst: Option<String>,
+
+    /// Destination Restic REST repository URL
+    #[arg(long, env = "REST_SYNC_DEST")]
+    dest: Option<String>,
+
+    #[command(flatten)]
+    connection: ConnectionArgs,
+
+    #[command(flatten)]
+    filter: FileFilter,
+}
+
+/// The restic password of a repository, for the commands that read its
+/// encrypted files.
+#[derive(clap::Args, Debug, Clone)]
+pub struct RepoKeyArgs {
+    /// The restic repository password (prefer the env var or a password file)
+    #[arg(long, env = "REST_SYNC_REPO_PASSWORD", value_name = "PASSWORD", hide_env_values = true, conflicts_with_all = ["repo_password_file", "repo_password_command"])]
+    repo_password: Option<Redacted<endpoint::Secret>>,
+
+    /// File to read the restic repository password from (not the password
+    /// of the REST server)
+    #[arg(long, env = "REST_SYNC_REPO_PASSWORD_FILE", value_name = "FILE")]
+    repo_password_file: Option<PathBuf>,
+
+    /// Shell command printing the restic repository password
+    #[arg(long, env = "REST_SYNC_REPO_PASSWORD_COMMAND", value_name = "CMD", conflicts_with = "repo_password_file")]
+    repo_password_command: Option<String>,
+}
+
+impl RepoKeyArgs {
+    pub(crate) fn password(&self) -> Result<endpoint::Secret> {
+        match (&self.repo_password_file, &self.repo_password_command) {
+            (Some(path), _) => endpoint::read_secret_file(path),
+            (None, Some(cmd)) => endpoint::run_secret_command(cmd),
+            (None, None) => match &self.repo_password {
+                Some(password) => Ok(password.0.clone()),
+                None => bail!(
+                    "The restic repository password is needed (--repo-password-file, --repo-password-command or REST_SYNC_REPO_PASSWORD)"
+                ),
+            },
+        }
+    }
+}
+
+
+/// Connects to both repositories of a pair.
+fn connect(repos: &RepoPair, connection: &ConnectionArgs) -> Result<(Repo, Repo)> {
+    let (source_opts, dest_opts) = connection.endpoint_options();
+    let run_id = request_id::new_run_id();
+    let source = Repo::connect(&repos.source, &source_opts, &run_id)?;
+    let dest = Repo::connect(&repos.dest, &dest_opts, &run_id)?;
+    Ok((source, dest))
+}
+
+pub async fn verify(args: &VerifyArgs) -> Result<()> {
+    let (source, dest) = connect(&args.repos.urls()?, &args.connection)?;
+    let mut diverged = 0;
+
+    if args.filter.includes_type("config") {
+        let source_config = fetch_config(&source).await?;
+        if source_config.is_some() && fetch_config(&dest).await? != source_config {
+            println!("config differs");
+            diverged += 1;
+        }
+    }
+
+    let mut checked = 0;
+    let mut present = Vec::new();
+    // Locks come and go with every restic operation, so they are not
+    // expected to match.
+    for file_type in args.filter.file_types().filter(|&t| t != "locks") {
+        let mut comparison = compare_type(&source, &dest, file_type).await?;
+        args.filter.apply(file_type, &mut comparison);
+        for file in &comparison.missing {
+            println!("missing {}/{}", file_type, file.name);
+        }
+        for (file, dest_size) in &comparison.mismatched {
+            println!(
+                "size    {}/{}: {} in the source, {} in the destination",
+                file_type, file.name, file.size, dest_size
+            );
+        }
+        if !comparison.extra.is_empty() {
+            info!(
+                "[{}] {} files in the destination are not in the source",
+                file_type,
+                comparison.extra.len()
+            );
+        }
+        diverged += comparison.missing.len() + comparison.mismatched.len();
+        checked +=
+            comparison.missing.len() + comparison.mismatched.len() + comparison.matching.len();
+        present.extend(
+            comparison
+                .matching
+                .into_iter()
+                .map(|file| (file_type, file)),
+        );
+    }
+
+    let to_hash = match args.mode {
+        VerifyMode::Size => Vec::new(),
+        VerifyMode::Sample => {
+            let amount = args.sample.min(present.len());
+            let mut chosen =
+                rand::seq::index::sample(&mut rand::rng(), present.len(), amount).into_vec();
+            chosen.sort_unstable();
+            chosen.into_iter().map(|i| present[i].clone()).collect()
+        }
+        VerifyMode::Deep => present,
+    };
+    if !to_hash.is_empty() {
+        let bytes: u64 = to_hash.iter().map(|(_, file)| file.size).sum();
+        info!(
+            "Hashing {} files ({}) in the destination",
+            to_hash.len(),
+            format_bytes(bytes)
+        );
+    }
+    for (file_type, file) in &to_hash {
+        let path = format!("{}/{}", file_type, file.name);
+        let resp = dest.get(&path).send().await?;
+        if !resp.status().is_success() {
+            bail!(Error::Http {
+                operation: "download",
+                url: redact_url(&dest.join(&path)),
+                status: resp.status(),
+            });
+        }
+        if sha256_hex(&resp.bytes().await?) != file.name {
+            println!("corrupt {}", path);
+            diverged += 1;
+        }
+    }
+
+    if diverged > 0 {
+        bail!(
+            "The destination differs from the source in {} files",
+            diverged
+        );
+    }
+    if to_hash.is_empty() {
+        println!("The destination matches the source ({} files)", checked);
+    } else {
+        println!(
+            "The destination matches the source ({} files, {} hashed)",
+            checked,
+            to_hash.len()
+        );
+    }
+    Ok(())
+}
+
+/// Reads the config file of a repository, `None` if it has none.
+pub async fn fetch_config(repo: &Repo) -> Result<Option<Vec<u8>>> {
+    let resp = repo.get("config").send().await?;
+    match resp.status() {
+        StatusCode::NOT_FOUND => Ok(None),
+        status if status.is_success() => Ok(Some(resp.bytes().await?.to_vec())),
+        status => bail!(Error::Http {
+            operation: "download",
+            url: redact_url(&repo.join("config")),
+            status,
+        }),
+    }
+}
+
+/// What a sync would change, as printed by `diff --json`.
+#[derive(Serialize, Default)]
+struct Diff {
+    upload: Vec<Change>,
+    delete: Vec<Change>,
+    upload_bytes: u64,
+}
+
+#[derive(Serialize)]
+struct Change {
+    #[serde(rename = "type")]
+    file_type: &'static str,
+    name: String,
+    size: u64,
+}
+
+pub async fn diff(args: &DiffArgs) -> Result<()> {
+    let (source, dest) = connect(&args.repos.urls()?, &args.connection)?;
+    let mut diff = Diff::default();
+    for file_type in args.filter.file_types() {
+        let mut comparison = compare_type(&source, &dest, file_type).await?;
+        args.filter.apply(file_type, &mut comparison);
+        let type_plan = restic_sync_core::plan(file_type, comparison, args.prune, args.dest_append_only);
+        let change = |file: PlannedFile| Change {
+            file_type,
+            name: file.name,
+            size: file.size,
+        };
+        diff.upload_bytes += type_plan.uploads.iter().map(|file| file.size).sum::<u64>();
+        diff.upload.extend(type_plan.uploads.into_iter().map(change));
+        diff.delete.extend(type_plan.deletes.into_iter().map(change));
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+        return Ok(());
+    }
+    for file in &diff.upload {
+        println!(
+            "upload {}/{} ({})",
+            file.file_type,
+            file.name,
+            format_bytes(file.size)
+        );
+    }
+    for file in &diff.delete {
+        println!("delete {}/{}", file.file_type, file.name);
+    }
+    println!(
+        "{} files to upload ({}), {} files to delete",
+        diff.upload.len(),
+        format_bytes(diff.upload_bytes),
+        diff.delete.len()
+    );
+    Ok(())
+}
+
+/// Number of files and bytes of each type, then the total.
+type Counts = Vec<(&'static str, usize, u64)>;
+
+pub async fn stats(args: &StatsArgs) -> Result<()> {
+    let (source_opts, dest_opts) = args.connection.endpoint_options();
+    let run_id = request_id::new_run_id();
+    let repos = [
+        (args.repos.source_url(), &source_opts),
+        (args.repos.dest_url(), &dest_opts),
+    ];
+    let mut counts = Vec::new();
+    for (url, options) in repos {
+        let Some(url) = url else { continue };
+        let repo = Repo::connect(url, options, &run_id)?;
+        counts.push((redact_url(repo.url()), count(&repo).await?));
+    }
+
+    match counts.as_slice() {
+        [] => bail!("No repository given (SRC, --source or --dest)"),
+        [(url, counts)] => {
+            println!("{}", url);
+            for (file_type, files, bytes) in counts {
+                println!(
+                    "  {:<10} {:>8} files {:>12}",
+                    file_type,
+                    files,
+                    format_bytes(*bytes)
+                );
+            }
+        }
+        [(source_url, source), (dest_url, dest)] => {
+            println!("Source:      {}", source_url);
+            println!("Destination: {}", dest_url);
+            println!("  {:<10} {:>27}   {:>27}", "", "source", "destination");
+            for ((file_type, files, bytes), (_, dest_files, dest_bytes)) in source.iter().zip(dest)
+            {
+                println!(
+                    "  {:<10} {:>8} files {:>12}   {:>8} files {:>12}",
+                    file_type,
+                    files,
+                    format_bytes(*bytes),
+                    dest_files,
+                    format_bytes(*dest_bytes)
+                );
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+async fn count(repo: &Repo) -> Result<Counts> {
+    let mut counts = Vec::new();
+    let (mut total_files, mut total_bytes) = (0, 0);
+    for file_type in FILE_TYPES {
+        let files = list_files(repo, file_type).await?;
+        let bytes: u64 = files.iter().map(|file| file.size).sum();
+        total_files += files.len();
+        total_bytes += bytes;
+        counts.push((*file_type, files.len(), bytes));
+    }
+    counts.push(("total", total_files, total_bytes));
+    Ok(counts)
+}
+
+/// The repositories `compare` looks at, in the order of its arguments.
+const COMPARED: [&str; 3] = ["source", "dest", "other"];
+
+/// The outcome of `compare`.
+#[derive(Serialize, Default)]
+struct ThreeWay {
+    /// The files that are not the same in all three repositories.
+    files: Vec<Disagreement>,
+    /// How often each repository disagrees with the two others.
+    odd_one_out: BTreeMap<&'static str, usize>,
+    /// Files on which no two repositories agree.
+    no_majority: usize,
+}
+
+#[derive(Serialize)]
+struct Disagreement {
+    #[serde(rename = "type")]
+    file_type: &'static str,
+    name: String,
+    /// The size in each repository, `None` where the file is missing.
+    sizes: BTreeMap<&'static str, Option<u64>>,
+    odd_one_out: Option<&'static str>,
+}
+
+impl ThreeWay {
+    fn add<T: PartialEq>(
+        &mut self,
+        file_type: &'static str,
+        name: String,
+        versions: [Option<T>; 3],
+        sizes: [Option<u64>; 3],
+    ) {
+        let [a, b, c] = &versions;
+        if a == b && b == c {
+            return;
+        }
+        let odd = if a == b {
+            Some(2)
+        } else if a == c {
+            Some(1)
+        } else if b == c {
+            Some(0)
+        } else {
+            None
+        };
+        let odd = odd.map(|i| COMPARED[i]);
+        match odd {
+            Some(repo) => *self.odd_one_out.entry(repo).or_default() += 1,
+            None => self.no_majority += 1,
+        }
+        self.files.push(Disagreement {
+            file_type,
+            name,
+            sizes: COMPARED.into_iter().zip(sizes).collect(),
+            odd_one_out: odd,
+        });
+    }
+}
+
+pub async fn compare(args: &CompareArgs) -> Result<()> {
+    let Some(other_url) = args.other_url.as_ref().or(args.other.as_ref()) else {
+        bail!("No second mirror given (OTHER, --other or REST_SYNC_OTHER)");
+    };
+    let (source_opts, dest_opts) = args.connection.endpoint_options();
+    let other_opts = args.connection.complete(args.other_opts.clone().into());
+    let run_id = request_id::new_run_id();
+    let repos = [
+        Repo::connect(args.repos.url(Side::Source)?, &source_opts, &run_id)?,
+        Repo::connect(args.repos.url(Side::Dest)?, &dest_opts, &run_id)?,
+        Repo::connect(other_url, &other_opts, &run_id)?,
+    ];
+
+    let mut result = ThreeWay::default();
+    if args.filter.includes_type("config") {
+        let mut configs = [None, None, None];
+        for (config, repo) in configs.iter_mut().zip(&repos) {
+            *config = fetch_config(repo).await?;
+        }
+        let sizes = configs
+            .each_ref()
+            .map(|config| config.as_ref().map(|config| config.len() as u64));
+        result.add("config", "config".to_string(), configs, sizes);
+    }
+    let mut checked = 0;
+    // Locks come and go with every restic operation, as in `verify`.
+    for file_type in args.filter.file_types().filter(|&t| t != "locks") {
+        let mut files: BTreeMap<String, [Option<u64>; 3]> = BTreeMap::new();
+        for (i, repo) in repos.iter().enumerate() {
+            for file in list_files(repo, file_type).await? {
+                if args.filter.includes(file_type, &file.name) {
+                    files.entry(file.name).or_default()[i] = Some(file.size);
+                }
+            }
+        }
+        checked += files.len();
+        for (name, sizes) in files {
+            result.add(file_type, name, sizes, sizes);
+        }
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    } else {
+        for file in &result.files {
+            let sizes: Vec<String> = COMPARED
+                .iter()
+                .map(|repo| match file.sizes[repo] {
+                    Some(size) => format!("{repo} {size} B"),
+                    None => format!("{repo} missing"),
+                })
+                .collect();
+            let verdict = match file.odd_one_out {
+                Some(repo) => format!("{repo} is the odd one out"),
+                None => "no two agree".to_string(),
+            };
+            let path = match file.file_type {
+                "config" => "config".to_string(),
+                file_type => format!("{}/{}", file_type, file.name),
+            };
+            println!("{}: {} ({})", path, sizes.join(", "), verdict);
+        }
+    }
+    if result.files.is_empty() {
+        if !args.json {
+            println!("All three repositories agree ({} files)", checked);
+        }
+        return Ok(());
+    }
+    if !args.json {
+        println!();
+        for (repo, url) in COMPARED.iter().zip(&repos) {
+            println!(
+                "{:<6} {:>6} files  {}",
+                repo,
+                result.odd_one_out.get(repo).copied().unwrap_or_default(),
+                redact_url(url.url())
+            );
+        }
+        if result.no_majority > 0 {
+            println!("No majority for {} files", result.no_majority);
+        }
+    }
+    bail!("The repositories disagree on {} files", result.files.len())
+}
+
+pub async fn init(args: &InitArgs) -> Result<()> {
+    let Some(url) = args.dst.as_ref().or(args.dest.as_ref()) else {
+        bail!("No destination repository given (DEST, --dest or REST_SYNC_DEST)");
+    };
+    let (source_opts, dest_opts) = args.connection.endpoint_options();
+    let run_id = request_id::new_run_id();
+    let dest = Repo::connect(url, &dest_opts, &run_id)?;
+    init_dest(&dest).await?;
+    // Listing needs the v2 REST API and read access, which a sync relies on.
+    list_files(&dest, "keys").await?;
+
+    if let Some(source) = &args.source {
+        let source = Repo::connect(source, &source_opts, &run_id)?;
+        sync_config(&source, &dest).await?;
+    }
+    let state = match fetch_config(&dest).await? {
+        Some(_) => "has a config file",
+        None => "has no config file yet; the first sync copies it from the source",
+    };
+    println!(
+        "Repository {} is ready and {}",
+        redact_url(dest.url()),
+        state
+    );
+    Ok(())
+}
+
+/// Copies one snapshot: the config and keys, the packs holding its trees
+/// and file contents, an index file for those packs, and last the snapshot
+/// file itself, so that an interrupted copy never leaves a snapshot whose
+/// data is missing. A snapshot that is already in the destination is left
+/// alone.
+pub async fn copy_snapshot(args: &CopySnapshotArgs) -> Result<()> {
+    let (source, dest) = connect(&args.repos.urls()?, &args.connection)?;
+    let key = crypto::open(&source, &args.key.password()?).await?;
+    let name = snapshot::resolve_id(&source, &args.snapshot).await?;
+    let short = name.get(..8).unwrap_or(&name);
+    if !args.dry_run {
+        init_dest(&dest).await?;
+    }
+    if list_files(&dest, "snapshots")
+        .await?
+        .iter()
+        .any(|file| file.name == name)
+    {
+        println!("Snapshot {} is already in the destination", short);
+        return Ok(());
+    }
+
+    let index = Index::load(&source, &key).await?;
+    let packs = snapshot::packs(&source, &key, &index, &name).await?;
+    let present: HashSet<String> = list_files(&dest, "data")
+        .await?
+        .into_iter()
+        .map(|file| file.name)
+        .collect();
+    let missing: Vec<&String> = packs
+        .iter()
+        .filter(|pack| !present.contains(*pack))
+        .collect();
+    let keys = compare_type(&source, &dest, "keys").await?.missing;
+
+    if args.dry_run {
+        for file in &keys {
+            println!("would copy keys/{}", file.name);
+        }
+        for pack in &missing {
+            println!("would copy data/{}", pack);
+        }
+        println!("would write an index file for {} packs", packs.len());
+        println!("would copy snapshots/{}", name);
+        return Ok(());
+    }
+
+    sync_config(&source, &dest).await?;
+    // Ctrl-C ends the command at once, there being no report to return.
+    let cancel = CancellationToken::new();
+    for file in &keys {
+        sync_file(&source, &dest, "keys", &file.name, Some(file.size), Verification::Sha256, &cancel).await?;
+    }
+    let mut bytes = 0;
+    for pack in &missing {
+        bytes += sync_file(&source, &dest, "data", pack, None, Verification::Sha256, &cancel).await?;
+        info!("[data] Copied {}", pack);
+    }
+    // The destination may lack the source's index files for these packs,
+    // so it gets its own, encrypted with the same key.
+    let entries = serde_json::to_vec(&index.entries(&packs))?;
+    let index_file = key.encrypt(&entries);
+    let index_name = sha256_hex(&index_file);
+    let path = format!("index/{}", index_name);
+    let resp = dest.post(&path).body(index_file).send().await?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let url = redact_url(&dest.join(&path));
+        bail!(match resp.text().await {
+            Ok(body) if crate::error::is_storage_full(status, &body) => Error::DestinationFull { url },
+            _ => Error::Http {
+                operation: "upload to",
+                url,
+                status,
+            },
+        });
+    }
+    sync_file(&source, &dest, "snapshots", &name, None, Verification::Sha256, &cancel).await?;
+    println!(
+        "Copied snapshot {}: {} of {} packs ({}), {} keys and index {}",
+        short,
+        missing.len(),
+        packs.len(),
+        format_bytes(bytes),
+        keys.len(),
+        &index_name[..8]
+    );
+    Ok(())
+}
+
+pub async fn prune_locks(args: &PruneLocksArgs) -> Result<()> {
+    if let Some(max_age) = args.older_than {
+        return prune_old_locks(args, max_age).await;
+    }
+    let (source, dest) = connect(&args.repos.urls()?, &args.connection)?;
+    let stale = compare_type(&source, &dest, "locks").await?.extra;
+    for file in &stale {
+        if args.dry_run {
+            println!("would delete locks/{}", file.name);
+        } else {
+            delete_file(&dest, "locks", &file.name).await?;
+            println!("deleted locks/{}", file.name);
+        }
+    }
+    if stale.is_empty() {
+        println!("No stale locks in the destination");
+    }
+    Ok(())
+}
+
+/// The contents of a lock file.
+#[derive(Deserialize)]
+struct Lock {
+    time: String,
+    #[serde(default)]
+    exclusive: bool,
+    #[serde(default)]
+    hostname: String,
+    #[serde(default)]
+    pid: u32,
+}
+
+/// Deletes the locks of one repository that were created more than
+/// `max_age` ago. The time is only recorded inside the encrypted lock, so
+/// this needs the repository password.
+async fn prune_old_locks(args: &PruneLocksArgs, max_age: Duration) -> Result<()> {
+    let (source_opts, dest_opts) = args.connection.endpoint_options();
+    let options = match args.from {
+        Side::Source => source_opts,
+        Side::Dest => dest_opts,
+    };
+    let repo = Repo::connect(
+        args.repos.url(args.from)?,
+        &options,
+        &request_id::new_run_id(),
+    )?;
+    let key = crypto::open(&repo, &args.key.password()?).await?;
+
+    let now = Utc::now();
+    let mut old = 0;
+    for file in list_files(&repo, "locks").await? {
+        let path = format!("locks/{}", file.name);
+        let resp = repo.get(&path).send().await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            // Released since it was listed.
+            continue;
+        }
+        if !resp.status().is_success() {
+            bail!(Error::Http {
+                operation: "download",
+                url: redact_url(&repo.join(&path)),
+                status: resp.status(),
+            });
+        }
+        let plain = key
+            .decrypt_file(&resp.bytes().await?)
+            .with_context(|| format!("Failed to decrypt {path}"))?;
+        let lock: Lock =
+            serde_json::from_slice(&plain).with_context(|| format!("Invalid lock {path}"))?;
+        let created = DateTime::parse_from_rfc3339(&lock.time)
+            .with_context(|| format!("Invalid time `{}` in lock {path}", lock.time))?;
+        let age = (now - created.with_timezone(&Utc))
+            .to_std()
+            .unwrap_or_default();
+        if age < max_age {
+            continue;
+        }
+
+        let description = format!(
+            "{} lock by {} (PID {}), {} old",
+            if lock.exclusive {
+                "exclusive"
+            } else {
+                "shared"
+            },
+            lock.hostname,
+            lock.pid,
+            humantime::format_duration(Duration::from_secs(age.as_secs()))
+        );
+        if args.dry_run {
+            println!("would delete {path}: {description}");
+        } else {
+            delete_file(&repo, "locks", &file.name).await?;
+            println!("deleted {path}: {description}");
+        }
+        old += 1;
+    }
+    if old == 0 {
+        println!(
+            "No locks older than {} in {}",
+            humantime::format_duration(max_age),
+            redact_url(repo.url())
+        );
+    }
+    Ok(())
+}
+
+/// The outcome of `check-config`.
+#[derive(Serialize)]
+struct ConfigCheck {
+    ok: bool,
+    source: EndpointCheck,
+    dest: EndpointCheck,
+}
+
+/// What was found out about one repository, as far as the probes got.
+#[derive(Serialize, Default)]
+struct EndpointCheck {
+    url: String,
+    credentials: Option<String>,
+    reachable: bool,
+    authorized: bool,
+    /// Whether the server lists files in the v2 format restic-sync needs.
+    api_v2: bool,
+    has_config: bool,
+    error: Option<String>,
+}
+
+pub async fn check_config(args: &CheckConfigArgs) -> Result<()> {
+    let sync = &args.sync;
+    // Everything that `sync` would set up before its first run.
+    #[cfg(feature = "cron")]
+    if let Some(cron) = sync.cron.as_deref().filter(|cron| !cron.is_empty()) {
+        tokio_cron_scheduler::Job::new_async(cron, |_, _| Box::pin(async {}))
+            .with_context(|| format!("Invalid cron expression `{cron}`"))?;
+    }
+    #[cfg(feature = "notifications")]
+    Notifiers::from_args(sync)?;
+
+    let (source_opts, dest_opts) = sync.connection.endpoint_options();
+    let run_id = request_id::new_run_id();
+    let repos = sync.repos.urls()?;
+    let source = probe(&repos.source, &source_opts, &run_id).await;
+    let dest = probe(&repos.dest, &dest_opts, &run_id).await;
+    let check = ConfigCheck {
+        ok: source.error.is_none() && dest.error.is_none(),
+        source,
+        dest,
+    };
+
+    if sync.json() {
+        println!("{}", serde_json::to_string_pretty(&check)?);
+    } else {
+        for (side, endpoint) in [("source", &check.source), ("dest", &check.dest)] {
+            println!("{}: {}", side, endpoint.url);
+            if let Some(credentials) = &endpoint.credentials {
+                println!("  credentials: {}", credentials);
+            }
+            let yes_no = |value| if value { "yes" } else { "no" };
+            println!("  reachable:   {}", yes_no(endpoint.reachable));
+            println!("  authorized:  {}", yes_no(endpoint.authorized));
+            println!("  API v2:      {}", yes_no(endpoint.api_v2));
+            println!("  config file: {}", yes_no(endpoint.has_config));
+            if let Some(error) = &endpoint.error {
+                println!("  error:       {}", error);
+            }
+        }
+    }
+    if !check.ok {
+        bail!("The configuration check failed");
+    }
+    Ok(())
+}
+
+async fn probe(url: &str, options: &EndpointOptions, run_id: &str) -> EndpointCheck {
+    let mut check = EndpointCheck {
+        url: redact_url(url),
+        ..EndpointCheck::default()
+    };
+    if let Err(e) = probe_into(&mut check, url, options, run_id).await {
+        check.error = Some(format!("{:#}", e));
+    }
+    check
+}
+
+async fn probe_into(
+    check: &mut EndpointCheck,
+    url: &str,
+    options: &EndpointOptions,
+    run_id: &str,
+) -> Result<()> {
+    let repo = Repo::connect(url, options, run_id)?;
+    check.url = redact_url(repo.url());
+    check.credentials = Some(repo.describe_auth());
+
+    let resp = repo.get("config").send().await?;
+    check.reachable = true;
+    match resp.status() {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            bail!("The server refused the credentials: {}", resp.status())
+        }
+        StatusCode::NOT_FOUND => {}
+        status if status.is_success() => check.has_config = true,
+        status => bail!("Unexpected response to reading the config file: {}", status),
+    }
+    check.authorized = true;
+
+    let resp = repo
+        .get("keys/")
+        .header("Accept", "application/vnd.x.restic.rest.v2")
+        .send()
+        .await?;
+    if resp.status() == StatusCode::NOT_FOUND {
+        bail!("The repository does not exist yet (`restic-sync init` creates it)");
+    }
+    if !resp.status().is_success() {
+        bail!("Failed to list keys: {}", resp.status());
+    }
+    check.api_v2 = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/vnd.x.restic.rest.v2"));
+    if !check.api_v2 {
+        bail!("The server does not support the v2 REST API");
+    }
+    Ok(())
+}
+
+pub async fn plan(args: &PlanArgs, cancel: &CancellationToken) -> Result<()> {
+    match &args.command {
+        PlanCommand::Export(args) => plan_export(args, cancel).await,
+        PlanCommand::Apply(args) => plan_apply(args, cancel).await,
+        PlanCommand::Listing(args) => plan_listing(args).await,
+    }
+}
+
+/// Lists the source, and downloads and checks each file that the
+/// destination listing lacks into the bundle, then writes the plan.
+async fn plan_export(args: &PlanExportArgs, cancel: &CancellationToken) -> Result<()> {
+    let Some(url) = args.src.as_ref().or(args.source.as_ref()) else {
+        bail!("No source repository given (SRC, --source or REST_SYNC_SOURCE)");
+    };
+    let (source_opts, _) = args.connection.endpoint_options();
+    let source = Repo::connect(url, &source_opts, &request_id::new_run_id())?;
+    let listing = match &args.dest_listing {
+        Some(path) => Listing::read(path)?,
+        None => Listing::default(),
+    };
+    let bundle = Bundle::new(&args.bundle)?;
+    bundle.start()?;
+
+    let mut plan = SyncPlan {
+        config: args.filter.includes_type("config"),
+        types: Vec::new(),
+    };
+    if plan.config {
+        let Some(config) = fetch_config(&source).await? else {
+            bail!("The source {} has no config file", redact_url(source.url()));
+        };
+        bundle.write("config", &config)?;
+    }
+    for file_type in args.filter.file_types() {
+        let files = list_files(&source, file_type).await?;
+        let mut comparison = restic_sync_core::compare(files, listing.files(file_type));
+        args.filter.apply(file_type, &mut comparison);
+        let type_plan = restic_sync_core::plan(file_type, comparison, args.prune, false);
+        let mut reused = 0;
+        for file in &type_plan.uploads {
+            let path = format!("{}/{}", file_type, file.name);
+            if bundle.has(&path, file.size) {
+                reused += 1;
+                continue;
+            }
+            let bytes = download(&source, file_type, &file.name, cancel).await?;
+            Verification::Sha256.check_download(&file.name, Some(file.size), &bytes)?;
+            bundle.write(&path, &bytes)?;
+        }
+        info!(
+            "[{}] {} files ({}) in the bundle, {} of them from the last export",
+            file_type,
+            type_plan.uploads.len(),
+            format_bytes(type_plan.uploads.iter().map(|file| file.size).sum()),
+            reused
+        );
+        plan.types.push(type_plan);
+    }
+    bundle.write_plan(&plan)?;
+    println!("Exported {} to {}", plan, args.bundle.display());
+    Ok(())
+}
+
+/// Runs a sync from the bundle to the destination that executes its plan.
+async fn plan_apply(args: &PlanApplyArgs, cancel: &CancellationToken) -> Result<()> {
+    let bundle = Bundle::new(&args.bundle)?;
+    let plan = bundle.read_plan()?;
+    let mut options = args.sync.clone();
+    if options.tui() {
+        bail!("plan apply has no dashboard; leave out --tui");
+    }
+    // A single repository URL lands where the source would go.
+    let repos = &mut options.repos;
+    if repos.src.is_some() && repos.dst.is_some() {
+        bail!("plan apply only takes the destination; the bundle is its source");
+    }
+    repos.dst = repos.dst.take().or(repos.src.take()).or(repos.dest.take());
+    repos.source = Some(bundle.url().to_string());
+    let mut client = SyncClient::new(options)?.with_cancellation(cancel.clone());
+    client.source_opts.transport = Some(InjectedTransport(Arc::new(bundle)));
+    info!("Applying the plan of {}: {}", args.bundle.display(), plan);
+    client.execute(&plan).await.map(|_| ())
+}
+
+async fn plan_listing(args: &PlanListingArgs) -> Result<()> {
+    let Some(url) = args.dst.as_ref().or(args.dest.as_ref()) else {
+        bail!("No destination repository given (DEST, --dest or REST_SYNC_DEST)");
+    };
+    let (_, dest_opts) = args.connection.endpoint_options();
+    let dest = Repo::connect(url, &dest_opts, &request_id::new_run_id())?;
+    let mut listing = Listing::default();
+    for file_type in args.filter.file_types() {
+        listing.add(file_type, list_files(&dest, file_type).await?);
+    }
+    println!("{}", serde_json::to_string(&listing)?);
+    Ok(())
+}
+
+pub fn history(args: &HistoryArgs) -> Result<()> {
+    let dir = &args.state_dir;
+    if !dir.is_dir() {
+        bail!("No state directory at {}; syncs keep their history there with --state-dir", dir.display());
+    }
+    // The state of each repository pair has a history of its own.
+    let histories: Vec<History> = State::open_all(dir)?
+        .into_iter()
+        .map(|state| History::new(Arc::new(state)))
+        .collect();
+
+    if args.watermarks {
+        let mut watermarks = Vec::new();
+        for history in &histories {
+            watermarks.extend(history.watermarks()?);
+        }
+        return print_watermarks(watermarks, args.json);
+    }
+    if let Some(run_id) = &args.run_id {
+        let mut found = None;
+        for history in &histories {
+            found = found.or(history.run(run_id)?);
+        }
+        let Some(run) = found else {
+            bail!("No run {} in the history at {}", run_id, dir.display());
+        };
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&run)?);
+            return Ok(());
+        }
+        println!("Run:         {}", run.run_id);
+        if let Some(job) = &run.job {
+            println!("Job:         {}", job);
+        }
+        println!("Source:      {}", run.source);
+        println!("Destination: {}", run.dest);
+        println!("Started:     {}", local_time(run.started_at));
+        match &run.error {
+            Some(error) => println!("Outcome:     {}: {}", run.outcome.as_str(), error),
+            None => println!("Outcome:     {}", run.outcome.as_str()),
+        }
+        if run.report.is_some() {
+            println!("Summary:     {}", summary(&run));
+        }
+        let types = run.report.as_ref().and_then(|report| report["types"].as_array());
+        for report in types.into_iter().flatten() {
+            let count = |field: &str| report[field].as_u64().unwrap_or_default();
+            println!(
+                "  {:<10} uploaded {} files ({}), deleted {} files ({}), skipped {} files ({}) in {:.1}s",
+                report["file_type"].as_str().unwrap_or_default(),
+                count("uploaded_files"),
+                format_bytes(count("uploaded_bytes")),
+                count("deleted_files"),
+                format_bytes(count("deleted_bytes")),
+                count("skipped_files"),
+                format_bytes(count("skipped_bytes")),
+                report["duration_secs"].as_f64().unwrap_or_default()
+            );
+        }
+        return Ok(());
+    }
+
+    let query = HistoryQuery {
+        job: args.job.clone(),
+        changed: args.changed,
+        limit: args.limit,
+    };
+    let mut runs = Vec::new();
+    for history in &histories {
+        runs.extend(history.runs(&query)?);
+    }
+    runs.sort_by_key(|run| std::cmp::Reverse(run.started_at));
+    runs.truncate(args.limit);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&runs)?);
+        return Ok(());
+    }
+    if runs.is_empty() {
+        println!("No runs recorded yet");
+    }
+    for run in &runs {
+        let job = run.job.as_deref().map(|job| format!(" [{}]", job)).unwrap_or_default();
+        println!(
+            "{}  {}{}  {:<9}  {}",
+            local_time(run.started_at),
+            run.run_id,
+            job,
+            run.outcome.as_str(),
+            summary(run)
+        );
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct WatermarkSummary {
+    source: String,
+    dest: String,
+    run_id: Option<String>,
+    synced_at: Option<i64>,
+    snapshots: usize,
+    /// Snapshots that other destinations of the source have caught up
+    /// with, and this one has not.
+    behind_snapshots: usize,
+}
+
+fn print_watermarks(mut watermarks: Vec<Watermark>, json: bool) -> Result<()> {
+    watermarks.sort_by(|a, b| (&a.source, &a.dest).cmp(&(&b.source, &b.dest)));
+    let summaries: Vec<WatermarkSummary> = watermarks
+        .iter()
+        .map(|watermark| {
+            let others: HashSet<String> = watermarks
+                .iter()
+                .filter(|other| other.source == watermark.source)
+                .flat_map(|other| other.snapshots.iter())
+                .map(|file| file.name)
+                .collect();
+            WatermarkSummary {
+                source: watermark.source.clone(),
+                dest: watermark.dest.clone(),
+                run_id: watermark.run_id.clone(),
+                synced_at: watermark.synced_at,
+                snapshots: watermark.snapshots.len(),
+                behind_snapshots: others
+                    .iter()
+                    .filter(|name| watermark.snapshots.get(name).is_none())
+                    .count(),
+            }
+        })
+        .collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+    if summaries.is_empty() {
+        println!("No destinations tracked yet");
+    }
+    for summary in &summaries {
+        let caught_up = match (&summary.run_id, summary.synced_at) {
+            (Some(run_id), Some(synced_at)) => format!(
+                "caught up at {} in run {} with {} snapshots",
+                local_time(synced_at),
+                run_id,
+                summary.snapshots
+            ),
+            _ => "never caught up".to_string(),
+        };
+        let behind = match summary.behind_snapshots {
+            0 => String::new(),
+            behind => format!(", {} snapshots behind the other destinations", behind),
+        };
+        println!("{} -> {}  {}{}", summary.source, summary.dest, caught_up, behind);
+    }
+    Ok(())
+}
+
+fn local_time(unix_secs: i64) -> String {
+    DateTime::from_timestamp(unix_secs, 0)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| unix_secs.to_string())
+}
+
+/// What the run did, or the error that stopped it.
+fn summary(run: &RunRecord) -> String {
+    let Some(report) = &run.report else {
+        return run.error.clone().unwrap_or_default();
+    };
+    let duration = run.duration_secs().unwrap_or_default();
+    if let Some(types) = report["divergence"]["types"].as_array() {
+        let sum = |field: &str| types.iter().filter_map(|t| t[field].as_u64()).sum::<u64>();
+        return format!(
+            "destination {} files ({}) behind, with {} extra files, checked in {:.1}s",
+            sum("behind_files"),
+            format_bytes(sum("behind_bytes")),
+            sum("extra_files"),
+            duration
+        );
+    }
+    format!(
+        "uploaded {} files ({}), deleted {} files in {:.1}s",
+        run.uploaded_files(),
+        format_bytes(run.uploaded_bytes()),
+        run.deleted_files(),
+        duration
+    )
+}
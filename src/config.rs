@@ -0,0 +1,786 @@
+//! Options from a configuration file.
+//!
+//! The file is TOML and uses the long option names as keys, either at the
+//! top level (`dest-user = "mirror"`) or grouped into tables (`[dest]` with
+//! `user = "mirror"`), where `url` in a `[source]` or `[dest]` table is the
+//! repository URL itself. Underscores may be used instead of dashes. The
+//! values become the defaults of the matching options, so flags and
+//! environment variables still take precedence over the file.
+//!
+//! A `[[job]]` array defines several sync pairs. Each job is parsed on its
+//! own, with its table layered over the top-level settings, which act as
+//! shared defaults.
+//!
+//! `[profile.<name>]` tables are only used when selected with `--profile`.
+//! They are layered over everything else, so a profile can point the same
+//! source at a different destination, credentials or schedule.
+//!
+//! The options of the command line are the schema: every key must name one,
+//! and its values must be ones the option accepts. All problems are
+//! reported at once, each with the line and column of its key.
+
+use crate::restic_env;
+use anyhow::{Context, Result, bail};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, Command};
+use std::ffi::OsString;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use toml::de::{DeTable, DeValue};
+use toml::{Table, Value};
+
+/// One option from the file, named by its long flag.
+#[derive(Debug, Clone)]
+pub struct Setting {
+    name: String,
+    values: Vec<String>,
+    /// Line and column of the key, for error messages.
+    location: Option<(usize, usize)>,
+}
+
+/// A parsed configuration file.
+#[derive(Debug, Default)]
+pub struct Config {
+    path: PathBuf,
+    settings: Vec<Setting>,
+    jobs: Vec<Section>,
+    profiles: Vec<Section>,
+}
+
+/// A setting that is not valid, with the line and column of its key.
+type Problem = (Option<(usize, usize)>, String);
+
+/// Where the values of a table are in the file.
+#[derive(Clone, Copy)]
+struct Spans<'a> {
+    text: &'a str,
+    table: Option<&'a DeTable<'a>>,
+}
+
+impl<'a> Spans<'a> {
+    fn get(&self, key: &str) -> Option<&'a toml::Spanned<DeValue<'a>>> {
+        self.table?.get(key)
+    }
+
+    /// The spans of the table under `key`.
+    fn table(&self, key: &str) -> Spans<'a> {
+        Spans {
+            text: self.text,
+            table: self.get(key).and_then(|value| value.get_ref().as_table()),
+        }
+    }
+
+    /// The spans of the `i`th table in the array under `key`.
+    fn array_table(&self, key: &str, i: usize) -> Spans<'a> {
+        Spans {
+            text: self.text,
+            table: self
+                .get(key)
+                .and_then(|value| value.get_ref().as_array())
+                .and_then(|array| array.get(i))
+                .and_then(|value| value.get_ref().as_table()),
+        }
+    }
+
+    /// Line and column of `key`, counted from 1.
+    fn locate(&self, key: &str) -> Option<(usize, usize)> {
+        let (key, _) = self.table?.get_key_value(key)?;
+        let before = &self.text[..key.span().start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        Some((line, column))
+    }
+}
+
+/// A job or profile.
+#[derive(Debug)]
+struct Section {
+    name: String,
+    settings: Vec<Setting>,
+}
+
+/// Finds a global option such as `--config` among the raw arguments, falling
+/// back to its environment variable. These are needed before the arguments
+/// can be parsed.
+pub fn raw_option(args: &[OsString], long: &str, env: &str) -> Option<OsString> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg == long {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg
+            .strip_prefix(long)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(OsString::from(value));
+        }
+    }
+    std::env::var_os(env)
+}
+
+pub fn load(path: &Path) -> Result<Config> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let mut table: Table = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+    let document = DeTable::parse(&contents).ok();
+    let spans = Spans {
+        text: &contents,
+        table: document.as_ref().map(|document| document.get_ref()),
+    };
+    let mut config = parse(&mut table, spans)
+        .with_context(|| format!("Invalid config file {}", path.display()))?;
+    config.path = path.to_path_buf();
+    Ok(config)
+}
+
+fn parse(table: &mut Table, spans: Spans) -> Result<Config> {
+    let mut config = Config::default();
+    if let Some(jobs) = table.remove("job") {
+        let Value::Array(jobs) = jobs else {
+            bail!("`job` must be an array of tables ([[job]])");
+        };
+        for (i, job) in jobs.into_iter().enumerate() {
+            let Value::Table(mut job) = job else {
+                bail!("`job` must be an array of tables ([[job]])");
+            };
+            let name = match job.remove("name") {
+                Some(Value::String(name)) => name,
+                Some(_) => bail!("The name of job {} must be a string", i + 1),
+                None => format!("job {}", i + 1),
+            };
+            if config.jobs.iter().any(|job| job.name == name) {
+                bail!("There is more than one job named `{name}`");
+            }
+            let mut settings = Vec::new();
+            flatten("", &job, spans.array_table("job", i), &mut settings)
+                .with_context(|| format!("In job `{name}`"))?;
+            config.jobs.push(Section { name, settings });
+        }
+    }
+    if let Some(profiles) = table.remove("profile") {
+        let Value::Table(profiles) = profiles else {
+            bail!("`profile` must be a table of profiles ([profile.<name>])");
+        };
+        for (name, profile) in profiles {
+            let Value::Table(profile) = profile else {
+                bail!("Profile `{name}` must be a table ([profile.{name}])");
+            };
+            let mut settings = Vec::new();
+            flatten(
+                "",
+                &profile,
+                spans.table("profile").table(&name),
+                &mut settings,
+            )
+            .with_context(|| format!("In profile `{name}`"))?;
+            config.profiles.push(Section { name, settings });
+        }
+    }
+    flatten("", table, spans, &mut config.settings)?;
+    Ok(config)
+}
+
+impl Config {
+    /// The command line parser for each job, or for all of the file if it
+    /// defines no jobs. `only` selects a single job by name, and `profile`
+    /// the profile to apply on top.
+    pub fn commands(
+        &self,
+        command: Command,
+        only: Option<&str>,
+        profile: Option<&str>,
+    ) -> Result<Vec<(Option<String>, Command)>> {
+        let profile = match profile {
+            Some(name) => {
+                let Some(profile) = self.profiles.iter().find(|profile| profile.name == name)
+                else {
+                    let names: Vec<&str> = self
+                        .profiles
+                        .iter()
+                        .map(|profile| profile.name.as_str())
+                        .collect();
+                    bail!(
+                        "There is no profile `{name}` in the config file (available: {})",
+                        if names.is_empty() {
+                            "none".to_string()
+                        } else {
+                            names.join(", ")
+                        }
+                    );
+                };
+                profile.settings.as_slice()
+            }
+            None => &[],
+        };
+        let mut problems = Vec::new();
+        let command = apply(command, &self.settings, &mut problems);
+        if self.jobs.is_empty() {
+            if let Some(name) = only {
+                bail!("The config file defines no jobs, so there is no job `{name}`");
+            }
+            let command = apply(command, profile, &mut problems);
+            self.check(problems)?;
+            return Ok(vec![(None, command)]);
+        }
+        let jobs: Vec<&Section> = match only {
+            Some(name) => vec![
+                self.jobs
+                    .iter()
+                    .find(|job| job.name == name)
+                    .with_context(|| format!("There is no job `{name}` in the config file"))?,
+            ],
+            None => self.jobs.iter().collect(),
+        };
+        let commands = jobs
+            .into_iter()
+            .map(|job| {
+                let command = apply(command.clone(), &job.settings, &mut problems);
+                (
+                    Some(job.name.clone()),
+                    apply(command, profile, &mut problems),
+                )
+            })
+            .collect();
+        self.check(problems)?;
+        Ok(commands)
+    }
+
+    /// Fails with all of `problems`, if there are any.
+    fn check(&self, mut problems: Vec<Problem>) -> Result<()> {
+        if problems.is_empty() {
+            return Ok(());
+        }
+        // Settings shared by all jobs are checked once per job.
+        problems.sort();
+        problems.dedup();
+        let problems: Vec<String> = problems
+            .into_iter()
+            .map(|problem| match problem {
+                (Some((line, column)), message) => {
+                    format!("line {line}, column {column}: {message}")
+                }
+                (None, message) => message,
+            })
+            .collect();
+        bail!(
+            "Invalid config file {}:\n  {}",
+            self.path.display(),
+            problems.join("\n  ")
+        )
+    }
+}
+
+fn flatten(prefix: &str, table: &Table, spans: Spans, settings: &mut Vec<Setting>) -> Result<()> {
+    for (key, value) in table {
+        let location = spans.locate(key);
+        let name = if prefix.is_empty() {
+            key.replace('_', "-")
+        } else {
+            format!("{prefix}-{}", key.replace('_', "-"))
+        };
+        let values = match value {
+            Value::Table(table) => {
+                flatten(&name, table, spans.table(key), settings)?;
+                continue;
+            }
+            Value::Array(items) => items
+                .iter()
+                .map(|item| {
+                    scalar(item).with_context(|| format!("`{name}` must be a list of values"))
+                })
+                .collect::<Result<_>>()?,
+            value => vec![scalar(value).expect("not a table or array")],
+        };
+        settings.push(Setting {
+            name,
+            values,
+            location,
+        });
+    }
+    Ok(())
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        Value::Datetime(d) => Some(d.to_string()),
+        Value::Array(_) | Value::Table(_) => None,
+    }
+}
+
+/// Installs the settings as defaults of the options they name, in every
+/// subcommand that has them. Settings that name no option, or have values
+/// the option rejects, are added to `problems` instead.
+fn apply(mut command: Command, settings: &[Setting], problems: &mut Vec<Problem>) -> Command {
+    for setting in settings {
+        let problem = |message: String| (setting.location, message);
+        // `[source] url = ...` flattens to `source-url`, which is `--source`.
+        let names = [
+            Some(setting.name.as_str()),
+            setting.name.strip_suffix("-url"),
+        ];
+        let mut targets = Vec::new();
+        let mut invalid = None;
+        for name in names.into_iter().flatten() {
+            for subcommand in command.get_subcommands() {
+                if let Some(arg) = subcommand
+                    .get_arguments()
+                    .find(|arg| arg.get_long() == Some(name))
+                {
+                    invalid = invalid.or_else(|| validate(arg, &setting.values));
+                    targets.push((subcommand.get_name().to_string(), arg.get_id().to_string()));
+                }
+            }
+            if !targets.is_empty() {
+                break;
+            }
+        }
+        if targets.is_empty() {
+            let suggestion = suggest(&command, &setting.name)
+                .map(|name| format!(" (did you mean `{name}`?)"))
+                .unwrap_or_default();
+            problems.push(problem(format!(
+                "unknown option `{}`{}",
+                setting.name, suggestion
+            )));
+            continue;
+        }
+        if let Some(message) = invalid {
+            problems.push(problem(format!("`{}` {}", setting.name, message)));
+            continue;
+        }
+        for (name, id) in targets {
+            command = set_default(command, &name, &id, setting.values.clone());
+        }
+    }
+    command
+}
+
+/// Why `values` are not valid for `arg`, if they are not.
+fn validate(arg: &Arg, values: &[String]) -> Option<String> {
+    let multiple = matches!(arg.get_action(), ArgAction::Append);
+    if values.len() != 1 && !multiple {
+        return Some("takes a single value, not a list".to_string());
+    }
+    // Parsing on its own keeps the other options, and what they conflict
+    // with or require, out of it.
+    let check = Command::new("check").no_binary_name(true).arg(
+        Arg::new("value")
+            .long("value")
+            .action(ArgAction::Set)
+            .value_parser(arg.get_value_parser().clone()),
+    );
+    for value in values {
+        let parts: Vec<&str> = match arg.get_value_delimiter() {
+            Some(delimiter) => value.split(delimiter).collect(),
+            None => vec![value.as_str()],
+        };
+        for part in parts {
+            let Err(e) = check.clone().try_get_matches_from(["--value", part]) else {
+                continue;
+            };
+            let reason = match std::error::Error::source(&e) {
+                Some(source) => source.to_string(),
+                None => {
+                    let possible: Vec<String> = arg
+                        .get_possible_values()
+                        .iter()
+                        .map(|value| value.get_name().to_string())
+                        .collect();
+                    if possible.is_empty() {
+                        "not accepted".to_string()
+                    } else {
+                        format!("expected one of {}", possible.join(", "))
+                    }
+                }
+            };
+            return Some(format!("has the invalid value `{part}`: {reason}"));
+        }
+    }
+    None
+}
+
+/// The option whose name is closest to `name`, if one is close enough to
+/// be a typo of it.
+fn suggest(command: &Command, name: &str) -> Option<String> {
+    command
+        .get_subcommands()
+        .flat_map(|subcommand| subcommand.get_arguments())
+        .filter_map(|arg| arg.get_long())
+        .map(|long| (edit_distance(name, long), long))
+        .filter(|&(distance, long)| distance <= 2.max(long.len() / 4))
+        .min()
+        .map(|(_, long)| long.to_string())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + (ca != cb) as usize)
+                .min(row[j] + 1)
+                .min(above + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// Makes `values` the default of argument `id` of `subcommand`, so that it
+/// counts as given without overriding the command line or its environment
+/// variable.
+pub fn set_default(command: Command, subcommand: &str, id: &str, values: Vec<String>) -> Command {
+    command.mut_subcommand(subcommand, |subcommand| {
+        // A default does not satisfy `required`, but a value given this
+        // way should.
+        let groups: Vec<String> = subcommand
+            .get_groups()
+            .filter(|group| group.get_args().any(|arg| arg == id))
+            .map(|group| group.get_id().to_string())
+            .collect();
+        let subcommand = groups.into_iter().fold(subcommand, |subcommand, group| {
+            subcommand.mut_group(group, |group| group.required(false))
+        });
+        subcommand.mut_arg(id, |arg| {
+            // Keep secrets out of `--help`.
+            let hide = arg.is_hide_env_values_set();
+            arg.default_values(values)
+                .required(false)
+                .hide_default_value(hide)
+        })
+    })
+}
+
+/// A config file with every option of `sync` commented out, at its default
+/// value or a placeholder, for `config print-default`.
+pub fn print_default(command: &Command) -> String {
+    let mut out = String::from(
+        "# restic-sync configuration file. Each key is the long name of an option\n\
+         # of `restic-sync sync`; uncomment the ones to set.\n",
+    );
+    for arg in &options(command) {
+        let mut help = arg
+            .get_help()
+            .map(|help| help.to_string())
+            .unwrap_or_default();
+        if let Some(env) = arg.get_env() {
+            let _ = write!(help, " (env: {})", env.to_string_lossy());
+        }
+        let defaults: Vec<String> = arg
+            .get_default_values()
+            .iter()
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect();
+        let value = if !defaults.is_empty() {
+            toml_value(arg, &defaults)
+        } else if matches!(arg.get_action(), ArgAction::Append) {
+            "[]".to_string()
+        } else {
+            let placeholder = arg
+                .get_value_names()
+                .and_then(|names| names.first())
+                .map_or_else(
+                    || arg.get_id().to_string().to_uppercase(),
+                    |name| name.to_string(),
+                );
+            toml_value(arg, &[format!("<{placeholder}>")])
+        };
+        out.push('\n');
+        for line in wrap(&help, 76) {
+            let _ = writeln!(out, "# {line}");
+        }
+        let _ = writeln!(out, "#{} = {}", arg.get_long().unwrap_or_default(), value);
+    }
+    out
+}
+
+/// The `sync` options that differ from their defaults in each of `jobs`,
+/// with where they come from, for `config print-effective`. `pristine` is
+/// the command line parser before the config file was applied.
+pub fn print_effective(jobs: Vec<(Option<String>, Command)>, pristine: &Command) -> Result<String> {
+    let mut out = String::from("# The sync options in effect, and where each comes from.\n");
+    for (name, command) in jobs {
+        let matches = restic_env::apply(command).try_get_matches_from(["restic-sync", "sync"])?;
+        let Some(matches) = matches.subcommand_matches("sync") else {
+            continue;
+        };
+        out.push('\n');
+        if let Some(name) = &name {
+            let _ = writeln!(out, "[[job]]\nname = {}", Value::String(name.clone()));
+        }
+        let mut any = false;
+        for arg in &options(pristine) {
+            let id = arg.get_id().as_str();
+            let Some(raw) = matches.get_raw(id) else {
+                continue;
+            };
+            let values: Vec<String> = raw
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect();
+            let env = arg.get_env().map(|env| env.to_string_lossy().into_owned());
+            let origin = match matches.value_source(id) {
+                Some(ValueSource::EnvVariable) => env.unwrap_or_default(),
+                Some(ValueSource::DefaultValue) => {
+                    let defaults: Vec<String> = arg
+                        .get_default_values()
+                        .iter()
+                        .map(|value| value.to_string_lossy().into_owned())
+                        .collect();
+                    if values == defaults {
+                        continue;
+                    }
+                    match env.as_deref().and_then(restic_env::stand_in) {
+                        Some(theirs) => theirs.to_string(),
+                        None => "config file".to_string(),
+                    }
+                }
+                _ => "command line".to_string(),
+            };
+            let value = if arg.is_hide_env_values_set() {
+                Value::String("<hidden>".to_string()).to_string()
+            } else {
+                toml_value(arg, &values)
+            };
+            let _ = writeln!(
+                out,
+                "{} = {}  # {}",
+                arg.get_long().unwrap_or_default(),
+                value,
+                origin
+            );
+            any = true;
+        }
+        if !any {
+            out.push_str("# Every option has its default value.\n");
+        }
+    }
+    Ok(out)
+}
+
+/// The options of `sync` that a config file can set, with the defaults
+/// that clap fills in for flags.
+fn options(command: &Command) -> Vec<Arg> {
+    let mut command = command.clone();
+    command.build();
+    command
+        .find_subcommand("sync")
+        .map(|sync| {
+            sync.get_arguments()
+                .filter(|arg| arg.get_long().is_some_and(|long| long != "help"))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// `values` of `arg` written as TOML: flags and numbers bare, anything else
+/// quoted, and a list for options that take several.
+fn toml_value(arg: &Arg, values: &[String]) -> String {
+    let flag = matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse);
+    let scalar = |value: &String| {
+        let number = value.parse::<i64>().is_ok_and(|n| n.to_string() == *value);
+        if flag || number {
+            value.clone()
+        } else {
+            Value::String(value.clone()).to_string()
+        }
+    };
+    if matches!(arg.get_action(), ArgAction::Append) {
+        let values: Vec<String> = values.iter().map(scalar).collect();
+        format!("[{}]", values.join(", "))
+    } else {
+        values.first().map(scalar).unwrap_or_default()
+    }
+}
+
+/// Splits `text` into lines of at most `width` characters, at spaces.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    for word in text.split_whitespace() {
+        let line = lines.last_mut().unwrap();
+        if !line.is_empty() && line.len() + 1 + word.len() > width {
+            lines.push(word.to_string());
+        } else {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+    use clap::{ArgMatches, CommandFactory};
+
+    /// Loads `contents` as the config file `name`.
+    fn config(name: &str, contents: &str) -> Result<Config> {
+        let path = std::env::temp_dir().join(format!(
+            "restic-sync-config-{}-{name}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, contents)?;
+        let config = load(&path);
+        std::fs::remove_file(&path)?;
+        config
+    }
+
+    /// The matches of `sync` with `args`, after the file `contents`.
+    fn sync(name: &str, contents: &str, args: &[&str]) -> Result<ArgMatches> {
+        let commands = config(name, contents)?.commands(Cli::command(), None, None)?;
+        let [(None, command)] = &commands[..] else {
+            panic!("expected a single command");
+        };
+        let args = ["restic-sync", "sync"].iter().chain(args);
+        let matches = command.clone().try_get_matches_from(args)?;
+        Ok(matches.subcommand_matches("sync").unwrap().clone())
+    }
+
+    const PAIR: &str = "source = \"https://a.example/\"\ndest = \"https://b.example/\"\n";
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let file = format!("{PAIR}max-failures = 5\n");
+        let max_failures = |args: &[&str]| {
+            *sync("precedence", &file, args)
+                .unwrap()
+                .get_one::<u64>("max_failures")
+                .unwrap()
+        };
+        assert_eq!(max_failures(&[]), 5);
+        // No other test reads this variable.
+        unsafe { std::env::set_var("REST_SYNC_MAX_FAILURES", "7") };
+        assert_eq!(max_failures(&[]), 7);
+        assert_eq!(max_failures(&["--max-failures", "9"]), 9);
+        unsafe { std::env::remove_var("REST_SYNC_MAX_FAILURES") };
+    }
+
+    #[test]
+    fn tables_and_underscores_name_the_long_options() {
+        let file = "[source]\nurl = \"https://a.example/\"\nuser = \"reader\"\n\n\
+                    [dest]\nurl = \"https://b.example/\"\n\n\
+                    file_retries = 4\n";
+        // `file_retries` here is `dest-file-retries`, which is no option.
+        assert!(sync("tables-nested", file, &[]).is_err());
+
+        let file = "file_retries = 4\n\n[source]\nurl = \"https://a.example/\"\nuser = \"reader\"\n\n\
+                    [dest]\nurl = \"https://b.example/\"\n";
+        let matches = sync("tables", file, &[]).unwrap();
+        assert_eq!(
+            matches.get_one::<String>("source").unwrap(),
+            "https://a.example/"
+        );
+        assert_eq!(matches.get_one::<String>("source_user").unwrap(), "reader");
+        assert_eq!(
+            matches.get_one::<String>("dest").unwrap(),
+            "https://b.example/"
+        );
+        assert_eq!(*matches.get_one::<u32>("file_retries").unwrap(), 4);
+    }
+
+    #[test]
+    fn jobs_layer_over_the_top_level_and_profiles_over_both() {
+        let file = format!(
+            "{PAIR}file-retries = 1\n\n\
+             [[job]]\nname = \"offsite\"\nfile-retries = 3\n\n\
+             [[job]]\nname = \"local\"\n\n\
+             [profile.test]\nfile-retries = 8\n"
+        );
+        let config = config("jobs", &file).unwrap();
+        let retries = |profile: Option<&str>| -> Vec<(String, u32)> {
+            config
+                .commands(Cli::command(), None, profile)
+                .unwrap()
+                .into_iter()
+                .map(|(name, command)| {
+                    let matches = command
+                        .try_get_matches_from(["restic-sync", "sync"])
+                        .unwrap();
+                    let sync = matches.subcommand_matches("sync").unwrap();
+                    (name.unwrap(), *sync.get_one::<u32>("file_retries").unwrap())
+                })
+                .collect()
+        };
+        assert_eq!(
+            retries(None),
+            [("offsite".to_string(), 3), ("local".to_string(), 1)]
+        );
+        assert_eq!(
+            retries(Some("test")),
+            [("offsite".to_string(), 8), ("local".to_string(), 8)]
+        );
+        assert!(
+            config
+                .commands(Cli::command(), Some("missing"), None)
+                .is_err()
+        );
+        assert!(
+            config
+                .commands(Cli::command(), None, Some("missing"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_location() {
+        let file = format!("{PAIR}file-retires = 3\nbogus = true\n");
+        let message = format!("{:#}", sync("unknown", &file, &[]).unwrap_err());
+        assert!(
+            message.contains(
+                "line 3, column 1: unknown option `file-retires` (did you mean `file-retries`?)"
+            ),
+            "{message}"
+        );
+        assert!(
+            message.contains("line 4, column 1: unknown option `bogus`"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn invalid_values_are_reported_with_their_location() {
+        let file = format!("{PAIR}file-retries = \"many\"\n");
+        let message = format!("{:#}", sync("invalid", &file, &[]).unwrap_err());
+        assert!(
+            message.contains("line 3, column 1: `file-retries` has the invalid value `many`"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn the_default_config_comments_out_every_option() {
+        let text = print_default(&Cli::command());
+        assert!(text.contains("\n#file-retries = 2\n"), "{text}");
+        assert!(text.contains("(env: REST_SYNC_FILE_RETRIES)"), "{text}");
+        assert!(
+            text.lines()
+                .all(|line| line.is_empty() || line.starts_with('#'))
+        );
+    }
+
+    #[test]
+    fn the_effective_config_names_where_each_value_comes_from() {
+        let commands = config("effective", &format!("{PAIR}file-retries = 4\n"))
+            .unwrap()
+            .commands(Cli::command(), None, None)
+            .unwrap();
+        let text = print_effective(commands, &Cli::command()).unwrap();
+        assert!(text.contains("file-retries = 4  # config file\n"), "{text}");
+        assert!(!text.contains("file-retry-delay"), "{text}");
+    }
+}
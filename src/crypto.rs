@@ -0,0 +1,236 @@
+//! Reading the encrypted files of a restic repository.
+//!
+//! Syncing copies files byte for byte and never needs the repository
+//! password, but some maintenance commands have to look inside them. This
+//! implements just enough of the restic format for that: unlocking the
+//! master key from a key file, and decrypting and decompressing files such
+//! as locks, snapshots and index files, and encrypting the index files
+//! written by `copy-snapshot` and by syncs that filter snapshots.
+
+use crate::Error;
+use crate::diagnose;
+use crate::list_files;
+use crate::redact::redact_url;
+use crate::repo::Repo;
+use aes::cipher::{BlockCipherEncrypt, KeyInit, KeyIvInit, StreamCipher};
+use aes::{Aes128, Aes256};
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use log::debug;
+use poly1305::Poly1305;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use zeroize::Zeroizing;
+
+const IV_SIZE: usize = 16;
+const MAC_SIZE: usize = 16;
+
+/// The encryption and authentication keys of a repository.
+pub struct Key {
+    encrypt: Zeroizing<[u8; 32]>,
+    mac_k: Zeroizing<[u8; 16]>,
+    mac_r: Zeroizing<[u8; 16]>,
+}
+
+/// A file in `keys/`, holding the master key encrypted with a key derived
+/// from the password.
+#[derive(Deserialize)]
+struct KeyFile {
+    kdf: String,
+    #[serde(rename = "N")]
+    n: u64,
+    r: u32,
+    p: u32,
+    salt: String,
+    data: String,
+}
+
+#[derive(Deserialize)]
+struct MasterKey {
+    mac: MacKey,
+    encrypt: String,
+}
+
+#[derive(Deserialize)]
+struct MacKey {
+    k: String,
+    r: String,
+}
+
+/// Unlocks the master key of `repo` by trying `password` on each of its
+/// key files.
+pub async fn open(repo: &Repo, password: &str) -> Result<Key> {
+    let keys = list_files(repo, "keys").await?;
+    if keys.is_empty() {
+        bail!("{} has no key files", redact_url(repo.url()));
+    }
+    for file in &keys {
+        let path = format!("keys/{}", file.name);
+        let resp = repo.get(&path).send().await?;
+        if !resp.status().is_success() {
+            bail!(Error::Http {
+                operation: "download",
+                url: redact_url(&repo.join(&path)),
+                status: resp.status(),
+            });
+        }
+        let headers = resp.headers().clone();
+        let bytes = resp.bytes().await?;
+        if let Some(what) = diagnose::foreign_response(&headers, &bytes) {
+            bail!(
+                "Downloading {} answered with {} instead of the key file; {}",
+                redact_url(&repo.join(&path)),
+                what,
+                diagnose::ADVICE
+            );
+        }
+        let key_file: KeyFile =
+            serde_json::from_slice(&bytes).with_context(|| format!("Invalid key file {path}"))?;
+        let password = Zeroizing::new(password.to_string());
+        // The key derivation is deliberately slow.
+        let unlocked = tokio::task::spawn_blocking(move || key_file.unlock(&password)).await??;
+        if let Some(key) = unlocked {
+            debug!("Unlocked {} with {}", redact_url(repo.url()), path);
+            return Ok(key);
+        }
+    }
+    bail!(
+        "The password does not open any key of {}",
+        redact_url(repo.url())
+    )
+}
+
+impl KeyFile {
+    /// The master key, or `None` if the password is wrong.
+    fn unlock(&self, password: &str) -> Result<Option<Key>> {
+        if self.kdf != "scrypt" {
+            bail!("Unsupported key derivation function `{}`", self.kdf);
+        }
+        if !self.n.is_power_of_two() {
+            bail!("Invalid scrypt parameter N = {}", self.n);
+        }
+        let params = scrypt::Params::new(self.n.trailing_zeros() as u8, self.r, self.p)
+            .context("Invalid scrypt parameters")?;
+        let salt = BASE64.decode(&self.salt).context("Invalid salt")?;
+        let mut derived = Zeroizing::new([0u8; 64]);
+        scrypt::scrypt(password.as_bytes(), &salt, &params, derived.as_mut())
+            .context("Key derivation failed")?;
+        let user_key = Key::from_parts(&derived[..32], &derived[32..48], &derived[48..])?;
+
+        let data = BASE64.decode(&self.data).context("Invalid key data")?;
+        let Ok(plain) = user_key.decrypt(&data) else {
+            return Ok(None);
+        };
+        let master: MasterKey = serde_json::from_slice(&plain).context("Invalid master key")?;
+        let decode = |value: &str| -> Result<Zeroizing<Vec<u8>>> {
+            Ok(Zeroizing::new(
+                BASE64.decode(value).context("Invalid master key")?,
+            ))
+        };
+        Key::from_parts(
+            &decode(&master.encrypt)?,
+            &decode(&master.mac.k)?,
+            &decode(&master.mac.r)?,
+        )
+        .map(Some)
+    }
+}
+
+impl Key {
+    fn from_parts(encrypt: &[u8], mac_k: &[u8], mac_r: &[u8]) -> Result<Self> {
+        let mut key = Key {
+            encrypt: Zeroizing::new([0; 32]),
+            mac_k: Zeroizing::new([0; 16]),
+            mac_r: Zeroizing::new([0; 16]),
+        };
+        if encrypt.len() != 32 || mac_k.len() != 16 || mac_r.len() != 16 {
+            bail!("Invalid key length");
+        }
+        key.encrypt.copy_from_slice(encrypt);
+        key.mac_k.copy_from_slice(mac_k);
+        key.mac_r.copy_from_slice(mac_r);
+        Ok(key)
+    }
+
+    /// Poly1305-AES of `data`, as restic uses it: the one-time key is `r`
+    /// together with the nonce encrypted under `k`.
+    fn mac(&self, nonce: &[u8; IV_SIZE], data: &[u8]) -> [u8; MAC_SIZE] {
+        let mut block = aes::Block::from(*nonce);
+        Aes128::new((&*self.mac_k).into()).encrypt_block(&mut block);
+        let mut one_time = Zeroizing::new([0u8; 32]);
+        one_time[..16].copy_from_slice(self.mac_r.as_ref());
+        one_time[16..].copy_from_slice(&block);
+        Poly1305::new((&*one_time).into())
+            .compute_unpadded(data)
+            .into()
+    }
+
+    /// Decrypts a blob or file: the IV, the AES-256-CTR ciphertext and its
+    /// MAC.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let Some((iv, rest)) = data.split_first_chunk::<IV_SIZE>() else {
+            bail!("Ciphertext is too short");
+        };
+        let Some((ciphertext, mac)) = rest.split_last_chunk::<MAC_SIZE>() else {
+            bail!("Ciphertext is too short");
+        };
+        // Not secret, so a plain comparison is fine.
+        if self.mac(iv, ciphertext) != *mac {
+            bail!("Ciphertext verification failed");
+        }
+        let mut plain = Zeroizing::new(ciphertext.to_vec());
+        ctr::Ctr128BE::<Aes256>::new((&*self.encrypt).into(), iv.into())
+            .apply_keystream(&mut plain);
+        Ok(plain)
+    }
+
+    /// Encrypts `plain` under a fresh random IV, the inverse of `decrypt`.
+    pub fn encrypt(&self, plain: &[u8]) -> Vec<u8> {
+        self.encrypt_with(rand::random(), plain)
+    }
+
+    fn encrypt_with(&self, iv: [u8; IV_SIZE], plain: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(IV_SIZE + plain.len() + MAC_SIZE);
+        data.extend_from_slice(&iv);
+        data.extend_from_slice(plain);
+        ctr::Ctr128BE::<Aes256>::new((&*self.encrypt).into(), (&iv).into())
+            .apply_keystream(&mut data[IV_SIZE..]);
+        let mac = self.mac(&iv, &data[IV_SIZE..]);
+        data.extend_from_slice(&mac);
+        data
+    }
+
+    /// Encrypts `plain` under an IV derived from it and the key, so that the
+    /// same plaintext always makes the same file.
+    pub fn encrypt_deterministic(&self, plain: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.encrypt.as_slice());
+        hasher.update(plain);
+        let iv: [u8; IV_SIZE] = hasher.finalize()[..IV_SIZE]
+            .try_into()
+            .expect("a SHA-256 is longer than an IV");
+        self.encrypt_with(iv, plain)
+    }
+
+    /// Decrypts a file that is not a pack, such as a lock, snapshot or
+    /// index, undoing the compression of version 2 repositories.
+    pub fn decrypt_file(&self, data: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+        let plain = self.decrypt(data)?;
+        match plain.first() {
+            // Uncompressed JSON, as written by version 1 repositories.
+            Some(b'{' | b'[') => Ok(plain),
+            Some(2) => {
+                let mut decoder = ruzstd::decoding::StreamingDecoder::new(&plain[1..])
+                    .map_err(|e| anyhow::anyhow!("Invalid compressed file: {e}"))?;
+                let mut out = Zeroizing::new(Vec::new());
+                decoder
+                    .read_to_end(&mut out)
+                    .context("Invalid compressed file")?;
+                Ok(out)
+            }
+            _ => bail!("Unknown file format"),
+        }
+    }
+}
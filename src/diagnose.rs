@@ -0,0 +1,111 @@
+//! Telling the answers of something in front of a REST server apart from
+//! its own.
+//!
+//! A captive portal, a login page or the error page of a reverse proxy
+//! answers with HTML where the server would list files as JSON or send a
+//! file, and the error that follows, e.g. that the listing is not valid
+//! JSON, reads like a problem with the repository. The answers here look
+//! at the content type and the start of the body to name what answered
+//! instead.
+
+use reqwest::header::{CONTENT_TYPE, HeaderMap};
+
+/// How much of the body is looked at.
+const SNIFF_LEN: usize = 4096;
+
+/// Words that a captive portal or a login page is likely to show.
+const PORTAL_WORDS: &[&str] = &[
+    "captive",
+    "portal",
+    "hotspot",
+    "wi-fi",
+    "wifi",
+    "log in",
+    "login",
+    "sign in",
+    "terms of use",
+    "accept the terms",
+];
+
+/// Names and phrases of the error pages of proxies and load balancers.
+const PROXY_WORDS: &[&str] = &[
+    "bad gateway",
+    "gateway timeout",
+    "service unavailable",
+    "nginx",
+    "cloudflare",
+    "squid",
+    "haproxy",
+    "varnish",
+    "envoy",
+    "traefik",
+    "caddy",
+    "apache",
+];
+
+/// What to check when something else answered.
+pub(crate) const ADVICE: &str = "check the repository URL, and whether a proxy, a firewall or a network login stands between here and the server";
+
+/// How much of the start and of the end of a body [`snippet`] shows.
+const SNIPPET_LEN: usize = 100;
+
+/// A short description of `body` for an error, its length with its start
+/// and, if it is longer, its end, where a body cut short shows.
+pub(crate) fn snippet(body: &str) -> String {
+    let chars = body.chars().count();
+    if chars <= 2 * SNIPPET_LEN {
+        return format!("{} bytes, {:?}", body.len(), body);
+    }
+    let start: String = body.chars().take(SNIPPET_LEN).collect();
+    let end: String = body.chars().skip(chars - SNIPPET_LEN).collect();
+    format!("{} bytes, {:?} … {:?}", body.len(), start, end)
+}
+
+/// What answered instead of the REST server, e.g. `a captive portal or
+/// login page ("Guest Wi-Fi")`, if the response with `headers` and `body`
+/// is a web page rather than an answer of the API.
+pub(crate) fn foreign_response(headers: &HeaderMap, body: &[u8]) -> Option<String> {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let text = String::from_utf8_lossy(&body[..body.len().min(SNIFF_LEN)]);
+    // ASCII only, so that positions in it are those in `text`.
+    let start = text.to_ascii_lowercase();
+    let markup = start.trim_start();
+    let html = ["<!doctype html", "<html", "<head", "<body", "<title"]
+        .iter()
+        .any(|tag| markup.starts_with(tag))
+        || (markup.starts_with('<') && content_type.starts_with("text/html"));
+    if !html {
+        return None;
+    }
+
+    let title = title(&text, &start);
+    let mentions = |words: &[&str]| words.iter().any(|word| start.contains(word));
+    let kind = if mentions(PORTAL_WORDS) {
+        "a captive portal or login page"
+    } else if mentions(PROXY_WORDS) {
+        "the error page of a proxy"
+    } else {
+        "a web page"
+    };
+    Some(match title {
+        Some(title) => format!("{} (\"{}\")", kind, title),
+        None => kind.to_string(),
+    })
+}
+
+/// The title of the HTML page in `text`, found in `lower`, its ASCII
+/// lowercase.
+fn title(text: &str, lower: &str) -> Option<String> {
+    let open = lower.find("<title")?;
+    let start = open + lower[open..].find('>')? + 1;
+    let end = start + lower[start..].find("</title")?;
+    let title = text[start..end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!title.is_empty()).then_some(title)
+}
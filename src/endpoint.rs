@@ -0,0 +1,615 @@
+//! Per-endpoint connection options.
+//!
+//! The source and destination are configured independently, each with its
+//! own set of flags (`--source-*` and `--dest-*`) generated from a single
+//! definition so that the two sides can never drift apart.
+
+use anyhow::{Context, Result, bail};
+use crate::circuit::CircuitBreaker;
+use crate::hooks::shell;
+use crate::netrc::Netrc;
+use crate::redact::{Redacted, redact_url};
+use crate::repo::InjectedTransport;
+use crate::tls::{Pin, TlsVersion};
+use clap::ValueEnum;
+use reqwest::Url;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+/// Declares the clap argument struct for one side of the sync.
+macro_rules! endpoint_args {
+    ($name:ident, $prefix:literal, $env:literal, $desc:literal) => {
+        #[derive(clap::Args, Debug, Clone, Default)]
+        pub struct $name {
+            #[arg(
+                long = concat!($prefix, "-auth"),
+                id = concat!($prefix, "_auth"),
+                env = concat!("REST_SYNC_", $env, "_AUTH"),
+                value_name = "SCHEME",
+                value_enum,
+                default_value_t = AuthScheme::Auto,
+                help = concat!("Authentication scheme for the ", $desc)
+            )]
+            pub auth: AuthScheme,
+
+            #[arg(
+                long = concat!($prefix, "-user"),
+                id = concat!($prefix, "_user"),
+                env = concat!("REST_SYNC_", $env, "_USER"),
+                value_name = "USER",
+                help = concat!("Username for HTTP basic auth to the ", $desc)
+            )]
+            pub user: Option<String>,
+
+            #[arg(
+                long = concat!($prefix, "-password"),
+                id = concat!($prefix, "_password"),
+                env = concat!("REST_SYNC_", $env, "_PASSWORD"),
+                value_name = "PASSWORD",
+                hide_env_values = true,
+                conflicts_with_all = [concat!($prefix, "_password_file"), concat!($prefix, "_password_command")],
+                help = concat!(
+                    "Password for HTTP basic auth to the ", $desc,
+                    " (prefer the env var or a password file)"
+                )
+            )]
+            pub password: Option<Redacted<Secret>>,
+
+            #[arg(
+                long = concat!($prefix, "-password-file"),
+                id = concat!($prefix, "_password_file"),
+                env = concat!("REST_SYNC_", $env, "_PASSWORD_FILE"),
+                value_name = "FILE",
+                help = concat!(
+                    "File to read the ", $desc,
+                    " password from (e.g., /run/secrets/pw or /dev/fd/3)"
+                )
+            )]
+            pub password_file: Option<PathBuf>,
+
+            #[arg(
+                long = concat!($prefix, "-password-command"),
+                id = concat!($prefix, "_password_command"),
+                env = concat!("REST_SYNC_", $env, "_PASSWORD_COMMAND"),
+                value_name = "CMD",
+                conflicts_with = concat!($prefix, "_password_file"),
+                help = concat!(
+                    "Shell command printing the ", $desc,
+                    " password (e.g., \"pass show backup/rest\")"
+                )
+            )]
+            pub password_command: Option<String>,
+
+            #[arg(
+                long = concat!($prefix, "-token"),
+                id = concat!($prefix, "_token"),
+                env = concat!("REST_SYNC_", $env, "_TOKEN"),
+                value_name = "TOKEN",
+                hide_env_values = true,
+                help = concat!("Bearer token for the ", $desc, " (prefer the env var or a token file)")
+            )]
+            pub token: Option<Redacted<Secret>>,
+
+            #[arg(
+                long = concat!($prefix, "-token-file"),
+                id = concat!($prefix, "_token_file"),
+                env = concat!("REST_SYNC_", $env, "_TOKEN_FILE"),
+                value_name = "FILE",
+                conflicts_with = concat!($prefix, "_token"),
+                help = concat!("File to read the ", $desc, " bearer token from")
+            )]
+            pub token_file: Option<PathBuf>,
+
+            #[arg(
+                long = concat!($prefix, "-netrc"),
+                id = concat!($prefix, "_netrc"),
+                env = concat!("REST_SYNC_", $env, "_NETRC"),
+                help = concat!(
+                    "Look up missing ", $desc,
+                    " credentials in ~/.netrc (or $NETRC)"
+                )
+            )]
+            pub netrc: bool,
+
+            #[arg(
+                long = concat!($prefix, "-netrc-file"),
+                id = concat!($prefix, "_netrc_file"),
+                env = concat!("REST_SYNC_", $env, "_NETRC_FILE"),
+                value_name = "FILE",
+                help = concat!("Look up missing ", $desc, " credentials in this netrc file")
+            )]
+            pub netrc_file: Option<PathBuf>,
+
+            #[arg(
+                long = concat!($prefix, "-cacert"),
+                id = concat!($prefix, "_cacert"),
+                env = concat!("REST_SYNC_", $env, "_CACERT"),
+                value_name = "FILE",
+                help = concat!(
+                    "PEM file with CA certificates to trust for the ", $desc,
+                    ", in addition to the system roots"
+                )
+            )]
+            pub cacert: Option<PathBuf>,
+
+            #[arg(
+                long = concat!($prefix, "-client-cert"),
+                id = concat!($prefix, "_client_cert"),
+                env = concat!("REST_SYNC_", $env, "_CLIENT_CERT"),
+                value_name = "FILE",
+                help = concat!(
+                    "Client certificate for mutual TLS with the ", $desc,
+                    " (PEM, or PKCS#12 with a .p12/.pfx extension)"
+                )
+            )]
+            pub client_cert: Option<PathBuf>,
+
+            #[arg(
+                long = concat!($prefix, "-client-key"),
+                id = concat!($prefix, "_client_key"),
+                env = concat!("REST_SYNC_", $env, "_CLIENT_KEY"),
+                value_name = "FILE",
+                requires = concat!($prefix, "_client_cert"),
+                help = concat!(
+                    "PEM private key for the ", $desc,
+                    " client certificate, if not bundled with it"
+                )
+            )]
+            pub client_key: Option<PathBuf>,
+
+            #[arg(
+                long = concat!($prefix, "-client-cert-password-file"),
+                id = concat!($prefix, "_client_cert_password_file"),
+                env = concat!("REST_SYNC_", $env, "_CLIENT_CERT_PASSWORD_FILE"),
+                value_name = "FILE",
+                requires = concat!($prefix, "_client_cert"),
+                help = concat!("File to read the ", $desc, " PKCS#12 bundle password from")
+            )]
+            pub client_cert_password_file: Option<PathBuf>,
+
+            #[arg(
+                long = concat!($prefix, "-insecure-tls"),
+                id = concat!($prefix, "_insecure_tls"),
+                env = concat!("REST_SYNC_", $env, "_INSECURE_TLS"),
+                help = concat!(
+                    "Skip TLS certificate verification for the ", $desc,
+                    " (INSECURE, for lab setups only)"
+                )
+            )]
+            pub insecure_tls: bool,
+
+            #[arg(
+                long = concat!($prefix, "-pin"),
+                id = concat!($prefix, "_pin"),
+                env = concat!("REST_SYNC_", $env, "_PIN"),
+                value_name = "PIN",
+                value_delimiter = ',',
+                help = concat!(
+                    "Expected certificate of the ", $desc,
+                    ": sha256/<base64> SPKI hash or hex SHA-256 fingerprint (repeatable)"
+                )
+            )]
+            pub pin: Vec<Pin>,
+
+            #[arg(
+                long = concat!($prefix, "-tls-min-version"),
+                id = concat!($prefix, "_tls_min_version"),
+                env = concat!("REST_SYNC_", $env, "_TLS_MIN_VERSION"),
+                value_name = "VERSION",
+                value_enum,
+                help = concat!("Minimum TLS version for the ", $desc)
+            )]
+            pub tls_min_version: Option<TlsVersion>,
+
+            #[arg(
+                long = concat!($prefix, "-tls-max-version"),
+                id = concat!($prefix, "_tls_max_version"),
+                env = concat!("REST_SYNC_", $env, "_TLS_MAX_VERSION"),
+                value_name = "VERSION",
+                value_enum,
+                help = concat!("Maximum TLS version for the ", $desc)
+            )]
+            pub tls_max_version: Option<TlsVersion>,
+
+            #[arg(
+                long = concat!($prefix, "-tls-ciphers"),
+                id = concat!($prefix, "_tls_ciphers"),
+                env = concat!("REST_SYNC_", $env, "_TLS_CIPHERS"),
+                value_name = "SUITES",
+                value_delimiter = ',',
+                help = concat!(
+                    "Comma-separated TLS cipher suites allowed for the ", $desc,
+                    " (e.g., TLS13_AES_256_GCM_SHA384)"
+                )
+            )]
+            pub tls_ciphers: Vec<String>,
+
+            #[arg(
+                long = concat!($prefix, "-proxy"),
+                id = concat!($prefix, "_proxy"),
+                env = concat!("REST_SYNC_", $env, "_PROXY"),
+                value_name = "URL",
+                help = concat!(
+                    "Proxy for requests to the ", $desc,
+                    " (http://, https://, socks5://, or socks5h:// to resolve names through the proxy)"
+                )
+            )]
+            pub proxy: Option<String>,
+
+            #[arg(
+                long = concat!($prefix, "-no-proxy"),
+                id = concat!($prefix, "_no_proxy"),
+                env = concat!("REST_SYNC_", $env, "_NO_PROXY"),
+                conflicts_with = concat!($prefix, "_proxy"),
+                help = concat!("Connect to the ", $desc, " directly, ignoring proxy environment variables")
+            )]
+            pub no_proxy: bool,
+
+            #[arg(
+                long = concat!($prefix, "-header"),
+                id = concat!($prefix, "_header"),
+                env = concat!("REST_SYNC_", $env, "_HEADER"),
+                value_name = "NAME: VALUE",
+                value_delimiter = '\n',
+                hide_env_values = true,
+                help = concat!(
+                    "Extra HTTP header sent with every request to the ", $desc,
+                    " (repeatable; newline-separated in the env var)"
+                )
+            )]
+            pub header: Vec<Header>,
+        }
+
+        impl From<$name> for EndpointOptions {
+            fn from(args: $name) -> Self {
+                EndpointOptions {
+                    auth: args.auth,
+                    user: args.user,
+                    password: args.password,
+                    password_file: args.password_file,
+                    password_command: args.password_command,
+                    token: args.token,
+                    token_file: args.token_file,
+                    netrc: args.netrc,
+                    netrc_file: args.netrc_file,
+                    cacert: args.cacert,
+                    client_cert: args.client_cert,
+                    client_key: args.client_key,
+                    client_cert_password_file: args.client_cert_password_file,
+                    insecure_tls: args.insecure_tls,
+                    pins: args.pin,
+                    tls_min_version: args.tls_min_version,
+                    tls_max_version: args.tls_max_version,
+                    tls_ciphers: args.tls_ciphers,
+                    proxy: args.proxy,
+                    no_proxy: args.no_proxy,
+                    headers: args.header,
+                    resolve: Vec::new(),
+                    circuit: None,
+                    user_agent: None,
+                    recycle_after: None,
+                    stall_timeout: None,
+                    transport: None,
+                }
+            }
+        }
+    };
+}
+
+endpoint_args!(SourceArgs, "source", "SOURCE", "source repository");
+endpoint_args!(DestArgs, "dest", "DEST", "destination repository");
+endpoint_args!(OtherArgs, "other", "OTHER", "second mirror");
+
+/// How requests to an endpoint are authenticated.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AuthScheme {
+    /// Use whichever credentials are configured, if any
+    #[default]
+    Auto,
+    /// Never send credentials, even if some are configured
+    None,
+    /// HTTP basic auth; fails if no username is configured
+    Basic,
+    /// `Authorization: Bearer` token; fails if no token is configured
+    Bearer,
+}
+
+/// Connection options for a single repository endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct EndpointOptions {
+    pub auth: AuthScheme,
+    pub user: Option<String>,
+    pub password: Option<Redacted<Secret>>,
+    pub password_file: Option<PathBuf>,
+    pub password_command: Option<String>,
+    pub token: Option<Redacted<Secret>>,
+    pub token_file: Option<PathBuf>,
+    pub netrc: bool,
+    pub netrc_file: Option<PathBuf>,
+    pub cacert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub client_cert_password_file: Option<PathBuf>,
+    pub insecure_tls: bool,
+    pub pins: Vec<Pin>,
+    pub tls_min_version: Option<TlsVersion>,
+    pub tls_max_version: Option<TlsVersion>,
+    pub tls_ciphers: Vec<String>,
+    pub proxy: Option<String>,
+    pub no_proxy: bool,
+    pub headers: Vec<Header>,
+    /// These come from global options rather than per-endpoint flags.
+    pub resolve: Vec<ResolveOverride>,
+    pub circuit: Option<CircuitBreaker>,
+    pub user_agent: Option<String>,
+    pub recycle_after: Option<Duration>,
+    pub stall_timeout: Option<Duration>,
+    /// Set by programs using the library, never from the command line.
+    pub transport: Option<InjectedTransport>,
+}
+
+/// A `NAME: VALUE` header given on the command line.
+#[derive(Clone)]
+pub struct Header {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| "expected NAME: VALUE".to_string())?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| format!("invalid header name {:?}", name.trim()))?;
+        let mut value = HeaderValue::from_str(value.trim())
+            .map_err(|_| format!("invalid value for header {name}"))?;
+        // Headers are often used for access tokens; keep them out of logs.
+        value.set_sensitive(true);
+        Ok(Header { name, value })
+    }
+}
+
+/// A curl-style `HOST:PORT:ADDRESS[,ADDRESS...]` DNS override.
+#[derive(Debug, Clone)]
+pub struct ResolveOverride {
+    pub host: String,
+    pub port: u16,
+    pub addrs: Vec<IpAddr>,
+}
+
+impl FromStr for ResolveOverride {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let (Some(host), Some(port), Some(addrs)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err("expected HOST:PORT:ADDRESS".to_string());
+        };
+        if host.is_empty() {
+            return Err("missing host name".to_string());
+        }
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid port {port:?}"))?;
+        let addrs = addrs
+            .split(',')
+            .map(|addr| {
+                let addr = addr.trim_start_matches('[').trim_end_matches(']');
+                addr.parse()
+                    .map_err(|_| format!("invalid IP address {addr:?}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ResolveOverride {
+            host: host.to_ascii_lowercase(),
+            port,
+            addrs,
+        })
+    }
+}
+
+impl std::fmt::Debug for Header {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Header")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Credentials attached to every request sent to an endpoint. Secrets are
+/// wiped from memory when dropped.
+#[derive(Clone)]
+pub enum Auth {
+    Basic {
+        user: String,
+        password: Option<Secret>,
+    },
+    Bearer {
+        token: Secret,
+    },
+}
+
+pub type Secret = Zeroizing<String>;
+
+impl std::fmt::Debug for Auth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Auth::Basic { user, .. } => f
+                .debug_struct("Basic")
+                .field("user", user)
+                .finish_non_exhaustive(),
+            Auth::Bearer { .. } => f.debug_struct("Bearer").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl EndpointOptions {
+    /// An HTTP client builder with this endpoint's transport settings for
+    /// connecting to `url`.
+    pub fn client_builder(&self, url: &Url) -> Result<reqwest::ClientBuilder> {
+        let tls = crate::tls::client_config(self)?;
+        let headers = self
+            .headers
+            .iter()
+            .map(|header| (header.name.clone(), header.value.clone()))
+            .collect::<HeaderMap>();
+        let mut builder = reqwest::Client::builder()
+            .tls_backend_preconfigured(tls)
+            .default_headers(headers);
+        // Without either option, reqwest honors HTTP_PROXY, HTTPS_PROXY,
+        // ALL_PROXY and NO_PROXY from the environment.
+        if let Some(url) = &self.proxy {
+            let proxy = reqwest::Proxy::all(url)
+                .with_context(|| format!("Invalid proxy URL {}", redact_url(url)))?
+                .no_proxy(reqwest::NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        } else if self.no_proxy {
+            builder = builder.no_proxy();
+        }
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        // A connection that stops acknowledging what is sent, the keep-alive
+        // probes of an idle one included, is closed instead of waited on.
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(stall_timeout) = self.stall_timeout {
+            builder = builder.tcp_user_timeout(stall_timeout);
+        }
+        // reqwest overrides whole host names, so only the entries for this
+        // endpoint's port are applied, as curl would.
+        if let Some(host) = url.host_str() {
+            let port = url.port_or_known_default();
+            for entry in &self.resolve {
+                if entry.host.eq_ignore_ascii_case(host) && Some(entry.port) == port {
+                    let addrs: Vec<_> = entry
+                        .addrs
+                        .iter()
+                        .map(|&ip| SocketAddr::new(ip, entry.port))
+                        .collect();
+                    builder = builder.resolve_to_addrs(host, &addrs);
+                }
+            }
+        }
+        Ok(builder)
+    }
+
+    /// Reads the netrc file if it was enabled. A missing default file is not
+    /// an error, matching curl.
+    fn load_netrc(&self) -> Result<Option<Netrc>> {
+        if let Some(path) = &self.netrc_file {
+            return Netrc::load(path).map(Some);
+        }
+        if !self.netrc {
+            return Ok(None);
+        }
+        match Netrc::default_path() {
+            Some(path) if path.exists() => Netrc::load(&path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Resolves the credentials for this endpoint, reading secrets from
+    /// disk. `url_auth` holds credentials that were embedded in the URL;
+    /// `host` is used to look up anything still missing in the netrc file.
+    pub fn resolve_auth(&self, host: Option<&str>, url_auth: Option<Auth>) -> Result<Option<Auth>> {
+        if self.auth == AuthScheme::None {
+            return Ok(None);
+        }
+
+        let password = match (&self.password_file, &self.password_command) {
+            (Some(path), _) => Some(read_secret_file(path)?),
+            (None, Some(cmd)) => Some(run_secret_command(cmd)?),
+            (None, None) => self.password.clone().map(|password| password.0),
+        };
+        let basic = match (&self.user, password) {
+            (Some(user), password) => Some(Auth::Basic {
+                user: user.clone(),
+                password,
+            }),
+            (None, Some(_)) => bail!("A password was given without a username"),
+            (None, None) => None,
+        };
+        let token = match &self.token_file {
+            Some(path) => Some(read_secret_file(path)?),
+            None => self.token.clone().map(|token| token.0),
+        };
+        let bearer = token.map(|token| Auth::Bearer { token });
+        let configured = match (basic, bearer) {
+            (Some(_), Some(_)) => bail!("Both a username and a bearer token were configured"),
+            (basic, bearer) => basic.or(bearer),
+        };
+        if configured.is_some() && url_auth.is_some() {
+            bail!("Credentials were given both in the URL and as options; use only one");
+        }
+
+        let mut auth = configured.or(url_auth);
+        if let Some(host) = host
+            && let Some(netrc) = self.load_netrc()?
+        {
+            auth = match auth {
+                None => netrc.lookup(host, None).and_then(|(login, password)| {
+                    Some(Auth::Basic {
+                        user: login?.to_string(),
+                        password: password.map(|password| Zeroizing::new(password.to_string())),
+                    })
+                }),
+                Some(Auth::Basic { user, password: None }) => {
+                    let password = netrc
+                        .lookup(host, Some(&user))
+                        .and_then(|(_, password)| password.map(|p| Zeroizing::new(p.to_string())));
+                    Some(Auth::Basic { user, password })
+                }
+                auth => auth,
+            };
+        }
+
+        match self.auth {
+            AuthScheme::Basic if !matches!(auth, Some(Auth::Basic { .. })) => {
+                bail!("Basic auth was requested but no username is configured")
+            }
+            AuthScheme::Bearer if !matches!(auth, Some(Auth::Bearer { .. })) => {
+                bail!("Bearer auth was requested but no token is configured")
+            }
+            _ => {}
+        }
+        Ok(auth)
+    }
+}
+
+/// Reads a secret from a file, ignoring a trailing newline.
+pub fn read_secret_file(path: &Path) -> Result<Secret> {
+    let secret = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read secret from {}", path.display()))?;
+    Ok(trim_newline(Zeroizing::new(secret)))
+}
+
+/// Runs a credential helper and returns what it printed, ignoring a
+/// trailing newline. Its stderr is passed through so prompts and errors
+/// stay visible.
+pub fn run_secret_command(cmd: &str) -> Result<Secret> {
+    let output = shell(cmd)
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run credential command: {cmd}"))?;
+    if !output.status.success() {
+        bail!("Credential command failed with {}: {cmd}", output.status);
+    }
+    let stdout = Zeroizing::new(output.stdout);
+    let secret = std::str::from_utf8(&stdout)
+        .with_context(|| format!("Credential command printed invalid UTF-8: {cmd}"))?;
+    Ok(trim_newline(Zeroizing::new(secret.to_string())))
+}
+
+fn trim_newline(mut secret: Secret) -> Secret {
+    let trimmed = secret.trim_end_matches(['\r', '\n']).len();
+    secret.truncate(trimmed);
+    secret
+}
@@ -0,0 +1,142 @@
+//! Errors a sync fails with, for callers that handle some causes on their
+//! own.
+//!
+//! Functions return [`anyhow::Result`], whose errors carry the ones here
+//! through any context added on the way up: find them with
+//! `error.downcast_ref::<restic_sync::Error>()`. [`exit_code`] is how the
+//! command tells them apart to its caller.
+
+use crate::{Cancelled, Failed};
+use reqwest::StatusCode;
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// The destination has a config file of another repository.
+    #[error("Destination config file already exists and DOES NOT MATCH source config! Aborting to prevent repository corruption.")]
+    ConfigMismatch,
+
+    /// With `--no-create-dest`, the destination repository does not exist.
+    #[error("The destination repository {url} does not exist; create it first (e.g., with `restic-sync init`) or drop --no-create-dest")]
+    DestinationMissing { url: String },
+
+    /// A file did not pass its `--verification`: `expected` and `actual`
+    /// are hashes, or sizes with `size-only`.
+    #[error("Blob verification failed for {name}. Expected: {expected}, Got: {actual}")]
+    VerificationFailed {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+
+    /// A server answered with an error, after any retries. The message says
+    /// whether it is [`permanent`](Error::is_permanent).
+    #[error("Failed to {operation} {url}: {status} ({})", if is_permanent(*status) { "permanent, not retried" } else { "retryable" })]
+    Http {
+        /// What was requested, e.g. "download" or "upload to".
+        operation: &'static str,
+        /// The URL, without credentials.
+        url: String,
+        status: StatusCode,
+    },
+
+    /// A request received no response for the `--stall-timeout`. The retries
+    /// take it as transient.
+    #[error("{url} stalled, answering nothing for {}", humantime::format_duration(*timeout))]
+    Stalled {
+        /// The URL, without credentials.
+        url: String,
+        timeout: Duration,
+    },
+
+    /// The destination has no space left for an upload.
+    #[error("The destination ran out of space uploading {url}; free some space there or grow it, and sync again")]
+    DestinationFull {
+        /// The URL of the upload, without credentials.
+        url: String,
+    },
+
+    /// Some of several jobs failed, each logged when it did.
+    #[error("{failed} of {total} jobs failed")]
+    JobsFailed { failed: usize, total: usize },
+}
+
+impl Error {
+    /// Whether trying again cannot help: the server refused the credentials
+    /// or does not have the file, or the destination is full, so the failure
+    /// is not retried.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Error::Http { status, .. } => is_permanent(*status),
+            Error::DestinationFull { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// Phrases of the errors that storage servers answer a full disk or quota
+/// with, besides `507 Insufficient Storage`.
+const FULL_PHRASES: &[&str] = &[
+    "no space left",
+    "disk full",
+    "disk quota exceeded",
+    "quota exceeded",
+    "insufficient storage",
+    "not enough space",
+];
+
+/// Whether an upload answered with `status` and `body` failed because the
+/// storage is full: a 507, or a server error saying so, as older versions
+/// of rest-server answer a full disk with a 500.
+pub(crate) fn is_storage_full(status: StatusCode, body: &str) -> bool {
+    let body = body.to_ascii_lowercase();
+    status == StatusCode::INSUFFICIENT_STORAGE
+        || (status.is_server_error() && FULL_PHRASES.iter().any(|phrase| body.contains(phrase)))
+}
+
+/// Whether a response with `status` fails on every try: 401, 403 and 404.
+/// Other errors, such as 5xx and timeouts, are taken as transient.
+pub(crate) fn is_permanent(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN | StatusCode::NOT_FOUND
+    )
+}
+
+/// The exit status of the command for `error`:
+///
+/// - 3 if the destination belongs to another repository
+/// - 4 if a file failed its verification, even if the run went on with the
+///   others
+/// - 5 if the destination does not exist, with `--no-create-dest`
+/// - 6 if the destination ran out of space
+/// - 130 if the sync was cancelled, like a process stopped by Ctrl-C
+/// - 1 for anything else
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    if error.downcast_ref::<Cancelled>().is_some() {
+        return 130;
+    }
+    if let Some(Failed(report)) = error.downcast_ref::<Failed>() {
+        let any = |found: fn(&Error) -> bool| {
+            report
+                .failures
+                .iter()
+                .any(|failure| failure.error.downcast_ref::<Error>().is_some_and(found))
+        };
+        return if any(|e| matches!(e, Error::DestinationFull { .. })) {
+            6
+        } else if any(|e| matches!(e, Error::VerificationFailed { .. })) {
+            4
+        } else {
+            1
+        };
+    }
+    match error.downcast_ref::<Error>() {
+        Some(Error::ConfigMismatch) => 3,
+        Some(Error::VerificationFailed { .. }) => 4,
+        Some(Error::DestinationMissing { .. }) => 5,
+        Some(Error::DestinationFull { .. }) => 6,
+        _ => 1,
+    }
+}
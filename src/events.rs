@@ -0,0 +1,78 @@
+//! The progress of a run as JSON lines, for `sync --json`.
+//!
+//! Each line is an object with the `version` of the format, the `event` and
+//! the `job` it belongs to, as `restic-sync schema events` describes them.
+//! They go to stdout as they happen, while log messages stay on stderr.
+
+use crate::{FileEvent, SyncObserver, SyncReport, TypeReport, partial_report, schema};
+use serde_json::{Value, json};
+use std::io::Write;
+
+pub struct JsonEvents {
+    /// The name of the job in the config file.
+    job: Option<String>,
+}
+
+impl JsonEvents {
+    pub fn new(job: Option<String>) -> Self {
+        JsonEvents { job }
+    }
+
+    fn emit(&self, event: &str, fields: Value) {
+        let mut line = json!({
+            "version": schema::VERSION,
+            "event": event,
+            "job": self.job,
+        });
+        if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+            line.extend(fields);
+        }
+        let mut stdout = std::io::stdout().lock();
+        // A reader that went away does not stop the sync.
+        let _ = writeln!(stdout, "{}", line).and_then(|()| stdout.flush());
+    }
+}
+
+fn file_fields(file: &FileEvent) -> Value {
+    json!({
+        "action": file.action,
+        "file_type": file.file_type,
+        "name": file.name,
+        "size": file.size,
+    })
+}
+
+impl SyncObserver for JsonEvents {
+    fn on_file_start(&self, file: &FileEvent) {
+        self.emit("file_start", file_fields(file));
+    }
+
+    fn on_file_complete(&self, file: &FileEvent, bytes: u64) {
+        let mut fields = file_fields(file);
+        fields["bytes"] = bytes.into();
+        self.emit("file_complete", fields);
+    }
+
+    fn on_file_error(&self, file: &FileEvent, error: &anyhow::Error) {
+        let mut fields = file_fields(file);
+        fields["error"] = format!("{:#}", error).into();
+        self.emit("file_error", fields);
+    }
+
+    fn on_type_complete(&self, report: &TypeReport) {
+        self.emit("type_complete", json!({ "report": report }));
+    }
+
+    fn on_run_complete(&self, result: &anyhow::Result<SyncReport>) {
+        let fields = match result {
+            Ok(report) => json!({ "ok": true, "report": report, "error": null }),
+            Err(e) => json!({
+                "ok": false,
+                // What a cancelled or partly failed run copied.
+                "report": partial_report(e),
+                "error": format!("{:#}", e),
+            }),
+        };
+        self.emit("run_complete", fields);
+    }
+}
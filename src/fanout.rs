@@ -0,0 +1,66 @@
+//! Downloads shared by the jobs that copy one source to several
+//! destinations.
+//!
+//! When jobs of a config file have the same source, the first to need a
+//! file downloads it, jobs that need it meanwhile wait for that download,
+//! and later ones get the bytes while they are among the most recent. Each
+//! job still checks the bytes with its own verification before uploading
+//! them. The jobs only meet while they run at the same time, so
+//! `--parallel-jobs` should let them.
+
+use anyhow::Result;
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// How much of the downloads that are done is kept for the jobs that come
+/// to them later.
+const MAX_SHARED_BYTES: usize = 256 * 1024 * 1024;
+
+/// The downloads from one source, shared by the jobs that sync it.
+#[derive(Debug, Default)]
+pub struct SharedDownloads {
+    files: Mutex<Files>,
+}
+
+#[derive(Debug, Default)]
+struct Files {
+    /// Downloads that are still running, by path.
+    running: HashMap<String, Arc<OnceCell<Bytes>>>,
+    /// The latest downloads that are done, oldest first.
+    done: VecDeque<(String, Bytes)>,
+}
+
+impl SharedDownloads {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The contents of `path`, from another job if it downloaded them or is
+    /// downloading them, or else from `download`.
+    pub async fn fetch(&self, path: &str, download: impl Future<Output = Result<Bytes>>) -> Result<Bytes> {
+        let cell = {
+            let mut files = self.files.lock().unwrap();
+            if let Some((_, bytes)) = files.done.iter().find(|(done, _)| done == path) {
+                return Ok(bytes.clone());
+            }
+            files.running.entry(path.to_string()).or_default().clone()
+        };
+        // If the download that others wait for fails, the next of them tries.
+        let bytes = cell.get_or_try_init(|| download).await?.clone();
+
+        let mut files = self.files.lock().unwrap();
+        if files.running.remove(path).is_some() {
+            files.done.push_back((path.to_string(), bytes.clone()));
+            let mut total: usize = files.done.iter().map(|(_, bytes)| bytes.len()).sum();
+            while total > MAX_SHARED_BYTES
+                && let Some((_, dropped)) = files.done.pop_front()
+            {
+                total -= dropped.len();
+            }
+        }
+        Ok(bytes)
+    }
+}
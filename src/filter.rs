@@ -0,0 +1,499 @@
+//! Choosing the files a command works on.
+//!
+//! `--only-types`/`--exclude-types` select whole file types, and
+//! `--include`/`--exclude` single files by name, e.g. to mirror a few
+//! snapshots ahead of the bulk data, or to shard the data files between
+//! mirrors by the first characters of their names, which `--shard` does
+//! evenly for any number of mirrors. `--min-size` and
+//! `--max-size` select them by size, to copy the small files over a slow
+//! link first and leave the large packs for later. A `--skip-list` names
+//! files never to upload, such as the corrupt packs of a source that
+//! awaits repair.
+
+use crate::FILE_TYPES;
+use clap::builder::PossibleValuesParser;
+use anyhow::{Context, Result};
+use log::warn;
+use restic_sync_core::{Comparison, FileInfo};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// What `--only-types` and `--exclude-types` choose from: the directories
+/// and the config file, in the order they are synced by default.
+pub(crate) const ALL_TYPES: [&str; 6] = ["config", "data", "keys", "locks", "snapshots", "index"];
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct FileFilter {
+    /// Only work on these file types (comma-separated: config, data, keys,
+    /// locks, snapshots, index)
+    #[arg(long, env = "REST_SYNC_ONLY_TYPES", value_name = "TYPES", value_delimiter = ',', value_parser = PossibleValuesParser::new(ALL_TYPES), conflicts_with = "exclude_types")]
+    only_types: Vec<String>,
+
+    /// Leave these file types alone (comma-separated)
+    #[arg(long, env = "REST_SYNC_EXCLUDE_TYPES", value_name = "TYPES", value_delimiter = ',', value_parser = PossibleValuesParser::new(ALL_TYPES))]
+    exclude_types: Vec<String>,
+
+    /// Only work on the files whose names match: a glob with `*`, `?` and `[...]`, a
+    /// name prefix, or either as TYPE/PATTERN to apply to one type only
+    /// (repeatable; comma-separated in the env var)
+    #[arg(
+        long,
+        env = "REST_SYNC_INCLUDE",
+        value_name = "PATTERN",
+        value_delimiter = ','
+    )]
+    include: Vec<NamePattern>,
+
+    /// Leave the files whose names match alone (repeatable; comma-separated
+    /// in the env var)
+    #[arg(
+        long,
+        env = "REST_SYNC_EXCLUDE",
+        value_name = "PATTERN",
+        value_delimiter = ','
+    )]
+    exclude: Vec<NamePattern>,
+
+    /// Only work on the files of at least this size, e.g. "64MiB"
+    #[arg(long, env = "REST_SYNC_MIN_SIZE", value_name = "SIZE", value_parser = parse_size)]
+    min_size: Option<u64>,
+
+    /// Only work on the files of at most this size, e.g. "16MiB", to leave
+    /// the large packs for another run
+    #[arg(long, env = "REST_SYNC_MAX_SIZE", value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
+
+    /// Only work on slice I of N of the files, e.g. "2/3", split by their
+    /// names so that N instances each sync their own
+    #[arg(long, env = "REST_SYNC_SHARD", value_name = "I/N")]
+    shard: Option<Shard>,
+}
+
+impl FileFilter {
+    /// Whether `file_type` (one of `ALL_TYPES`) is selected.
+    pub fn includes_type(&self, file_type: &str) -> bool {
+        (self.only_types.is_empty() || self.only_types.iter().any(|t| t == file_type))
+            && !self.exclude_types.iter().any(|t| t == file_type)
+    }
+
+    /// The selected directories, in the order they are synced.
+    pub fn file_types(&self) -> impl Iterator<Item = &'static str> + '_ {
+        FILE_TYPES.iter().copied().filter(|t| self.includes_type(t))
+    }
+
+    /// Whether the file `name` of a selected type is selected. Include
+    /// patterns only restrict the types they apply to, so
+    /// `--include snapshots/1a2b` still syncs all the other types.
+    pub fn includes(&self, file_type: &str, name: &str) -> bool {
+        let mut includes = self
+            .include
+            .iter()
+            .filter(|pattern| pattern.applies_to(file_type))
+            .peekable();
+        let included = includes.peek().is_none() || includes.any(|pattern| pattern.matches(name));
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| pattern.applies_to(file_type) && pattern.matches(name))
+            && self.shard.as_ref().is_none_or(|shard| shard.includes(name))
+    }
+
+    /// Whether a file of `size` bytes is selected.
+    pub fn includes_size(&self, size: u64) -> bool {
+        self.min_size.is_none_or(|min| size >= min) && self.max_size.is_none_or(|max| size <= max)
+    }
+
+    /// Drops the files of `comparison` that are not selected, by name and by
+    /// size.
+    pub fn apply(&self, file_type: &str, comparison: &mut Comparison) {
+        comparison.retain(|name| self.includes(file_type, name));
+        comparison.retain_sizes(|size| self.includes_size(size));
+    }
+
+    /// Sets `--only-types` and `--exclude-types`, which name types of
+    /// `ALL_TYPES`.
+    pub fn set_types(&mut self, only: &[String], exclude: &[String]) -> Result<(), String> {
+        if !only.is_empty() && !exclude.is_empty() {
+            return Err("only some types and excluding others cannot be combined".to_string());
+        }
+        if let Some(unknown) = only.iter().chain(exclude).find(|t| !ALL_TYPES.contains(&t.as_str())) {
+            return Err(format!(
+                "unknown file type `{unknown}` (expected one of {})",
+                ALL_TYPES.join(", ")
+            ));
+        }
+        self.only_types = only.to_vec();
+        self.exclude_types = exclude.to_vec();
+        Ok(())
+    }
+
+    /// Adds an `--include` pattern.
+    pub fn add_include(&mut self, pattern: &str) -> Result<(), String> {
+        self.include.push(pattern.parse()?);
+        Ok(())
+    }
+
+    /// Adds an `--exclude` pattern.
+    pub fn add_exclude(&mut self, pattern: &str) -> Result<(), String> {
+        self.exclude.push(pattern.parse()?);
+        Ok(())
+    }
+}
+
+/// The order of `--type-order`: the types of `order` first, then the others
+/// in their usual order. The config comes before the data, so that a
+/// destination with another config stops the run before it writes packs,
+/// and the data before the snapshots and index that refer to it.
+pub fn type_order(order: &[String]) -> Result<Vec<&'static str>, String> {
+    let mut types = Vec::new();
+    for name in order {
+        let Some(&file_type) = ALL_TYPES.iter().find(|t| **t == name) else {
+            return Err(format!(
+                "unknown file type `{name}` (expected one of {})",
+                ALL_TYPES.join(", ")
+            ));
+        };
+        if types.contains(&file_type) {
+            return Err(format!("`{file_type}` is given twice"));
+        }
+        types.push(file_type);
+    }
+    types.extend(ALL_TYPES.iter().filter(|t| !order.iter().any(|name| name == *t)));
+    let position = |file_type| types.iter().position(|t| *t == file_type);
+    for (before, after) in [("config", "data"), ("data", "snapshots"), ("data", "index")] {
+        if position(before) > position(after) {
+            return Err(format!("`{before}` has to come before `{after}`"));
+        }
+    }
+    Ok(types)
+}
+
+/// A size such as "512", "64KiB", "1.5G" or "2TiB", in bytes and their
+/// binary multiples.
+pub(crate) fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let unit = unit.trim();
+    let shift = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        "t" | "tb" | "tib" => 40,
+        _ => return Err(format!("unknown unit `{unit}` (expected B, KiB, MiB, GiB or TiB)")),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{value}`"))?;
+    Ok((number * (1u64 << shift) as f64) as u64)
+}
+
+/// The files of a `--skip-list`, which are never uploaded.
+#[derive(Debug)]
+pub struct SkipList {
+    path: PathBuf,
+    /// `type/name`, or just the name for a file of any type.
+    names: HashSet<String>,
+    /// What the plans left out because of the list.
+    skipped: Mutex<Vec<String>>,
+}
+
+impl SkipList {
+    /// Reads the list in `path`: one `TYPE/NAME` or `NAME` a line, anything
+    /// after it being a comment, as are the lines starting with `#`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let list = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the skip list {}", path.display()))?;
+        let names = list
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_whitespace().next())
+            .map(str::to_string)
+            .collect();
+        Ok(SkipList {
+            path: path.to_path_buf(),
+            names,
+            skipped: Mutex::default(),
+        })
+    }
+
+    fn includes(&self, file_type: &str, name: &str) -> bool {
+        self.names.contains(name) || self.names.contains(&format!("{file_type}/{name}"))
+    }
+
+    /// Drops the listed files from what `comparison` would upload. A listed
+    /// file that the destination has is kept even by `--prune`.
+    pub fn apply(&self, file_type: &str, comparison: &mut Comparison) {
+        let mut skipped = self.skipped.lock().unwrap();
+        let mut keep = |file: &FileInfo| {
+            if !self.includes(file_type, &file.name) {
+                return true;
+            }
+            warn!("[{}] Leaving out {}, which is on the skip list", file_type, file.name);
+            skipped.push(format!(
+                "{}/{} is on the skip list {} and was not synced",
+                file_type,
+                file.name,
+                self.path.display()
+            ));
+            false
+        };
+        comparison.missing.retain(|file| keep(file));
+        comparison.mismatched.retain(|(file, _)| keep(file));
+    }
+
+    /// What the plans so far left out.
+    pub fn take_skipped(&self) -> Vec<String> {
+        std::mem::take(&mut self.skipped.lock().unwrap())
+    }
+}
+
+/// A `--shard`, one of `count` slices of the files.
+#[derive(Debug, Clone)]
+pub struct Shard {
+    /// Counted from 1, as given.
+    index: u64,
+    count: u64,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid shard `{s}` (expected I/N, e.g. 2/3)");
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index: u64 = index.trim().parse().map_err(|_| invalid())?;
+        let count: u64 = count.trim().parse().map_err(|_| invalid())?;
+        if index == 0 || index > count {
+            return Err(format!("shard {index} of {count} does not exist (the first is 1/{count})"));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl Shard {
+    /// Whether `name` falls into this slice. Names are SHA-256 digests, so
+    /// their first 16 hex digits spread them evenly; other names are hashed
+    /// first.
+    fn includes(&self, name: &str) -> bool {
+        let prefix = name
+            .get(..16)
+            .and_then(|prefix| u64::from_str_radix(prefix, 16).ok())
+            .unwrap_or_else(|| {
+                let digest = Sha256::digest(name);
+                u64::from_be_bytes(digest[..8].try_into().expect("a SHA-256 is longer than 8 bytes"))
+            });
+        prefix % self.count == self.index - 1
+    }
+}
+
+/// An `--include` or `--exclude` pattern.
+#[derive(Debug, Clone)]
+pub struct NamePattern {
+    file_type: Option<String>,
+    pattern: String,
+}
+
+impl FromStr for NamePattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Names are hex digests, so a slash can only follow a type.
+        let (file_type, pattern) = match s.split_once('/') {
+            Some((file_type, pattern)) => {
+                if !FILE_TYPES.contains(&file_type) {
+                    return Err(format!(
+                        "unknown file type `{file_type}` (expected one of {})",
+                        FILE_TYPES.join(", ")
+                    ));
+                }
+                (Some(file_type.to_string()), pattern)
+            }
+            None => (None, s),
+        };
+        if pattern.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        Ok(NamePattern {
+            file_type,
+            pattern: pattern.to_string(),
+        })
+    }
+}
+
+impl NamePattern {
+    fn applies_to(&self, file_type: &str) -> bool {
+        self.file_type.as_deref().is_none_or(|t| t == file_type)
+    }
+
+    /// A pattern without wildcards is a prefix, as hex names are rarely
+    /// written out in full.
+    fn matches(&self, name: &str) -> bool {
+        if self.pattern.contains(['*', '?', '[']) {
+            glob_match(self.pattern.as_bytes(), name.as_bytes())
+        } else {
+            name.starts_with(&self.pattern)
+        }
+    }
+}
+
+/// Matches `*`, `?` and `[...]` against the whole of `name`, backtracking
+/// to the last `*` on a mismatch.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        let matched = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(&pattern[p..], name[n]).map(|len| p + len),
+            Some(&c) => (c == name[n]).then_some(p + 1),
+            None => None,
+        };
+        match (matched, star) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            }
+            (None, Some((star_p, star_n))) => {
+                p = star_p + 1;
+                n = star_n + 1;
+                star = Some((star_p, star_n + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the `[...]` class at the start of `pattern`, such as
+/// `[0-7]` or `[!ab]`, and returns the length of the class if it matches.
+/// A `[` without a closing `]` only matches itself.
+fn match_class(pattern: &[u8], c: u8) -> Option<usize> {
+    let Some(end) = pattern
+        .iter()
+        .skip(2)
+        .position(|&b| b == b']')
+        .map(|i| i + 2)
+    else {
+        return (c == b'[').then_some(1);
+    };
+    let (negated, mut set) = match pattern[1] {
+        b'!' | b'^' => (true, &pattern[2..end]),
+        _ => (false, &pattern[1..end]),
+    };
+    let mut found = false;
+    while let Some(&first) = set.first() {
+        if let [_, b'-', last, ..] = set {
+            found |= (first..=*last).contains(&c);
+            set = &set[3..];
+        } else {
+            found |= first == c;
+            set = &set[1..];
+        }
+    }
+    (found != negated).then_some(end + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        filter: FileFilter,
+    }
+
+    fn filter(args: &[&str]) -> FileFilter {
+        Cli::try_parse_from(std::iter::once("restic-sync").chain(args.iter().copied()))
+            .unwrap()
+            .filter
+    }
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        pattern.parse::<NamePattern>().unwrap().matches(name)
+    }
+
+    #[test]
+    fn patterns_without_wildcards_are_prefixes() {
+        assert!(matches("1a2b", "1a2b3c"));
+        assert!(matches("1a2b3c", "1a2b3c"));
+        assert!(!matches("2b3c", "1a2b3c"));
+    }
+
+    #[test]
+    fn globs_match_the_whole_name() {
+        assert!(matches("1a*", "1a2b3c"));
+        assert!(!matches("2b*", "1a2b3c"));
+        assert!(matches("*3c", "1a2b3c"));
+        assert!(!matches("*2b", "1a2b3c"));
+        assert!(matches("*2b*", "1a2b3c"));
+        assert!(matches("1a2b3c*", "1a2b3c"));
+    }
+
+    #[test]
+    fn stars_match_any_run_of_characters() {
+        assert!(matches("*", "1a2b3c"));
+        assert!(matches("**", "1a2b3c"));
+        assert!(matches("1a**3c", "1a2b3c"));
+        assert!(matches("1*2*3*", "1a2b3c"));
+        assert!(matches("1a*b*c", "1a2b3b4c"));
+        assert!(!matches("1a*b*d", "1a2b3b4c"));
+    }
+
+    #[test]
+    fn question_marks_and_classes_match_one_character() {
+        assert!(matches("1?2b??", "1a2b3c"));
+        assert!(!matches("1?2b?", "1a2b3c"));
+        assert!(matches("[0-3]a*", "1a2b3c"));
+        assert!(!matches("[4-7]a*", "1a2b3c"));
+        assert!(matches("[!4-7]a*", "1a2b3c"));
+        assert!(matches("[^0ab]a*", "1a2b3c"));
+        assert!(matches("1[a]2*", "1a2b3c"));
+        // Without its `]`, a `[` is an ordinary character.
+        assert!(matches("[1*", "[1a"));
+        assert!(!matches("[1*", "1a"));
+    }
+
+    #[test]
+    fn type_qualified_patterns_apply_to_their_type_only() {
+        let filter = filter(&["--include", "snapshots/1a*", "--exclude", "data/ff"]);
+        assert!(filter.includes("snapshots", "1a2b"));
+        assert!(!filter.includes("snapshots", "2b1a"));
+        // Includes for other types do not restrict this one.
+        assert!(filter.includes("index", "2b1a"));
+        assert!(!filter.includes("data", "ff00"));
+        assert!(filter.includes("index", "ff00"));
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let filter = filter(&["--include", "1a,2b", "--exclude", "1a2b"]);
+        assert!(filter.includes("data", "1a00"));
+        assert!(filter.includes("index", "2b00"));
+        assert!(!filter.includes("data", "3c00"));
+        assert!(!filter.includes("data", "1a2b00"));
+    }
+
+    #[test]
+    fn malformed_patterns_are_refused() {
+        assert!("bogus/1a".parse::<NamePattern>().is_err());
+        assert!("config/1a".parse::<NamePattern>().is_err());
+        assert!("".parse::<NamePattern>().is_err());
+        assert!("data/".parse::<NamePattern>().is_err());
+    }
+}
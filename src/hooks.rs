@@ -0,0 +1,137 @@
+//! External commands run around each sync.
+//!
+//! Hooks are run through the platform shell with environment variables
+//! describing the job and, after the sync, its result:
+//!
+//! - `REST_SYNC_SOURCE`, `REST_SYNC_DEST`
+//! - `REST_SYNC_STATUS`: `started`, `success`, or `failure`
+//! - `REST_SYNC_SUMMARY`, `REST_SYNC_ERROR`
+//! - `REST_SYNC_UPLOADED_FILES`, `REST_SYNC_UPLOADED_BYTES`,
+//!   `REST_SYNC_DELETED_FILES`, `REST_SYNC_DURATION_SECS`
+
+use crate::redact::redact_url;
+use crate::{SyncOptions, SyncReport, normalize_url};
+use anyhow::{Context, Result, bail};
+use log::info;
+use tokio::process::Command;
+
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    source: String,
+    dest: String,
+    pre: Option<String>,
+    post: Option<String>,
+    failure: Option<String>,
+}
+
+impl Hooks {
+    pub fn from_args(args: &SyncOptions) -> Result<Self> {
+        let repos = args.repos.urls()?;
+        Ok(Hooks {
+            source: redact_url(&normalize_url(&repos.source)),
+            dest: redact_url(&normalize_url(&repos.dest)),
+            pre: args.pre_hook.clone(),
+            post: args.post_hook.clone(),
+            failure: args.failure_hook.clone(),
+        })
+    }
+
+    /// Runs the pre-hook. An error means the sync must not start.
+    pub async fn pre(&self) -> Result<()> {
+        match &self.pre {
+            Some(cmd) => self.run("pre", cmd, None).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn post(&self, result: &Result<SyncReport>) -> Result<()> {
+        match &self.post {
+            Some(cmd) => self.run("post", cmd, Some(result)).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn failure(&self, result: &Result<SyncReport>) -> Result<()> {
+        match &self.failure {
+            Some(cmd) => self.run("failure", cmd, Some(result)).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn run(&self, name: &str, cmd: &str, result: Option<&Result<SyncReport>>) -> Result<()> {
+        info!("Running {} hook: {}", name, cmd);
+
+        let mut command = Command::from(shell(cmd));
+        command
+            .env("REST_SYNC_SOURCE", &self.source)
+            .env("REST_SYNC_DEST", &self.dest);
+        match result {
+            None => {
+                command.env("REST_SYNC_STATUS", "started");
+            }
+            Some(Ok(report)) => {
+                command
+                    .env("REST_SYNC_STATUS", "success")
+                    .env("REST_SYNC_SUMMARY", report.to_string())
+                    .env(
+                        "REST_SYNC_UPLOADED_FILES",
+                        report.uploaded_files.to_string(),
+                    )
+                    .env(
+                        "REST_SYNC_UPLOADED_BYTES",
+                        report.uploaded_bytes.to_string(),
+                    )
+                    .env("REST_SYNC_DELETED_FILES", report.deleted_files.to_string())
+                    .env(
+                        "REST_SYNC_DURATION_SECS",
+                        format!("{:.3}", report.duration.as_secs_f64()),
+                    );
+                if let Some(divergence) = &report.divergence {
+                    command
+                        .env(
+                            "REST_SYNC_BEHIND_FILES",
+                            divergence.behind_files().to_string(),
+                        )
+                        .env(
+                            "REST_SYNC_BEHIND_BYTES",
+                            divergence.behind_bytes().to_string(),
+                        )
+                        .env(
+                            "REST_SYNC_EXTRA_FILES",
+                            divergence.extra_files().to_string(),
+                        );
+                }
+            }
+            Some(Err(e)) => {
+                command
+                    .env("REST_SYNC_STATUS", "failure")
+                    .env("REST_SYNC_SUMMARY", format!("Sync failed: {:#}", e))
+                    .env("REST_SYNC_ERROR", format!("{:#}", e));
+            }
+        }
+
+        let status = command
+            .status()
+            .await
+            .with_context(|| format!("Failed to spawn {} hook", name))?;
+        if !status.success() {
+            bail!("{} hook failed with {}", name, status);
+        }
+        Ok(())
+    }
+}
+
+/// A command that runs `cmd` through the platform shell.
+#[cfg(unix)]
+pub fn shell(cmd: &str) -> std::process::Command {
+    let mut command = std::process::Command::new("sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+#[cfg(windows)]
+pub fn shell(cmd: &str) -> std::process::Command {
+    let mut command = std::process::Command::new("cmd");
+    command.arg("/C").arg(cmd);
+    command
+}
@@ -1,307 +1,327 @@
 use anyhow::{bail, Context, Result};
-use clap::Parser;
-use log::{debug, info, warn};
-use reqwest::{Client, StatusCode};
-use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
-use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
-use serde::Deserialize;
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use log::{error, warn};
+use restic_sync::commands::{
+    self, CheckConfigArgs, CompareArgs, CopySnapshotArgs, DiffArgs, HistoryArgs, InitArgs,
+    PlanArgs, PruneLocksArgs, StatsArgs, VerifyArgs,
+};
+use restic_sync::{output, schema, status, tui, CancellationToken, Error, SyncClient, SyncOptions};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+mod config;
+mod restic_env;
 
 /// Synchronizes a Restic REST repository to another.
-#[derive(Parser, Debug, Clone)]
+#[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    /// Source Restic REST repository URL
-    #[arg(long, env = "REST_SYNC_SOURCE")]
-    source: String,
-
-    /// Destination Restic REST repository URL
-    #[arg(long, env = "REST_SYNC_DEST")]
-    dest: String,
-
-    /// Delete files in the destination that do not exist in the source
-    #[arg(long, default_value_t = false)]
-    prune: bool,
-
-    /// Cron expression for periodic sync (e.g., "0 0 * * * *")
-    #[arg(long, env = "REST_SYNC_CRON")]
-    cron: Option<String>,
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// TOML file with default values for any of the options
+    #[arg(long, global = true, env = "REST_SYNC_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Only run the job with this name from the config file
+    #[arg(long, global = true, env = "REST_SYNC_JOB", value_name = "NAME", requires = "config")]
+    job: Option<String>,
+
+    /// Apply the profile with this name from the config file
+    #[arg(long, global = true, env = "REST_SYNC_PROFILE", value_name = "NAME", requires = "config")]
+    profile: Option<String>,
+
+    /// Print more: -v logs every file, -vv adds debug messages, -vvv those
+    /// of the libraries
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only print errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
-struct FileInfo {
-    name: String,
-    size: u64,
+/// Options of [`Cli`] that may precede the subcommand, and are needed before
+/// the arguments can be parsed.
+const GLOBAL_OPTIONS: &[&str] = &["--config", "--job", "--profile"];
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Synchronize the source repository to the destination (the default)
+    Sync(Box<SyncOptions>),
+    /// Compare the repositories and fail if the destination is missing or has damaged files
+    Verify(Box<VerifyArgs>),
+    /// Show which files a sync would upload and delete, without changing anything
+    Diff(Box<DiffArgs>),
+    /// Show file counts and sizes per type of a repository, or compare two
+    Stats(Box<StatsArgs>),
+    /// Compare the source with two mirrors and show which one disagrees
+    Compare(Box<CompareArgs>),
+    /// Create the destination repository if it does not exist yet and check that it is usable
+    Init(Box<InitArgs>),
+    /// Copy one snapshot with just the packs and index entries it needs
+    CopySnapshot(Box<CopySnapshotArgs>),
+    /// Delete destination locks that no longer exist in the source, or old locks from either repository
+    PruneLocks(Box<PruneLocksArgs>),
+    /// Validate the sync options and probe both repositories without transferring data
+    CheckConfig(Box<CheckConfigArgs>),
+    /// Carry a sync in a bundle between a host that reaches the source and one that reaches the destination
+    Plan(PlanArgs),
+    /// Print a config file with the default options, or the options in effect
+    Config(ConfigArgs),
+    /// Check the status file of a scheduled sync, e.g. as a Docker HEALTHCHECK
+    Healthcheck(HealthcheckArgs),
+    /// List the past runs recorded in the state directory, or show one of them
+    History(Box<HistoryArgs>),
+    /// Print the JSON Schema of the run report, the progress events of `sync --json`, plan files or destination listings
+    Schema(SchemaArgs),
+    /// Print a shell completion script
+    Completions(CompletionsArgs),
+    /// Write man pages for restic-sync and its commands
+    Man(ManArgs),
 }
 
-const FILE_TYPES: &[&str] = &["data", "keys", "locks", "snapshots", "index"];
-
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
-    let args = Args::parse();
-
-    if let Some(cron_expr) = &args.cron {
-        use tokio_cron_scheduler::{Job, JobScheduler};
-        
-        info!("Starting scheduled sync with cron: {}", cron_expr);
-        let sched = JobScheduler::new().await?;
-        
-        let args_clone = args.clone();
-        let job = Job::new_async(cron_expr.as_str(), move |uuid, _l| {
-            let args = args_clone.clone();
-            Box::pin(async move {
-                info!("Running scheduled sync job {}", uuid);
-                if let Err(e) = run_sync(&args).await {
-                    warn!("Scheduled sync failed: {:?}", e);
-                }
-            })
-        })?;
-        
-        sched.add(job).await?;
-        sched.start().await?;
-        
-        // Wait forever
-        tokio::signal::ctrl_c().await?;
-        info!("Shutting down scheduled sync...");
-    } else {
-        run_sync(&args).await?;
-    }
-
-    Ok(())
+#[derive(clap::Args, Debug, Clone)]
+struct ConfigArgs {
+    #[command(subcommand)]
+    command: ConfigCommand,
 }
 
-async fn run_sync(args: &Args) -> Result<()> {
-    let source = normalize_url(&args.source);
-    let dest = normalize_url(&args.dest);
-
-    info!("Source: {}", source);
-    info!("Dest: {}", dest);
-    info!("Prune: {}", args.prune);
-
-    let retry_policy = ExponentialBackoff::builder().build_with_max_retries(5);
-    let client = ClientBuilder::new(Client::new())
-        .with(RetryTransientMiddleware::new_with_policy(retry_policy))
-        .build();
-
-    // 1. Initialize destination repository
-    init_dest(&client, &dest).await?;
-
-    // 2. Sync config file
-    sync_config(&client, &source, &dest).await?;
-
-    // 3. Sync each file type
-    for file_type in FILE_TYPES {
-        sync_type(&client, &source, &dest, file_type, args.prune).await?;
-    }
-
-    info!("Synchronization complete.");
-    Ok(())
+#[derive(Subcommand, Debug, Clone)]
+enum ConfigCommand {
+    /// Print a config file with every sync option commented out, at its default
+    PrintDefault,
+    /// Print the sync options that the config file, the environment and
+    /// restic's variables set, for each job, and where each comes from
+    PrintEffective,
 }
 
-fn normalize_url(url: &str) -> String {
-    if url.ends_with('/') {
-        url.to_string()
-    } else {
-        format!("{}/", url)
-    }
+#[derive(clap::Args, Debug, Clone)]
+struct SchemaArgs {
+    /// Which output to describe
+    #[arg(value_enum)]
+    schema: schema::Schema,
 }
 
-async fn init_dest(client: &ClientWithMiddleware, dest: &str) -> Result<()> {
-    let url = format!("{}?create=true", dest);
-    info!("Ensuring destination repository exists: {}", url);
-    let resp = client.post(&url).send().await?;
-    if !resp.status().is_success() {
-        bail!("Failed to create/verify dest repository: {}", resp.status());
-    }
-    Ok(())
+#[derive(clap::Args, Debug, Clone)]
+struct CompletionsArgs {
+    /// Shell to complete in
+    #[arg(value_enum)]
+    shell: clap_complete::Shell,
 }
 
-async fn sync_config(client: &ClientWithMiddleware, source: &str, dest: &str) -> Result<()> {
-    let source_url = format!("{}config", source);
-    let dest_url = format!("{}config", dest);
-
-    info!("Syncing config file");
-
-    let resp = client.get(&source_url).send().await?;
-    if !resp.status().is_success() {
-        if resp.status() == StatusCode::NOT_FOUND {
-            warn!("Config file not found in source repository.");
-            return Ok(());
-        }
-        bail!("Failed to fetch config from source: {}", resp.status());
-    }
+#[derive(clap::Args, Debug, Clone)]
+struct ManArgs {
+    /// Directory to write the pages to
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    out_dir: PathBuf,
+}
 
-    let config_bytes = resp.bytes().await?;
+#[derive(clap::Args, Debug, Clone)]
+struct HealthcheckArgs {
+    /// Status file written by the scheduled sync
+    #[arg(long, env = "REST_SYNC_STATUS_FILE")]
+    status_file: PathBuf,
 
-    let post_resp = client.post(&dest_url).body(config_bytes.clone()).send().await?;
-    if !post_resp.status().is_success() {
-        if post_resp.status() == StatusCode::FORBIDDEN {
-            // rest-server returns 403 when trying to overwrite an existing config.
-            // We MUST verify that the destination config matches the source config.
-            debug!("Config file exists (403). Fetching destination config to ensure match.");
-            let dest_get = client.get(&dest_url).send().await?;
-            if dest_get.status().is_success() {
-                let dest_bytes = dest_get.bytes().await?;
-                if dest_bytes != config_bytes {
-                    bail!("Destination config file already exists and DOES NOT MATCH source config! Aborting to prevent repository corruption.");
-                }
-                info!("Destination config file matches source config.");
-                return Ok(());
-            } else {
-                bail!("Failed to read existing configuration from destination to verify it: {}", dest_get.status());
-            }
-        }
-        bail!("Failed to save config to destination: {}", post_resp.status());
-    }
+    /// Maximum age of the scheduler heartbeat
+    #[arg(long, default_value = "2m", value_parser = humantime::parse_duration)]
+    max_heartbeat_age: Duration,
 
-    Ok(())
+    /// Maximum time since the last successful sync (e.g., "26h")
+    #[arg(long, env = "REST_SYNC_HEALTHCHECK_MAX_AGE", value_parser = humantime::parse_duration)]
+    max_age: Option<Duration>,
 }
 
-async fn list_files(client: &ClientWithMiddleware, repo: &str, file_type: &str) -> Result<Vec<FileInfo>> {
-    let url = format!("{}{}/", repo, file_type);
-    debug!("Listing files for {}: {}", file_type, url);
-
-    let resp = client
-        .get(&url)
-        .header("Accept", "application/vnd.x.restic.rest.v2")
-        .send()
-        .await?;
 
-    if !resp.status().is_success() {
-        if resp.status() == StatusCode::NOT_FOUND {
-            return Ok(vec![]);
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(restic_sync::exit_code(&e))
         }
-        bail!("Failed to list generic files {}: {}", url, resp.status());
     }
-
-    // Attempt to parse as v2 JSON array
-    let text = resp.text().await?;
-    let items: Vec<FileInfo> = serde_json::from_str(&text).with_context(|| {
-        format!(
-            "Failed to parse v2 JSON response from {} for type {}",
-            url, file_type
-        )
-    })?;
-
-    Ok(items)
 }
 
-async fn sync_type(
-    client: &ClientWithMiddleware,
-    source: &str,
-    dest: &str,
-    file_type: &str,
-    prune: bool,
-) -> Result<()> {
-    info!("Syncing type: {}", file_type);
-
-    let source_items = list_files(client, source, file_type).await?;
-    let dest_items = list_files(client, dest, file_type).await?;
-
-    let source_map: HashMap<String, u64> = source_items
-        .into_iter()
-        .map(|item| (item.name, item.size))
-        .collect();
-    let dest_map: HashMap<String, u64> = dest_items
-        .into_iter()
-        .map(|item| (item.name, item.size))
+async fn run() -> Result<()> {
+    let args = with_default_subcommand(std::env::args_os());
+    let commands = match config::raw_option(&args, "--config", "REST_SYNC_CONFIG") {
+        Some(path) => {
+            let only = config::raw_option(&args, "--job", "REST_SYNC_JOB");
+            let only = only.as_ref().map(|name| name.to_string_lossy());
+            let profile = config::raw_option(&args, "--profile", "REST_SYNC_PROFILE");
+            let profile = profile.as_ref().map(|name| name.to_string_lossy());
+            config::load(Path::new(&path))?.commands(
+                Cli::command(),
+                only.as_deref(),
+                profile.as_deref(),
+            )?
+        }
+        None => vec![(None, Cli::command())],
+    };
+    let clis: Vec<(Option<String>, Cli)> = commands
+        .iter()
+        .map(|(name, command)| {
+            let matches = restic_env::apply(command.clone()).get_matches_from(&args);
+            let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+            (name.clone(), cli)
+        })
         .collect();
-
-    // Identify missing
-    let mut to_download = Vec::new();
-    for (name, size) in &source_map {
-        if let Some(dest_size) = dest_map.get(name) {
-            if size != dest_size {
-                to_download.push(name.clone());
+    if let Command::Config(config_args) = &clis[0].1.command {
+        match config_args.command {
+            ConfigCommand::PrintDefault => print!("{}", config::print_default(&Cli::command())),
+            ConfigCommand::PrintEffective => {
+                print!("{}", config::print_effective(commands, &Cli::command())?)
             }
-        } else {
-            to_download.push(name.clone());
         }
+        return Ok(());
     }
+    let tui = matches!(&clis[0].1.command, Command::Sync(args) if args.tui());
+    let json = matches!(&clis[0].1.command, Command::Sync(args) if args.json());
+    output::init(clis[0].1.verbose, clis[0].1.quiet, tui, json);
+    let jobs: Vec<(Option<String>, Command)> = clis
+        .into_iter()
+        .map(|(name, cli)| (name, cli.command))
+        .collect();
 
-    // Identify extra
-    let mut to_delete = Vec::new();
-    if prune {
-        for name in dest_map.keys() {
-            if !source_map.contains_key(name) {
-                to_delete.push(name.clone());
-            }
-        }
+    // All jobs run the same subcommand.
+    if matches!(jobs[0].1, Command::Sync(_)) {
+        let cancel = cancel_on_ctrl_c();
+        let jobs = jobs
+            .into_iter()
+            .map(|(name, command)| match command {
+                Command::Sync(args) => sync_client(name, *args, &cancel),
+                _ => unreachable!(),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let dashboard = tui.then(tui::Tui::start).transpose()?;
+        let result = restic_sync::sync(jobs).await;
+        drop(dashboard);
+        return result;
     }
-
-    info!(
-        "[{}] Found {} missing blobs, {} extra blobs",
-        file_type,
-        to_download.len(),
-        to_delete.len()
-    );
-
-    // Sync missing sequentially
-    for name in to_download {
-        info!("[{}] Syncing file: {}", file_type, name);
-        sync_file(client, source, dest, file_type, &name).await?;
+    if jobs.len() == 1 {
+        let (_, command) = jobs.into_iter().next().unwrap();
+        return run_command(command).await;
     }
-
-    // Delete extra sequentially
-    if prune {
-        for name in to_delete {
-            info!("[{}] Deleting extra file: {}", file_type, name);
-            delete_file(client, dest, file_type, &name).await?;
+    let total = jobs.len();
+    let mut failed = 0;
+    for (name, command) in jobs {
+        let name = name.unwrap_or_default();
+        println!("{}:", name);
+        if let Err(e) = run_command(command).await {
+            error!("Job {} failed: {:?}", name, e);
+            failed += 1;
         }
     }
-
+    if failed > 0 {
+        bail!(Error::JobsFailed { failed, total });
+    }
     Ok(())
 }
 
-async fn sync_file(
-    client: &ClientWithMiddleware,
-    source: &str,
-    dest: &str,
-    file_type: &str,
-    name: &str,
-) -> Result<()> {
-    let source_url = format!("{}{}/{}", source, file_type, name);
-    let dest_url = format!("{}{}/{}", dest, file_type, name);
-
-    // Download blob into memory
-    let resp = client.get(&source_url).send().await?;
-    if !resp.status().is_success() {
-        bail!("Failed to download {}: {}", source_url, resp.status());
+async fn run_command(command: Command) -> Result<()> {
+    match command {
+        Command::Sync(args) => {
+            let job = sync_client(None, *args, &cancel_on_ctrl_c())?;
+            restic_sync::sync(vec![job]).await
+        }
+        Command::Verify(args) => commands::verify(&args).await,
+        Command::Diff(args) => commands::diff(&args).await,
+        Command::Stats(args) => commands::stats(&args).await,
+        Command::Compare(args) => commands::compare(&args).await,
+        Command::Init(args) => commands::init(&args).await,
+        Command::CopySnapshot(args) => commands::copy_snapshot(&args).await,
+        Command::PruneLocks(args) => commands::prune_locks(&args).await,
+        Command::CheckConfig(args) => commands::check_config(&args).await,
+        Command::Plan(args) => commands::plan(&args, &cancel_on_ctrl_c()).await,
+        // Handled in main, which has the config file.
+        Command::Config(_) => unreachable!(),
+        Command::Healthcheck(args) => healthcheck(&args),
+        Command::History(args) => commands::history(&args),
+        Command::Schema(args) => {
+            println!("{}", serde_json::to_string_pretty(&schema::document(args.schema))?);
+            Ok(())
+        }
+        Command::Completions(args) => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(args.shell, &mut command, name, &mut std::io::stdout());
+            Ok(())
+        }
+        Command::Man(args) => {
+            clap_mangen::generate_to(Cli::command(), &args.out_dir).with_context(|| {
+                format!("Failed to write man pages to {}", args.out_dir.display())
+            })
+        }
     }
+}
 
-    let bytes = resp.bytes().await?;
-
-    // Compute SHA256 sum
-    let mut hasher = Sha256::new();
-    hasher.update(&bytes);
-    let result = hasher.finalize();
-    let hash_hex = format!("{:x}", result);
-
-    if hash_hex != name {
-        bail!(
-            "Blob verification failed for {}. Expected hash: {}, Got: {}",
-            name,
-            name,
-            hash_hex
-        );
+/// Inserts the implicit `sync` subcommand unless another one was given, so
+/// that `restic-sync --source ... --dest ...` keeps working.
+fn with_default_subcommand(args: impl Iterator<Item = OsString>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.collect();
+    // Global options may come before the subcommand.
+    let mut first = 1;
+    while let Some(arg) = args.get(first).and_then(|arg| arg.to_str()) {
+        if GLOBAL_OPTIONS.contains(&arg) {
+            first += 2;
+        } else if GLOBAL_OPTIONS.iter().any(|option| arg.starts_with(&format!("{option}=")))
+            || is_verbosity_flag(arg)
+        {
+            first += 1;
+        } else {
+            break;
+        }
     }
-
-    // Upload verified blob
-    let post_resp = client.post(&dest_url).body(bytes).send().await?;
-    if !post_resp.status().is_success() {
-        bail!("Failed to upload to {}: {}", dest_url, post_resp.status());
+    let explicit = args.get(first).and_then(|arg| arg.to_str()).is_some_and(|arg| {
+        matches!(arg, "-h" | "--help" | "-V" | "--version" | "help")
+            || Cli::command().find_subcommand(arg).is_some()
+    });
+    if !explicit {
+        args.insert(first.min(args.len()), OsString::from("sync"));
     }
+    args
+}
 
-    Ok(())
+/// `-v`, `-vv`, `--verbose`, `-q` and the like.
+fn is_verbosity_flag(arg: &str) -> bool {
+    matches!(arg, "--verbose" | "--quiet")
+        || arg
+            .strip_prefix('-')
+            .is_some_and(|flags| !flags.is_empty() && (flags.bytes().all(|c| c == b'v') || flags == "q"))
 }
 
-async fn delete_file(client: &ClientWithMiddleware, dest: &str, file_type: &str, name: &str) -> Result<()> {
-    let url = format!("{}{}/{}", dest, file_type, name);
-    let resp = client.delete(&url).send().await?;
-    if !resp.status().is_success() {
-        bail!("Failed to delete {}: {}", url, resp.status());
-    }
+fn healthcheck(args: &HealthcheckArgs) -> Result<()> {
+    let message = status::check(&args.status_file, args.max_heartbeat_age, args.max_age)?;
+    println!("{}", message);
     Ok(())
 }
+
+fn sync_client(name: Option<String>, args: SyncOptions, cancel: &CancellationToken) -> Result<SyncClient> {
+    let client = SyncClient::new(args)?.with_cancellation(cancel.clone());
+    Ok(match name {
+        Some(name) => client.with_name(name),
+        None => client,
+    })
+}
+
+/// Lets Ctrl-C stop the syncs after the file they are uploading, so that
+/// they end with a summary of what they copied. A second Ctrl-C quits at
+/// once.
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Stopping after the current upload; press Ctrl-C again to quit at once");
+            token.cancel();
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        }
+    });
+    cancel
+}
@@ -0,0 +1,144 @@
+//! Metrics file for monitoring systems.
+//!
+//! After each run, the outcome is written in the Prometheus text format,
+//! for node_exporter's textfile collector or anything else that can scrape
+//! a file. With `--monitor`, it also holds how far the destination is
+//! behind the source, per file type, which is the number to alert on for a
+//! mirror that has stopped keeping up.
+
+use crate::SyncReport;
+use anyhow::Result;
+use log::warn;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone)]
+pub struct MetricsFile {
+    path: PathBuf,
+}
+
+impl MetricsFile {
+    pub fn new(path: &Path) -> Self {
+        MetricsFile {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn run_finished(&self, result: &Result<SyncReport>) {
+        if let Err(e) = self.write(&render(result)) {
+            warn!(
+                "Failed to write metrics file {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn write(&self, metrics: &str) -> Result<()> {
+        // The collector must never see a half-written file.
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, metrics)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+fn render(result: &Result<SyncReport>) -> String {
+    let mut out = String::new();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    metric(
+        &mut out,
+        "last_run_success",
+        "Whether the last run succeeded",
+        &[(None, result.is_ok() as u64)],
+    );
+    metric(
+        &mut out,
+        "last_run_timestamp_seconds",
+        "When the last run finished",
+        &[(None, now)],
+    );
+    let Ok(report) = result else {
+        return out;
+    };
+    let _ = writeln!(
+        out,
+        "# HELP restic_sync_last_run_duration_seconds How long the last run took\n\
+         # TYPE restic_sync_last_run_duration_seconds gauge\n\
+         restic_sync_last_run_duration_seconds {:.3}",
+        report.duration.as_secs_f64()
+    );
+    metric(
+        &mut out,
+        "uploaded_files",
+        "Files uploaded by the last run",
+        &[(None, report.uploaded_files)],
+    );
+    metric(
+        &mut out,
+        "uploaded_bytes",
+        "Bytes uploaded by the last run",
+        &[(None, report.uploaded_bytes)],
+    );
+    metric(
+        &mut out,
+        "deleted_files",
+        "Files deleted by the last run",
+        &[(None, report.deleted_files)],
+    );
+    if let Some(divergence) = &report.divergence {
+        let by_type = |value: fn(&crate::TypeDivergence) -> u64| -> Vec<(Option<&str>, u64)> {
+            divergence
+                .types
+                .iter()
+                .map(|t| (Some(t.file_type), value(t)))
+                .collect()
+        };
+        metric(
+            &mut out,
+            "behind_files",
+            "Source files missing from the destination or different there",
+            &by_type(|t| t.behind_files),
+        );
+        metric(
+            &mut out,
+            "behind_bytes",
+            "Size of the source files missing from the destination or different there",
+            &by_type(|t| t.behind_bytes),
+        );
+        metric(
+            &mut out,
+            "extra_files",
+            "Destination files that are not in the source",
+            &by_type(|t| t.extra_files),
+        );
+        metric(
+            &mut out,
+            "extra_bytes",
+            "Size of the destination files that are not in the source",
+            &by_type(|t| t.extra_bytes),
+        );
+    }
+    out
+}
+
+/// Appends the gauge `restic_sync_<name>`, with a `type` label for values
+/// that have one.
+fn metric(out: &mut String, name: &str, help: &str, values: &[(Option<&str>, u64)]) {
+    let _ = writeln!(out, "# HELP restic_sync_{name} {help}");
+    let _ = writeln!(out, "# TYPE restic_sync_{name} gauge");
+    for (file_type, value) in values {
+        match file_type {
+            Some(file_type) => {
+                let _ = writeln!(out, "restic_sync_{name}{{type=\"{file_type}\"}} {value}");
+            }
+            None => {
+                let _ = writeln!(out, "restic_sync_{name} {value}");
+            }
+        }
+    }
+}
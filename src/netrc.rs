@@ -0,0 +1,246 @@
+//! Minimal `.netrc` support.
+//!
+//! Understands the `machine`, `default`, `login`, `password`, `account` and
+//! `macdef` keywords, with double-quoted tokens for values containing spaces,
+//! the same subset curl accepts.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+struct Entry {
+    /// `None` for the `default` entry.
+    machine: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Netrc {
+    entries: Vec<Entry>,
+}
+
+impl Netrc {
+    /// The default location: `$NETRC`, or `~/.netrc` (`~/_netrc` on Windows).
+    pub fn default_path() -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os("NETRC") {
+            return Some(PathBuf::from(path));
+        }
+        let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+        let name = if cfg!(windows) { "_netrc" } else { ".netrc" };
+        Some(Path::new(&home).join(name))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read netrc file {}", path.display()))?;
+        Ok(Self::parse(&contents))
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        let mut netrc = Netrc::default();
+        let mut tokens = Vec::new();
+        let mut in_macdef = false;
+        for line in contents.lines() {
+            // A macro definition runs until the next blank line.
+            if in_macdef {
+                in_macdef = !line.trim().is_empty();
+                continue;
+            }
+            for word in tokenize(line) {
+                if word == "macdef" {
+                    in_macdef = true;
+                    break;
+                }
+                tokens.push(word);
+            }
+        }
+
+        let mut tokens = tokens.into_iter();
+        let mut current: Option<Entry> = None;
+        while let Some(token) = tokens.next() {
+            match token.as_str() {
+                "machine" | "default" => {
+                    netrc.entries.extend(current.take());
+                    let machine = if token == "machine" {
+                        tokens.next()
+                    } else {
+                        None
+                    };
+                    current = Some(Entry {
+                        machine,
+                        ..Entry::default()
+                    });
+                }
+                "login" | "password" | "account" => {
+                    let value = tokens.next();
+                    if let Some(entry) = current.as_mut() {
+                        match token.as_str() {
+                            "login" => entry.login = value,
+                            "password" => entry.password = value,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        netrc.entries.extend(current);
+        netrc
+    }
+
+    /// Finds the login and password for `host`, falling back to the
+    /// `default` entry. If `login` is given, only entries for that user (or
+    /// without a login) match.
+    pub fn lookup(&self, host: &str, login: Option<&str>) -> Option<(Option<&str>, Option<&str>)> {
+        let matches_login = |entry: &&Entry| match (login, entry.login.as_deref()) {
+            (Some(wanted), Some(found)) => wanted == found,
+            _ => true,
+        };
+        let entry = self
+            .entries
+            .iter()
+            .filter(|entry| {
+                entry
+                    .machine
+                    .as_deref()
+                    .is_some_and(|machine| machine.eq_ignore_ascii_case(host))
+            })
+            .find(matches_login)
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .filter(|entry| entry.machine.is_none())
+                    .find(matches_login)
+            })?;
+        Some((entry.login.as_deref(), entry.password.as_deref()))
+    }
+}
+
+/// Splits a line into whitespace-separated tokens, honoring double quotes
+/// and backslash escapes inside them.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '#' && tokens.is_empty() {
+            break;
+        }
+        let mut token = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => token.extend(chars.next()),
+                    c => token.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_machine_entry_is_picked_by_host() {
+        let netrc = Netrc::parse(
+            "machine backup.example login alice password one\n\
+             machine mirror.example\n  login bob\n  password two\n",
+        );
+        assert_eq!(
+            netrc.lookup("mirror.example", None),
+            Some((Some("bob"), Some("two")))
+        );
+        assert_eq!(
+            netrc.lookup("BACKUP.example", None),
+            Some((Some("alice"), Some("one")))
+        );
+        assert_eq!(netrc.lookup("other.example", None), None);
+    }
+
+    #[test]
+    fn the_default_entry_answers_for_other_hosts() {
+        let netrc = Netrc::parse(
+            "default login anonymous password guest\n\
+             machine backup.example login alice password one\n",
+        );
+        assert_eq!(
+            netrc.lookup("other.example", None),
+            Some((Some("anonymous"), Some("guest")))
+        );
+        assert_eq!(
+            netrc.lookup("backup.example", None),
+            Some((Some("alice"), Some("one")))
+        );
+    }
+
+    #[test]
+    fn a_given_login_picks_among_entries_for_a_host() {
+        let netrc = Netrc::parse(
+            "machine backup.example login alice password one\n\
+             machine backup.example login bob password two\n\
+             default password fallback\n",
+        );
+        assert_eq!(
+            netrc.lookup("backup.example", Some("bob")),
+            Some((Some("bob"), Some("two")))
+        );
+        assert_eq!(
+            netrc.lookup("backup.example", Some("carol")),
+            Some((None, Some("fallback")))
+        );
+    }
+
+    #[test]
+    fn macro_definitions_are_skipped_until_a_blank_line() {
+        let netrc = Netrc::parse(
+            "macdef init\nmachine evil.example login mallory password x\n\n\
+             machine backup.example login alice password one\n",
+        );
+        assert_eq!(netrc.lookup("evil.example", None), None);
+        assert_eq!(
+            netrc.lookup("backup.example", None),
+            Some((Some("alice"), Some("one")))
+        );
+    }
+
+    #[test]
+    fn comments_and_account_are_ignored() {
+        let netrc = Netrc::parse(
+            "# machine evil.example login mallory\n\
+             machine backup.example account ops login alice password one#two\n",
+        );
+        assert_eq!(netrc.lookup("evil.example", None), None);
+        assert_eq!(
+            netrc.lookup("backup.example", None),
+            Some((Some("alice"), Some("one#two")))
+        );
+    }
+
+    #[test]
+    fn quoted_tokens_keep_spaces_and_escapes() {
+        let netrc =
+            Netrc::parse(r#"machine backup.example login "alice smith" password "a \"b\" \\c""#);
+        assert_eq!(
+            netrc.lookup("backup.example", None),
+            Some((Some("alice smith"), Some(r#"a "b" \c"#)))
+        );
+    }
+}
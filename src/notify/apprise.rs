@@ -0,0 +1,67 @@
+//! Apprise notifications.
+//!
+//! Rather than implementing every service natively, run results are handed
+//! to the `apprise` CLI, which understands URLs for dozens of services
+//! (`tgram://`, `mailto://`, `pover://`, ...). URLs are passed through the
+//! `APPRISE_URLS` environment variable so that embedded secrets do not show
+//! up in the process list.
+
+use super::{NotifyOn, RunOutcome};
+use crate::redact::Redacted;
+use anyhow::{Context, Result, bail};
+use tokio::process::Command;
+
+#[derive(Debug, Clone)]
+pub struct Apprise {
+    bin: String,
+    urls: Vec<Redacted>,
+    on: NotifyOn,
+}
+
+impl Apprise {
+    pub fn new(bin: &str, urls: &[Redacted], on: NotifyOn) -> Self {
+        Apprise {
+            bin: bin.to_string(),
+            urls: urls.to_vec(),
+            on,
+        }
+    }
+
+    pub async fn run_finished(&self, outcome: &RunOutcome<'_>) -> Result<()> {
+        if !self.on.matches(outcome) {
+            return Ok(());
+        }
+
+        let notification_type = if outcome.succeeded() {
+            "success"
+        } else {
+            "failure"
+        };
+        let body = format!(
+            "{} -> {}\n{}",
+            outcome.source,
+            outcome.dest,
+            outcome.summary()
+        );
+        let output = Command::new(&self.bin)
+            .arg("--title")
+            .arg(outcome.title())
+            .arg("--body")
+            .arg(body)
+            .arg("--notification-type")
+            .arg(notification_type)
+            .env("APPRISE_URLS", self.urls.iter().map(|url| url.as_str()).collect::<Vec<_>>().join(" "))
+            .output()
+            .await
+            .with_context(|| format!("Failed to run {}", self.bin))?;
+        if !output.status.success() {
+            bail!(
+                "{} exited with {}: {}",
+                self.bin,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
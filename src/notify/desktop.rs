@@ -0,0 +1,114 @@
+//! Native desktop notifications.
+//!
+//! Uses the notification tool that ships with each platform instead of
+//! linking against platform libraries: `notify-send` on Linux and BSDs,
+//! `osascript` on macOS, and PowerShell on Windows, where failures are also
+//! written to the Application Event Log.
+
+use super::{NotifyOn, RunOutcome};
+use anyhow::{Context, Result, bail};
+use tokio::process::Command;
+
+#[derive(Debug, Clone)]
+pub struct Desktop {
+    on: NotifyOn,
+}
+
+impl Desktop {
+    pub fn new(on: NotifyOn) -> Self {
+        Desktop { on }
+    }
+
+    pub async fn run_finished(&self, outcome: &RunOutcome<'_>) -> Result<()> {
+        if !self.on.matches(outcome) {
+            return Ok(());
+        }
+
+        let body = format!(
+            "{} -> {}\n{}",
+            outcome.source,
+            outcome.dest,
+            outcome.summary()
+        );
+        for mut command in commands(outcome.title(), &body, outcome.succeeded()) {
+            let program = command
+                .as_std()
+                .get_program()
+                .to_string_lossy()
+                .into_owned();
+            let output = command
+                .output()
+                .await
+                .with_context(|| format!("Failed to run {}", program))?;
+            if !output.status.success() {
+                bail!(
+                    "{} exited with {}: {}",
+                    program,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn commands(title: &str, body: &str, succeeded: bool) -> Vec<Command> {
+    let mut command = Command::new("notify-send");
+    command
+        .arg("--app-name=restic-sync")
+        .arg(if succeeded {
+            "--urgency=normal"
+        } else {
+            "--urgency=critical"
+        })
+        .arg(title)
+        .arg(body);
+    vec![command]
+}
+
+#[cfg(target_os = "macos")]
+fn commands(title: &str, body: &str, _succeeded: bool) -> Vec<Command> {
+    // Pass the text through argv rather than interpolating it into the script.
+    let mut command = Command::new("osascript");
+    command
+        .arg("-e")
+        .arg("on run argv")
+        .arg("-e")
+        .arg("display notification (item 2 of argv) with title (item 1 of argv)")
+        .arg("-e")
+        .arg("end run")
+        .arg(title)
+        .arg(body);
+    vec![command]
+}
+
+#[cfg(windows)]
+fn commands(title: &str, body: &str, succeeded: bool) -> Vec<Command> {
+    const BALLOON: &str = "Add-Type -AssemblyName System.Windows.Forms; \
+        $n = New-Object System.Windows.Forms.NotifyIcon; \
+        $n.Icon = [System.Drawing.SystemIcons]::Information; \
+        $n.Visible = $true; \
+        $n.ShowBalloonTip(10000, $env:RESTIC_SYNC_TITLE, $env:RESTIC_SYNC_BODY, $env:RESTIC_SYNC_LEVEL); \
+        Start-Sleep -Seconds 10; $n.Dispose()";
+
+    let mut balloon = Command::new("powershell");
+    balloon
+        .args(["-NoProfile", "-NonInteractive", "-Command", BALLOON])
+        .env("RESTIC_SYNC_TITLE", title)
+        .env("RESTIC_SYNC_BODY", body)
+        .env(
+            "RESTIC_SYNC_LEVEL",
+            if succeeded { "Info" } else { "Error" },
+        );
+
+    let mut event = Command::new("eventcreate");
+    event
+        .args(["/L", "APPLICATION", "/SO", "restic-sync", "/ID", "1000"])
+        .args(["/T", if succeeded { "INFORMATION" } else { "ERROR" }])
+        .arg("/D")
+        .arg(format!("{}: {}", title, body));
+
+    vec![event, balloon]
+}
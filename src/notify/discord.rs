@@ -0,0 +1,60 @@
+//! Discord webhook notifications.
+
+use super::{NotifyOn, RunOutcome};
+use crate::redact::Redacted;
+use anyhow::{Result, bail};
+use reqwest::Client;
+use serde_json::json;
+
+const COLOR_SUCCESS: u32 = 0x2ecc71;
+const COLOR_FAILURE: u32 = 0xe74c3c;
+
+#[derive(Debug, Clone)]
+pub struct Discord {
+    url: Redacted,
+    on: NotifyOn,
+}
+
+impl Discord {
+    pub fn new(url: &str, on: NotifyOn) -> Self {
+        Discord {
+            url: Redacted(url.to_string()),
+            on,
+        }
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        if !self.on.matches(outcome) {
+            return Ok(());
+        }
+
+        let color = if outcome.succeeded() {
+            COLOR_SUCCESS
+        } else {
+            COLOR_FAILURE
+        };
+        let payload = json!({
+            "username": "restic-sync",
+            "embeds": [{
+                "title": outcome.title(),
+                "description": outcome.summary(),
+                "color": color,
+                "fields": [
+                    { "name": "Source", "value": outcome.source, "inline": true },
+                    { "name": "Destination", "value": outcome.dest, "inline": true },
+                ],
+            }],
+        });
+
+        let resp = client
+            .post(self.url.as_str())
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        if !resp.status().is_success() {
+            bail!("Discord webhook returned {}", resp.status());
+        }
+        Ok(())
+    }
+}
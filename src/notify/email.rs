@@ -0,0 +1,142 @@
+//! SMTP email reports.
+//!
+//! Failed runs are reported immediately. Successful runs are only mailed as
+//! part of an optional periodic digest, which is flushed by the first run
+//! that finishes after the digest period has elapsed.
+
+use super::RunOutcome;
+use crate::redact::Redacted;
+use anyhow::{Context, Result};
+use chrono::Local;
+use clap::ValueEnum;
+use lettre::message::{Mailbox, header::ContentType};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often successful runs are summarized in a digest email.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestPeriod {
+    Daily,
+    Weekly,
+}
+
+impl DigestPeriod {
+    fn duration(self) -> Duration {
+        match self {
+            DigestPeriod::Daily => Duration::from_secs(24 * 60 * 60),
+            DigestPeriod::Weekly => Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Digest {
+    period: DigestPeriod,
+    since: Instant,
+    runs: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    digest: Option<Arc<Mutex<Digest>>>,
+}
+
+impl Email {
+    pub fn new(
+        url: &str,
+        credentials: Option<(String, Redacted)>,
+        from: &str,
+        to: &[String],
+        digest: Option<DigestPeriod>,
+    ) -> Result<Self> {
+        let mut builder =
+            AsyncSmtpTransport::<Tokio1Executor>::from_url(url).context("Invalid SMTP URL")?;
+        if let Some((user, password)) = credentials {
+            builder = builder.credentials(Credentials::new(user, password.0));
+        }
+
+        let from = from
+            .parse()
+            .with_context(|| format!("Invalid sender address {}", from))?;
+        let to = to
+            .iter()
+            .map(|addr| {
+                addr.parse()
+                    .with_context(|| format!("Invalid recipient address {}", addr))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Email {
+            mailer: builder.build(),
+            from,
+            to,
+            digest: digest.map(|period| {
+                Arc::new(Mutex::new(Digest {
+                    period,
+                    since: Instant::now(),
+                    runs: Vec::new(),
+                }))
+            }),
+        })
+    }
+
+    pub async fn run_finished(&self, outcome: &RunOutcome<'_>) -> Result<()> {
+        let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
+
+        if !outcome.succeeded() {
+            let body = format!(
+                "restic-sync run failed at {}.\n\nSource: {}\nDestination: {}\n\n{}\n",
+                timestamp,
+                outcome.source,
+                outcome.dest,
+                outcome.summary()
+            );
+            return self.send("restic-sync failed", body).await;
+        }
+
+        let Some(digest) = &self.digest else {
+            return Ok(());
+        };
+        let flushed = {
+            let mut digest = digest.lock().unwrap();
+            digest
+                .runs
+                .push(format!("{}  {}", timestamp, outcome.summary()));
+            if digest.since.elapsed() >= digest.period.duration() {
+                digest.since = Instant::now();
+                Some(std::mem::take(&mut digest.runs))
+            } else {
+                None
+            }
+        };
+
+        if let Some(runs) = flushed {
+            let body = format!(
+                "Successful restic-sync runs from {} to {}:\n\n{}\n",
+                outcome.source,
+                outcome.dest,
+                runs.join("\n")
+            );
+            self.send("restic-sync digest", body).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&self, subject: &str, body: String) -> Result<()> {
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN);
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let message = builder.body(body).context("Failed to build email")?;
+        self.mailer.send(message).await?;
+        Ok(())
+    }
+}
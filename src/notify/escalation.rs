@@ -0,0 +1,186 @@
+//! Escalation of repeated failures to PagerDuty and Opsgenie.
+//!
+//! A single failed run is usually a blip, so nothing is paged until
+//! `escalate_after` runs in a row have failed. The incident is resolved
+//! automatically by the next successful run.
+
+use super::RunOutcome;
+use crate::redact::Redacted;
+use anyhow::{Result, bail};
+use log::info;
+use reqwest::Client;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+const PAGERDUTY_EVENTS_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+#[derive(Debug, Default)]
+struct State {
+    consecutive_failures: AtomicU32,
+    escalated: AtomicBool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Escalation {
+    pagerduty_routing_key: Option<Redacted>,
+    opsgenie_api_key: Option<Redacted>,
+    opsgenie_url: String,
+    escalate_after: u32,
+    state: Arc<State>,
+}
+
+impl Escalation {
+    pub fn new(
+        pagerduty_routing_key: Option<Redacted>,
+        opsgenie_api_key: Option<Redacted>,
+        opsgenie_url: &str,
+        escalate_after: u32,
+    ) -> Self {
+        Escalation {
+            pagerduty_routing_key,
+            opsgenie_api_key,
+            opsgenie_url: opsgenie_url.trim_end_matches('/').to_string(),
+            escalate_after,
+            state: Arc::new(State::default()),
+        }
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        let key = dedup_key(outcome);
+
+        if outcome.succeeded() {
+            self.state.consecutive_failures.store(0, Ordering::SeqCst);
+            if self.state.escalated.swap(false, Ordering::SeqCst) {
+                info!("Sync recovered; resolving escalated incident {}", key);
+                self.resolve(client, &key).await?;
+            }
+            return Ok(());
+        }
+
+        let failures = self
+            .state
+            .consecutive_failures
+            .fetch_add(1, Ordering::SeqCst)
+            + 1;
+        if failures < self.escalate_after {
+            return Ok(());
+        }
+        // Subsequent failures are still sent: both services deduplicate them
+        // into the open incident, and it refreshes the latest error message.
+        self.state.escalated.store(true, Ordering::SeqCst);
+        info!(
+            "Sync failed {} times in a row; escalating incident {}",
+            failures, key
+        );
+        self.trigger(client, &key, failures, outcome).await
+    }
+
+    async fn trigger(
+        &self,
+        client: &Client,
+        key: &str,
+        failures: u32,
+        outcome: &RunOutcome<'_>,
+    ) -> Result<()> {
+        let summary = format!(
+            "restic-sync failed {} times in a row: {} -> {}",
+            failures, outcome.source, outcome.dest
+        );
+        let error = outcome.error().unwrap_or_default();
+
+        if let Some(routing_key) = &self.pagerduty_routing_key {
+            let payload = json!({
+                "routing_key": routing_key.as_str(),
+                "event_action": "trigger",
+                "dedup_key": key,
+                "payload": {
+                    "summary": summary,
+                    "source": "restic-sync",
+                    "severity": "error",
+                    "custom_details": {
+                        "source": outcome.source,
+                        "dest": outcome.dest,
+                        "consecutive_failures": failures,
+                        "error": error,
+                    },
+                },
+            });
+            send(
+                client.post(PAGERDUTY_EVENTS_URL).json(&payload),
+                "PagerDuty",
+            )
+            .await?;
+        }
+
+        if let Some(api_key) = &self.opsgenie_api_key {
+            let payload = json!({
+                "message": summary,
+                "alias": key,
+                "description": error,
+                "source": "restic-sync",
+                "priority": "P2",
+                "details": {
+                    "source": outcome.source,
+                    "dest": outcome.dest,
+                    "consecutive_failures": failures.to_string(),
+                },
+            });
+            let url = format!("{}/v2/alerts", self.opsgenie_url);
+            let req = client
+                .post(&url)
+                .header("Authorization", format!("GenieKey {}", api_key.as_str()))
+                .json(&payload);
+            send(req, "Opsgenie").await?;
+        }
+        Ok(())
+    }
+
+    async fn resolve(&self, client: &Client, key: &str) -> Result<()> {
+        if let Some(routing_key) = &self.pagerduty_routing_key {
+            let payload = json!({
+                "routing_key": routing_key.as_str(),
+                "event_action": "resolve",
+                "dedup_key": key,
+            });
+            send(
+                client.post(PAGERDUTY_EVENTS_URL).json(&payload),
+                "PagerDuty",
+            )
+            .await?;
+        }
+
+        if let Some(api_key) = &self.opsgenie_api_key {
+            let url = format!(
+                "{}/v2/alerts/{}/close?identifierType=alias",
+                self.opsgenie_url, key
+            );
+            let req = client
+                .post(&url)
+                .header("Authorization", format!("GenieKey {}", api_key.as_str()))
+                .json(&json!({ "source": "restic-sync" }));
+            send(req, "Opsgenie").await?;
+        }
+        Ok(())
+    }
+}
+
+/// A stable incident key for the repository pair that does not leak any
+/// credentials embedded in the URLs.
+fn dedup_key(outcome: &RunOutcome) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(outcome.source.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(outcome.dest.as_bytes());
+    let digest = format!("{:x}", hasher.finalize());
+    format!("restic-sync-{}", &digest[..16])
+}
+
+async fn send(req: reqwest::RequestBuilder, service: &str) -> Result<()> {
+    let resp = req.send().await.map_err(|e| e.without_url())?;
+    if !resp.status().is_success() {
+        bail!("{} returned {}", service, resp.status());
+    }
+    Ok(())
+}
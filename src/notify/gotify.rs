@@ -0,0 +1,58 @@
+//! Gotify notifications for self-hosted push setups.
+
+use super::{NotifyOn, RunOutcome};
+use crate::redact::{Redacted, url_host};
+use anyhow::{Result, bail};
+use reqwest::Client;
+use serde_json::json;
+
+#[derive(Debug, Clone)]
+pub struct Gotify {
+    url: String,
+    token: Redacted,
+    on: NotifyOn,
+    priority: u8,
+    failure_priority: u8,
+}
+
+impl Gotify {
+    pub fn new(url: &str, token: &str, on: NotifyOn, priority: u8, failure_priority: u8) -> Self {
+        Gotify {
+            url: url.trim_end_matches('/').to_string(),
+            token: Redacted(token.to_string()),
+            on,
+            priority,
+            failure_priority,
+        }
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        if !self.on.matches(outcome) {
+            return Ok(());
+        }
+
+        let priority = if outcome.succeeded() {
+            self.priority
+        } else {
+            self.failure_priority
+        };
+        let payload = json!({
+            "title": outcome.title(),
+            "message": format!("{} -> {}\n{}", outcome.source, outcome.dest, outcome.summary()),
+            "priority": priority,
+        });
+
+        let url = format!("{}/message", self.url);
+        let resp = client
+            .post(&url)
+            .header("X-Gotify-Key", self.token.as_str())
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        if !resp.status().is_success() {
+            bail!("Gotify {} returned {}", url_host(&url), resp.status());
+        }
+        Ok(())
+    }
+}
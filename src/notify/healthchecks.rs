@@ -0,0 +1,49 @@
+//! Healthchecks.io ping integration.
+//!
+//! Pings `<url>/start` when a run begins, then `<url>` on success or
+//! `<url>/fail` on failure with the run summary as the request body.
+
+use super::RunOutcome;
+use crate::redact::{Redacted, url_host};
+use anyhow::{Result, bail};
+use reqwest::Client;
+
+#[derive(Debug, Clone)]
+pub struct Healthchecks {
+    url: Redacted,
+}
+
+impl Healthchecks {
+    pub fn new(url: &str) -> Self {
+        Healthchecks {
+            url: Redacted(url.trim_end_matches('/').to_string()),
+        }
+    }
+
+    pub async fn run_started(&self, client: &Client) -> Result<()> {
+        self.ping(client, "/start", String::new()).await
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        let suffix = if outcome.succeeded() { "" } else { "/fail" };
+        self.ping(client, suffix, outcome.summary()).await
+    }
+
+    async fn ping(&self, client: &Client, suffix: &str, body: String) -> Result<()> {
+        let url = format!("{}{}", self.url.as_str(), suffix);
+        let resp = client
+            .post(&url)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        if !resp.status().is_success() {
+            bail!(
+                "Healthchecks ping to {} failed: {}",
+                url_host(&url),
+                resp.status()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+//! MQTT status publishing with optional Home Assistant discovery.
+//!
+//! All messages are retained so that dashboards show the latest state
+//! immediately after subscribing:
+//!
+//! - `<topic>/state`: `running`, `success`, or `failure`
+//! - `<topic>/last_run`: JSON with the summary, error, and statistics of the
+//!   last finished run
+//!
+//! A connection is opened for each batch of messages rather than kept alive
+//! between (possibly hours apart) scheduled runs.
+
+use super::RunOutcome;
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use reqwest::Url;
+use rumqttc::{AsyncClient, Event, MqttOptions, Outgoing, Packet, QoS, Transport};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+struct Discovery {
+    prefix: String,
+    node_id: String,
+    published: Arc<AtomicBool>,
+}
+
+#[derive(Clone)]
+pub struct Mqtt {
+    options: MqttOptions,
+    topic: String,
+    discovery: Option<Discovery>,
+}
+
+// The options hold the credentials of the broker URL.
+impl std::fmt::Debug for Mqtt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mqtt")
+            .field("broker", &self.options.broker_address())
+            .field("topic", &self.topic)
+            .field("discovery", &self.discovery)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Mqtt {
+    /// `discovery` is the Home Assistant discovery prefix and node ID.
+    pub fn new(url: &str, topic: &str, discovery: Option<(&str, &str)>) -> Result<Self> {
+        let url = Url::parse(url).context("Invalid MQTT URL")?;
+        let (default_port, tls) = match url.scheme() {
+            "mqtt" | "tcp" => (1883, false),
+            "mqtts" | "ssl" => (8883, true),
+            other => bail!("Unsupported MQTT URL scheme: {}", other),
+        };
+        let host = url.host_str().context("MQTT URL has no host")?;
+        let client_id = format!("restic-sync-{}", std::process::id());
+
+        let mut options = MqttOptions::new(client_id, host, url.port().unwrap_or(default_port));
+        options.set_keep_alive(Duration::from_secs(30));
+        if !url.username().is_empty() {
+            options.set_credentials(url.username(), url.password().unwrap_or_default());
+        }
+        if tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+
+        Ok(Mqtt {
+            options,
+            topic: topic.trim_end_matches('/').to_string(),
+            discovery: discovery.map(|(prefix, node_id)| Discovery {
+                prefix: prefix.trim_end_matches('/').to_string(),
+                node_id: node_id.to_string(),
+                published: Arc::new(AtomicBool::new(false)),
+            }),
+        })
+    }
+
+    pub async fn run_started(&self) -> Result<()> {
+        let mut messages = self.discovery_messages();
+        messages.push((format!("{}/state", self.topic), json!("running")));
+        self.publish(messages).await
+    }
+
+    pub async fn run_finished(&self, outcome: &RunOutcome<'_>) -> Result<()> {
+        let state = if outcome.succeeded() {
+            "success"
+        } else {
+            "failure"
+        };
+        let report = outcome.report();
+        let last_run = json!({
+            "state": state,
+            "finished_at": Utc::now().to_rfc3339(),
+            "source": outcome.source,
+            "dest": outcome.dest,
+            "summary": outcome.summary(),
+            "error": outcome.error(),
+            "uploaded_files": report.map(|r| r.uploaded_files),
+            "uploaded_bytes": report.map(|r| r.uploaded_bytes),
+            "deleted_files": report.map(|r| r.deleted_files),
+            "duration_secs": outcome.duration.as_secs_f64(),
+        });
+
+        let mut messages = self.discovery_messages();
+        messages.push((format!("{}/state", self.topic), json!(state)));
+        messages.push((format!("{}/last_run", self.topic), last_run));
+        self.publish(messages).await
+    }
+
+    /// Home Assistant discovery payloads, sent once per process.
+    fn discovery_messages(&self) -> Vec<(String, Value)> {
+        let Some(discovery) = &self.discovery else {
+            return Vec::new();
+        };
+        if discovery.published.swap(true, Ordering::SeqCst) {
+            return Vec::new();
+        }
+
+        let node = &discovery.node_id;
+        let device = json!({
+            "identifiers": [node],
+            "name": format!("restic-sync {}", node),
+            "manufacturer": "restic-sync",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        });
+        let state_topic = format!("{}/state", self.topic);
+        let last_run_topic = format!("{}/last_run", self.topic);
+
+        let sensor = |object: &str, name: &str, extra: Value| {
+            let mut config = json!({
+                "name": name,
+                "unique_id": format!("{}_{}", node, object),
+                "object_id": format!("{}_{}", node, object),
+                "device": device,
+            });
+            if let (Some(config), Some(extra)) = (config.as_object_mut(), extra.as_object()) {
+                config.extend(extra.clone());
+            }
+            config
+        };
+
+        vec![
+            (
+                format!("{}/sensor/{}/state/config", discovery.prefix, node),
+                sensor(
+                    "state",
+                    "State",
+                    json!({ "state_topic": state_topic, "icon": "mdi:backup-restore" }),
+                ),
+            ),
+            (
+                format!("{}/binary_sensor/{}/problem/config", discovery.prefix, node),
+                sensor(
+                    "problem",
+                    "Problem",
+                    json!({
+                        "state_topic": state_topic,
+                        "device_class": "problem",
+                        "value_template": "{{ 'ON' if value == 'failure' else 'OFF' }}",
+                    }),
+                ),
+            ),
+            (
+                format!("{}/sensor/{}/uploaded_bytes/config", discovery.prefix, node),
+                sensor(
+                    "uploaded_bytes",
+                    "Uploaded",
+                    json!({
+                        "state_topic": last_run_topic,
+                        "device_class": "data_size",
+                        "unit_of_measurement": "B",
+                        "value_template": "{{ value_json.uploaded_bytes }}",
+                    }),
+                ),
+            ),
+            (
+                format!("{}/sensor/{}/uploaded_files/config", discovery.prefix, node),
+                sensor(
+                    "uploaded_files",
+                    "Uploaded files",
+                    json!({
+                        "state_topic": last_run_topic,
+                        "value_template": "{{ value_json.uploaded_files }}",
+                    }),
+                ),
+            ),
+            (
+                format!("{}/sensor/{}/deleted_files/config", discovery.prefix, node),
+                sensor(
+                    "deleted_files",
+                    "Deleted files",
+                    json!({
+                        "state_topic": last_run_topic,
+                        "value_template": "{{ value_json.deleted_files }}",
+                    }),
+                ),
+            ),
+            (
+                format!("{}/sensor/{}/duration/config", discovery.prefix, node),
+                sensor(
+                    "duration",
+                    "Duration",
+                    json!({
+                        "state_topic": last_run_topic,
+                        "device_class": "duration",
+                        "unit_of_measurement": "s",
+                        "value_template": "{{ value_json.duration_secs }}",
+                    }),
+                ),
+            ),
+            (
+                format!("{}/sensor/{}/last_run/config", discovery.prefix, node),
+                sensor(
+                    "last_run",
+                    "Last run",
+                    json!({
+                        "state_topic": last_run_topic,
+                        "device_class": "timestamp",
+                        "value_template": "{{ value_json.finished_at }}",
+                        "json_attributes_topic": last_run_topic,
+                    }),
+                ),
+            ),
+        ]
+    }
+
+    async fn publish(&self, messages: Vec<(String, Value)>) -> Result<()> {
+        tokio::time::timeout(PUBLISH_TIMEOUT, self.publish_inner(messages))
+            .await
+            .context("Timed out publishing to MQTT broker")?
+    }
+
+    async fn publish_inner(&self, messages: Vec<(String, Value)>) -> Result<()> {
+        let (client, mut eventloop) = AsyncClient::new(self.options.clone(), messages.len() + 1);
+        let mut pending = messages.len();
+        for (topic, payload) in messages {
+            let payload = match payload {
+                Value::String(s) => s.into_bytes(),
+                other => other.to_string().into_bytes(),
+            };
+            client
+                .publish(topic, QoS::AtLeastOnce, true, payload)
+                .await?;
+        }
+
+        loop {
+            match eventloop.poll().await? {
+                Event::Incoming(Packet::PubAck(_)) => {
+                    pending -= 1;
+                    if pending == 0 {
+                        client.disconnect().await?;
+                    }
+                }
+                Event::Outgoing(Outgoing::Disconnect) => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+}
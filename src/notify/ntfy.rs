@@ -0,0 +1,73 @@
+//! ntfy (ntfy.sh or self-hosted) notifications.
+//!
+//! Publishes the run summary to a topic URL, mapping failures to a separate
+//! (by default urgent) priority so they can cut through do-not-disturb.
+
+use super::{NotifyOn, RunOutcome};
+use crate::redact::{Redacted, url_host};
+use anyhow::{Result, bail};
+use reqwest::Client;
+
+#[derive(Debug, Clone)]
+pub struct Ntfy {
+    url: Redacted,
+    token: Option<Redacted>,
+    on: NotifyOn,
+    priority: u8,
+    failure_priority: u8,
+}
+
+impl Ntfy {
+    pub fn new(
+        url: &str,
+        token: Option<Redacted>,
+        on: NotifyOn,
+        priority: u8,
+        failure_priority: u8,
+    ) -> Self {
+        Ntfy {
+            url: Redacted(url.to_string()),
+            token,
+            on,
+            priority,
+            failure_priority,
+        }
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        if !self.on.matches(outcome) {
+            return Ok(());
+        }
+
+        let (priority, tags) = if outcome.succeeded() {
+            (self.priority, "white_check_mark")
+        } else {
+            (self.failure_priority, "rotating_light")
+        };
+
+        let mut req = client
+            .post(self.url.as_str())
+            .header("X-Title", outcome.title())
+            .header("X-Priority", priority.to_string())
+            .header("X-Tags", tags)
+            .body(format!(
+                "{} -> {}\n{}",
+                outcome.source,
+                outcome.dest,
+                outcome.summary()
+            ));
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token.as_str());
+        }
+
+        let resp = req.send().await.map_err(|e| e.without_url())?;
+        if !resp.status().is_success() {
+            bail!(
+                "ntfy publish to {} failed: {}",
+                url_host(&self.url),
+                resp.status()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,62 @@
+//! Slack incoming webhook notifications.
+
+use super::{NotifyOn, RunOutcome};
+use crate::redact::Redacted;
+use anyhow::{Result, bail};
+use reqwest::Client;
+use serde_json::json;
+
+#[derive(Debug, Clone)]
+pub struct Slack {
+    url: Redacted,
+    channel: Option<String>,
+    on: NotifyOn,
+}
+
+impl Slack {
+    pub fn new(url: &str, channel: Option<String>, on: NotifyOn) -> Self {
+        Slack {
+            url: Redacted(url.to_string()),
+            channel,
+            on,
+        }
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        if !self.on.matches(outcome) {
+            return Ok(());
+        }
+
+        let color = if outcome.succeeded() {
+            "good"
+        } else {
+            "danger"
+        };
+        let mut payload = json!({
+            "text": format!("{}: {}", outcome.title(), outcome.summary()),
+            "attachments": [{
+                "color": color,
+                "title": outcome.title(),
+                "text": outcome.summary(),
+                "fields": [
+                    { "title": "Source", "value": outcome.source, "short": true },
+                    { "title": "Destination", "value": outcome.dest, "short": true },
+                ],
+            }],
+        });
+        if let Some(channel) = &self.channel {
+            payload["channel"] = json!(channel);
+        }
+
+        let resp = client
+            .post(self.url.as_str())
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        if !resp.status().is_success() {
+            bail!("Slack webhook returned {}", resp.status());
+        }
+        Ok(())
+    }
+}
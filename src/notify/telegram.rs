@@ -0,0 +1,63 @@
+//! Telegram bot notifications.
+
+use super::{NotifyOn, RunOutcome};
+use crate::redact::Redacted;
+use anyhow::{Result, bail};
+use reqwest::Client;
+use serde_json::json;
+
+const API_URL: &str = "https://api.telegram.org";
+
+#[derive(Debug, Clone)]
+pub struct Telegram {
+    token: Redacted,
+    chat_id: String,
+    on: NotifyOn,
+}
+
+impl Telegram {
+    pub fn new(token: &str, chat_id: &str, on: NotifyOn) -> Self {
+        Telegram {
+            token: Redacted(token.to_string()),
+            chat_id: chat_id.to_string(),
+            on,
+        }
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        if !self.on.matches(outcome) {
+            return Ok(());
+        }
+
+        let icon = if outcome.succeeded() {
+            "\u{2705}"
+        } else {
+            "\u{1F6A8}"
+        };
+        let payload = json!({
+            "chat_id": self.chat_id,
+            "text": format!(
+                "{} {}\n{} -> {}\n{}",
+                icon,
+                outcome.title(),
+                outcome.source,
+                outcome.dest,
+                outcome.summary()
+            ),
+            "disable_web_page_preview": true,
+        });
+
+        // The bot token is part of the URL, so keep it out of error messages.
+        let url = format!("{}/bot{}/sendMessage", API_URL, self.token.as_str());
+        let resp = client
+            .post(&url)
+            .json(&payload)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        if !resp.status().is_success() {
+            bail!("Telegram sendMessage returned {}", resp.status());
+        }
+        Ok(())
+    }
+}
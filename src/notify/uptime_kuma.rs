@@ -0,0 +1,38 @@
+//! Uptime Kuma push monitor integration.
+//!
+//! The push URL shown by Uptime Kuma already carries default `status`, `msg`
+//! and `ping` parameters; they are replaced with the run status, summary, and
+//! duration in milliseconds.
+
+use super::RunOutcome;
+use anyhow::{Context, Result, bail};
+use crate::redact::Redacted;
+use reqwest::{Client, Url};
+
+#[derive(Debug, Clone)]
+pub struct UptimeKuma {
+    url: Redacted<Url>,
+}
+
+impl UptimeKuma {
+    pub fn new(url: &str) -> Result<Self> {
+        let mut url = Url::parse(url).context("Invalid Uptime Kuma push URL")?;
+        url.set_query(None);
+        Ok(UptimeKuma { url: Redacted(url) })
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        let status = if outcome.succeeded() { "up" } else { "down" };
+        let mut url = self.url.0.clone();
+        url.query_pairs_mut()
+            .append_pair("status", status)
+            .append_pair("msg", &outcome.summary())
+            .append_pair("ping", &outcome.duration.as_millis().to_string());
+
+        let resp = client.get(url).send().await.map_err(|e| e.without_url())?;
+        if !resp.status().is_success() {
+            bail!("Uptime Kuma push returned {}", resp.status());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+//! Generic JSON webhook notifications.
+//!
+//! Without a template the payload is a fixed JSON document describing the
+//! run. A template is any JSON text containing `{{placeholder}}` markers;
+//! string values are substituted JSON-escaped (without surrounding quotes) so
+//! they can be placed inside string literals, and numbers are substituted
+//! verbatim. Available placeholders: `status`, `source`, `dest`, `summary`,
+//! `error`, `uploaded_files`, `uploaded_bytes`, `deleted_files`,
+//! `duration_secs`.
+
+use super::{NotifyOn, RunOutcome};
+use crate::redact::{Redacted, url_host};
+use anyhow::{Result, bail};
+use reqwest::Client;
+use serde_json::{Value, json};
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: Redacted,
+    template: Option<String>,
+    on: NotifyOn,
+}
+
+impl Webhook {
+    pub fn new(url: &str, template: Option<String>, on: NotifyOn) -> Self {
+        Webhook {
+            url: Redacted(url.to_string()),
+            template,
+            on,
+        }
+    }
+
+    pub async fn run_finished(&self, client: &Client, outcome: &RunOutcome<'_>) -> Result<()> {
+        if !self.on.matches(outcome) {
+            return Ok(());
+        }
+
+        let body = match &self.template {
+            Some(template) => render(template, &variables(outcome)),
+            None => payload(outcome).to_string(),
+        };
+
+        let resp = client
+            .post(self.url.as_str())
+            .header("Content-Type", "application/json")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+        if !resp.status().is_success() {
+            bail!(
+                "Webhook to {} returned {}",
+                url_host(&self.url),
+                resp.status()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn status(outcome: &RunOutcome) -> &'static str {
+    if outcome.succeeded() {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+fn payload(outcome: &RunOutcome) -> Value {
+    let report = outcome.report();
+    json!({
+        "status": status(outcome),
+        "source": outcome.source,
+        "dest": outcome.dest,
+        "summary": outcome.summary(),
+        "error": outcome.error(),
+        "stats": report.map(|r| json!({
+            "uploaded_files": r.uploaded_files,
+            "uploaded_bytes": r.uploaded_bytes,
+            "deleted_files": r.deleted_files,
+            "duration_secs": r.duration.as_secs_f64(),
+            "types": r.types.iter().map(|t| json!({
+                "type": t.file_type,
+                "uploaded_files": t.uploaded_files,
+                "uploaded_bytes": t.uploaded_bytes,
+                "deleted_files": t.deleted_files,
+                "deleted_bytes": t.deleted_bytes,
+                "skipped_files": t.skipped_files,
+                "skipped_bytes": t.skipped_bytes,
+                "duration_secs": t.duration.as_secs_f64(),
+            })).collect::<Vec<_>>(),
+            "errors": r.errors,
+        })),
+    })
+}
+
+fn variables(outcome: &RunOutcome) -> Vec<(&'static str, Value)> {
+    let report = outcome.report().cloned().unwrap_or_default();
+    vec![
+        ("status", json!(status(outcome))),
+        ("source", json!(outcome.source)),
+        ("dest", json!(outcome.dest)),
+        ("summary", json!(outcome.summary())),
+        ("error", json!(outcome.error().unwrap_or_default())),
+        ("uploaded_files", json!(report.uploaded_files)),
+        ("uploaded_bytes", json!(report.uploaded_bytes)),
+        ("deleted_files", json!(report.deleted_files)),
+        ("duration_secs", json!(report.duration.as_secs_f64())),
+    ]
+}
+
+fn render(template: &str, variables: &[(&str, Value)]) -> String {
+    let mut out = template.to_string();
+    for (name, value) in variables {
+        let replacement = match value {
+            Value::String(s) => {
+                let quoted = Value::String(s.clone()).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            other => other.to_string(),
+        };
+        out = out.replace(&format!("{{{{{}}}}}", name), &replacement);
+    }
+    out
+}
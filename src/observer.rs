@@ -0,0 +1,77 @@
+//! Callbacks for programs that embed the sync.
+//!
+//! The command shows progress through [`crate::output`]; other programs
+//! register a [`SyncObserver`] with [`crate::SyncClient::with_observer`] to
+//! draw their own progress or collect their own metrics. The callbacks run
+//! on the sync's task between requests, so they should return quickly.
+
+use crate::{FileAction, SyncReport, TypeReport};
+use std::fmt;
+use std::sync::Arc;
+
+/// A file that a sync uploads or deletes.
+#[derive(Debug, Clone, Copy)]
+pub struct FileEvent<'a> {
+    pub action: FileAction,
+    pub file_type: &'a str,
+    pub name: &'a str,
+    /// The size listed by the source, or by the destination for deletions.
+    pub size: u64,
+}
+
+/// Gets told about each file of a sync as it is handled. Every method does
+/// nothing unless implemented.
+pub trait SyncObserver: Send + Sync {
+    fn on_file_start(&self, _file: &FileEvent) {}
+
+    /// The file was uploaded, `bytes` of it, or deleted.
+    fn on_file_complete(&self, _file: &FileEvent, _bytes: u64) {}
+
+    /// Handling the file failed, which fails the sync.
+    fn on_file_error(&self, _file: &FileEvent, _error: &anyhow::Error) {}
+
+    /// All files of a type are handled.
+    fn on_type_complete(&self, _report: &TypeReport) {}
+
+    /// The run is over, after its hooks and notifiers.
+    fn on_run_complete(&self, _result: &anyhow::Result<SyncReport>) {}
+}
+
+/// The observers of a [`crate::SyncClient`], called in the order they were
+/// added.
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Arc<dyn SyncObserver>>);
+
+impl Observers {
+    pub fn add(&mut self, observer: Arc<dyn SyncObserver>) {
+        self.0.push(observer);
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl SyncObserver for Observers {
+    fn on_file_start(&self, file: &FileEvent) {
+        self.0.iter().for_each(|o| o.on_file_start(file));
+    }
+
+    fn on_file_complete(&self, file: &FileEvent, bytes: u64) {
+        self.0.iter().for_each(|o| o.on_file_complete(file, bytes));
+    }
+
+    fn on_file_error(&self, file: &FileEvent, error: &anyhow::Error) {
+        self.0.iter().for_each(|o| o.on_file_error(file, error));
+    }
+
+    fn on_type_complete(&self, report: &TypeReport) {
+        self.0.iter().for_each(|o| o.on_type_complete(report));
+    }
+
+    fn on_run_complete(&self, result: &anyhow::Result<SyncReport>) {
+        self.0.iter().for_each(|o| o.on_run_complete(result));
+    }
+}
@@ -0,0 +1,234 @@
+//! What a sync prints, depending on the verbosity.
+//!
+//! `-q`, `-v`, `-vv` and `-vvv` set the log level and with it whether each
+//! transferred file is logged, how progress is shown and whether a run ends
+//! with a summary. Without them, the output depends on where stderr goes: a
+//! terminal gets warnings, a live progress line and a plain summary, while
+//! anything else (Docker, systemd, cron mail) gets info logs with a line per
+//! file and progress every half minute. With `--tui`, all of it goes to the
+//! dashboard of [`crate::tui`] instead. With `--json`, stdout is left to
+//! the events, so the summary is only logged. `RUST_LOG` still overrides the
+//! log filter.
+
+use crate::format_bytes;
+use crate::tui;
+use log::{Level, LevelFilter, info};
+use std::io::{IsTerminal, Write};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+    Debug,
+    Trace,
+}
+
+struct Settings {
+    verbosity: Verbosity,
+    tty: bool,
+    tui: bool,
+    json: bool,
+    /// Whether this is the command rather than another program using the
+    /// library, which only gets log messages.
+    command: bool,
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(|| Settings {
+        verbosity: Verbosity::Normal,
+        tty: false,
+        tui: false,
+        json: false,
+        command: false,
+    })
+}
+
+/// Sets up logging for `-v` given `verbose` times, or `-q`, for the
+/// dashboard if `tui` is set and for JSON lines on stdout if `json` is.
+pub fn init(verbose: u8, quiet: bool, tui: bool, json: bool) {
+    let verbosity = match (quiet, verbose) {
+        (true, _) => Verbosity::Quiet,
+        (false, 0) => Verbosity::Normal,
+        (false, 1) => Verbosity::Verbose,
+        (false, 2) => Verbosity::Debug,
+        (false, _) => Verbosity::Trace,
+    };
+    let tty = std::io::stderr().is_terminal();
+
+    let mut builder = env_logger::Builder::new();
+    // Libraries only get to speak up about problems, except at -vvv.
+    let (libraries, ours) = match verbosity {
+        Verbosity::Quiet => (LevelFilter::Error, LevelFilter::Error),
+        Verbosity::Normal if tty && !tui => (LevelFilter::Warn, LevelFilter::Warn),
+        Verbosity::Normal | Verbosity::Verbose => (LevelFilter::Warn, LevelFilter::Info),
+        Verbosity::Debug => (LevelFilter::Warn, LevelFilter::Debug),
+        Verbosity::Trace => (LevelFilter::Debug, LevelFilter::Trace),
+    };
+    builder
+        .filter_level(libraries)
+        .filter_module(env!("CARGO_CRATE_NAME"), ours);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    if tui {
+        builder.target(env_logger::Target::Pipe(Box::new(tui::LogPane::new())));
+    }
+    builder.init();
+    let _ = SETTINGS.set(Settings {
+        verbosity,
+        tty,
+        tui,
+        json,
+        command: true,
+    });
+}
+
+/// Whether every uploaded and deleted file gets a log line.
+pub fn file_lines() -> bool {
+    let settings = settings();
+    match settings.verbosity {
+        Verbosity::Quiet => false,
+        Verbosity::Normal => !settings.tty && !settings.tui,
+        _ => true,
+    }
+}
+
+/// Ends a sync run with `summary`, as a log line if info logs are shown and
+/// printed plainly otherwise.
+pub fn summary(summary: &str) {
+    let settings = settings();
+    if settings.tui {
+        tui::summary(summary);
+        return;
+    }
+    if settings.verbosity == Verbosity::Quiet {
+        return;
+    }
+    if log::log_enabled!(Level::Info) || !settings.command || settings.json {
+        info!("{}", summary);
+    } else {
+        println!("{}", summary);
+    }
+}
+
+/// Progress through the transfers of one file type.
+pub struct Progress {
+    file_type: String,
+    files: usize,
+    bytes: u64,
+    done_files: usize,
+    done_bytes: u64,
+    style: Style,
+    last: Instant,
+    drawn: bool,
+}
+
+#[derive(PartialEq)]
+enum Style {
+    None,
+    /// A line on the terminal that is redrawn in place.
+    Live,
+    /// An info log line every so often.
+    Periodic,
+    /// The queue of the file type on the dashboard.
+    Dashboard,
+}
+
+impl Progress {
+    pub fn new(file_type: &str, files: usize, bytes: u64) -> Self {
+        let settings = settings();
+        let style = match settings.verbosity {
+            _ if settings.tui => Style::Dashboard,
+            Verbosity::Quiet => Style::None,
+            // Per-file log lines would tear up a live line.
+            Verbosity::Normal if settings.tty => Style::Live,
+            _ => Style::Periodic,
+        };
+        if style == Style::Dashboard {
+            tui::queue(file_type, files, bytes);
+        }
+        Progress {
+            file_type: file_type.to_string(),
+            files,
+            bytes,
+            done_files: 0,
+            done_bytes: 0,
+            style,
+            last: Instant::now(),
+            drawn: false,
+        }
+    }
+
+    /// Records a finished transfer of `bytes`.
+    pub fn advance(&mut self, bytes: u64) {
+        self.done_files += 1;
+        self.done_bytes += bytes;
+        let interval = match self.style {
+            Style::None => return,
+            Style::Dashboard => return tui::dequeue(&self.file_type, bytes),
+            Style::Live => Duration::from_millis(250),
+            Style::Periodic => Duration::from_secs(30),
+        };
+        if self.last.elapsed() >= interval && self.done_files < self.files {
+            self.last = Instant::now();
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        let line = format!(
+            "[{}] {}/{} files, {} of {}",
+            self.file_type,
+            self.done_files,
+            self.files,
+            format_bytes(self.done_bytes),
+            format_bytes(self.bytes)
+        );
+        if self.style == Style::Live {
+            let mut stderr = std::io::stderr().lock();
+            let _ = write!(stderr, "\r\x1b[K{}", line);
+            let _ = stderr.flush();
+            self.drawn = true;
+        } else {
+            info!("{}", line);
+        }
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        // Leave the terminal clean for whatever comes next.
+        if self.drawn {
+            let _ = write!(std::io::stderr(), "\r\x1b[K");
+        }
+    }
+}
+
+/// A download in progress, shown on the dashboard until it is dropped.
+pub struct Transfer(Option<u64>);
+
+impl Transfer {
+    pub fn start(file_type: &str, name: &str, size: u64) -> Self {
+        Transfer(settings().tui.then(|| tui::start(file_type, name, size)))
+    }
+
+    /// Records `bytes` more downloaded.
+    pub fn advance(&self, bytes: u64) {
+        if let Some(id) = self.0 {
+            tui::progress(id, bytes);
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if let Some(id) = self.0 {
+            tui::finish(id);
+        }
+    }
+}
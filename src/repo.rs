@@ -0,0 +1,355 @@
+//! HTTP access to a single Restic REST repository.
+
+use crate::Error;
+use crate::endpoint::{Auth, EndpointOptions};
+use crate::normalize_url;
+use crate::redact::redact_url;
+use crate::request_id::RequestId;
+use crate::snapshot::Selector;
+use crate::state::ListingCache;
+use crate::fanout::SharedDownloads;
+use crate::filter::SkipList;
+use crate::verification::VerifiedDownloads;
+use crate::throttle::{RetryStrategy, Throttle};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use http::Extensions;
+use log::{debug, info, warn};
+use percent_encoding::percent_decode_str;
+use reqwest::{Method, Request, Response, Url};
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next, RequestBuilder};
+use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// A repository endpoint with its own HTTP client and credentials.
+#[derive(Clone)]
+pub struct Repo {
+    url: String,
+    client: Arc<RecyclingClient>,
+    auth: Option<Auth>,
+    /// Where listings of the repository are answered from and its changes
+    /// recorded, with `--cache-dest-listing`.
+    listing_cache: Option<ListingCache>,
+    /// Files downloaded from the repository in this run that passed their
+    /// check, but whose upload failed.
+    verified: Arc<VerifiedDownloads>,
+    /// Downloads shared with the other jobs that sync from the repository.
+    shared: Option<Arc<SharedDownloads>>,
+    /// The snapshots to sync from the repository, with a snapshot filter.
+    selector: Option<Arc<Selector>>,
+    /// The files never to upload from the repository.
+    skip_list: Option<Arc<SkipList>>,
+    /// How many more times to download a file that fails its check.
+    redownloads: u32,
+}
+
+impl std::fmt::Debug for Repo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Repo")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Repo {
+    /// Sets up the client for the repository at `url`; `run_id` identifies
+    /// the sync run in the request IDs sent to the server.
+    pub fn connect(url: &str, options: &EndpointOptions, run_id: &str) -> Result<Self> {
+        // As restic writes them, e.g. in RESTIC_REPOSITORY.
+        let url = url.strip_prefix("rest:").unwrap_or(url);
+        let (url, url_auth) = split_credentials(url)?;
+        let parsed = Url::parse(&url)
+            .with_context(|| format!("Invalid repository URL {}", redact_url(&url)))?;
+        let host = parsed.host_str().map(str::to_string);
+        if options.insecure_tls {
+            warn!(
+                "TLS certificate verification is DISABLED for {}; \
+                 the connection is vulnerable to interception",
+                redact_url(&url)
+            );
+        }
+        if let Some(proxy) = &options.proxy {
+            info!(
+                "Connecting to {} through proxy {}",
+                redact_url(&url),
+                redact_url(proxy)
+            );
+        }
+
+        let retry_policy = ExponentialBackoff::builder().build_with_max_retries(5);
+        let mut middleware: Vec<Arc<dyn Middleware>> = vec![Arc::new(
+            RetryTransientMiddleware::new_with_policy_and_strategy(retry_policy, RetryStrategy),
+        )];
+        if let Some(circuit) = &options.circuit {
+            middleware.push(Arc::new(circuit.clone()));
+        }
+        middleware.push(Arc::new(Throttle::default()));
+        middleware.push(Arc::new(RequestId::new(run_id)));
+        if let Some(timeout) = options.stall_timeout {
+            middleware.push(Arc::new(StallTimeout(timeout)));
+        }
+
+        let client = RecyclingClient::new(options.clone(), parsed, middleware)?;
+        Ok(Repo {
+            url: normalize_url(&url),
+            client: Arc::new(client),
+            auth: options.resolve_auth(host.as_deref(), url_auth)?,
+            listing_cache: None,
+            verified: Arc::default(),
+            shared: None,
+            selector: None,
+            skip_list: None,
+            redownloads: 0,
+        })
+    }
+
+    /// Lists the repository from `cache` once it holds a listing, and
+    /// records the uploads and deletions there.
+    pub fn with_listing_cache(mut self, cache: ListingCache) -> Self {
+        self.listing_cache = Some(cache);
+        self
+    }
+
+    pub fn listing_cache(&self) -> Option<&ListingCache> {
+        self.listing_cache.as_ref()
+    }
+
+    pub fn verified_downloads(&self) -> &VerifiedDownloads {
+        &self.verified
+    }
+
+    pub fn with_shared_downloads(mut self, shared: Arc<SharedDownloads>) -> Self {
+        self.shared = Some(shared);
+        self
+    }
+
+    pub fn shared_downloads(&self) -> Option<&SharedDownloads> {
+        self.shared.as_deref()
+    }
+
+    pub fn with_selector(mut self, selector: Selector) -> Self {
+        self.selector = Some(Arc::new(selector));
+        self
+    }
+
+    pub fn selector(&self) -> Option<&Selector> {
+        self.selector.as_deref()
+    }
+
+    pub fn with_skip_list(mut self, skip_list: SkipList) -> Self {
+        self.skip_list = Some(Arc::new(skip_list));
+        self
+    }
+
+    pub fn skip_list(&self) -> Option<&SkipList> {
+        self.skip_list.as_deref()
+    }
+
+    /// Downloads a file that fails its check again up to `redownloads`
+    /// times, past any caches, before taking it as corrupt.
+    pub fn with_redownloads(mut self, redownloads: u32) -> Self {
+        self.redownloads = redownloads;
+        self
+    }
+
+    pub fn redownloads(&self) -> u32 {
+        self.redownloads
+    }
+
+    /// How long a download may make no progress, with `--stall-timeout`.
+    pub fn stall_timeout(&self) -> Option<Duration> {
+        self.client.options.stall_timeout
+    }
+
+    /// The repository base URL, always ending in `/`.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The absolute URL of `path` within the repository.
+    pub fn join(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    /// The credentials sent to the repository, without their secrets.
+    pub fn describe_auth(&self) -> String {
+        match &self.auth {
+            Some(Auth::Basic { user, .. }) => format!("basic auth as {user}"),
+            Some(Auth::Bearer { .. }) => "bearer token".to_string(),
+            None => "none".to_string(),
+        }
+    }
+
+    pub fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let req = self.client.get().request(method, self.join(path));
+        match &self.auth {
+            Some(Auth::Basic { user, password }) => req.basic_auth(user, password.as_deref()),
+            Some(Auth::Bearer { token }) => req.bearer_auth(token.as_str()),
+            None => req,
+        }
+    }
+
+    pub fn get(&self, path: &str) -> RequestBuilder {
+        self.request(Method::GET, path)
+    }
+
+    pub fn head(&self, path: &str) -> RequestBuilder {
+        self.request(Method::HEAD, path)
+    }
+
+    pub fn post(&self, path: &str) -> RequestBuilder {
+        self.request(Method::POST, path)
+    }
+
+    pub fn delete(&self, path: &str) -> RequestBuilder {
+        self.request(Method::DELETE, path)
+    }
+}
+
+/// Answers the requests to a repository in place of its server, e.g. with
+/// canned responses in tests. Requests still pass through the retries and
+/// the rest of the middleware first.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    async fn send(&self, request: Request) -> Result<Response>;
+}
+
+/// The [`Transport`] of an endpoint, as the innermost middleware.
+#[derive(Clone)]
+pub struct InjectedTransport(pub Arc<dyn Transport>);
+
+impl std::fmt::Debug for InjectedTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("InjectedTransport")
+    }
+}
+
+#[async_trait]
+impl Middleware for InjectedTransport {
+    async fn handle(
+        &self,
+        req: Request,
+        _extensions: &mut Extensions,
+        _next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        self.0
+            .send(req)
+            .await
+            .map_err(reqwest_middleware::Error::Middleware)
+    }
+}
+
+/// Gives up on a read, i.e. a `GET` or `HEAD`, whose response does not
+/// start within the endpoint's `stall_timeout`, with [`Error::Stalled`]. It
+/// runs inside the retries and throttling, so that it bounds each try rather
+/// than their pauses; uploads are left alone, since sending a large file
+/// takes its time.
+struct StallTimeout(Duration);
+
+#[async_trait]
+impl Middleware for StallTimeout {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) {
+            return next.run(req, extensions).await;
+        }
+        let url = redact_url(req.url().as_str());
+        match tokio::time::timeout(self.0, next.run(req, extensions)).await {
+            Ok(result) => result,
+            Err(_) => Err(reqwest_middleware::Error::Middleware(
+                Error::Stalled { url, timeout: self.0 }.into(),
+            )),
+        }
+    }
+}
+
+/// An HTTP client that is rebuilt once it gets older than the endpoint's
+/// `recycle_after`, dropping its pooled connections so that the host name is
+/// resolved again. This lets long runs follow a failover to a new address or
+/// a load balancer rotating its backends. Middleware state (throttling, the
+/// circuit breaker, request numbering) is kept across rebuilds.
+struct RecyclingClient {
+    options: EndpointOptions,
+    url: Url,
+    middleware: Vec<Arc<dyn Middleware>>,
+    current: Mutex<(ClientWithMiddleware, Instant)>,
+}
+
+impl RecyclingClient {
+    fn new(options: EndpointOptions, url: Url, middleware: Vec<Arc<dyn Middleware>>) -> Result<Self> {
+        let client = build_client(&options, &url, &middleware)?;
+        Ok(RecyclingClient {
+            options,
+            url,
+            middleware,
+            current: Mutex::new((client, Instant::now())),
+        })
+    }
+
+    fn get(&self) -> ClientWithMiddleware {
+        let mut current = self.current.lock().unwrap();
+        if let Some(max_age) = self.options.recycle_after
+            && current.1.elapsed() >= max_age
+        {
+            match build_client(&self.options, &self.url, &self.middleware) {
+                Ok(client) => {
+                    debug!("Recycling connections to {}", redact_url(self.url.as_str()));
+                    *current = (client, Instant::now());
+                }
+                // The settings were valid when first built, so keep going
+                // with the old client rather than failing the request.
+                Err(e) => warn!(
+                    "Failed to rebuild HTTP client for {}: {:#}",
+                    redact_url(self.url.as_str()),
+                    e
+                ),
+            }
+        }
+        current.0.clone()
+    }
+}
+
+fn build_client(
+    options: &EndpointOptions,
+    url: &Url,
+    middleware: &[Arc<dyn Middleware>],
+) -> Result<ClientWithMiddleware> {
+    let client = options
+        .client_builder(url)?
+        .build()
+        .context("Failed to build HTTP client")?;
+    let builder = middleware
+        .iter()
+        .fold(ClientBuilder::new(client), |builder, m| builder.with_arc(m.clone()));
+    Ok(match &options.transport {
+        Some(transport) => builder.with(transport.clone()),
+        None => builder,
+    }
+    .build())
+}
+
+/// Splits `user:password@` out of a URL so that credentials are attached
+/// explicitly per request instead of travelling (and being logged) with it.
+fn split_credentials(url: &str) -> Result<(String, Option<Auth>)> {
+    let mut parsed =
+        Url::parse(url).with_context(|| format!("Invalid repository URL {}", redact_url(url)))?;
+    if parsed.username().is_empty() && parsed.password().is_none() {
+        return Ok((url.to_string(), None));
+    }
+
+    let decode = |s: &str| percent_decode_str(s).decode_utf8_lossy().into_owned();
+    let auth = Auth::Basic {
+        user: decode(parsed.username()),
+        password: parsed.password().map(|password| Zeroizing::new(decode(password))),
+    };
+    // Neither call can fail for URLs that already carry credentials.
+    let _ = parsed.set_username("");
+    let _ = parsed.set_password(None);
+    Ok((parsed.to_string(), Some(auth)))
+}
@@ -0,0 +1,74 @@
+//! Identification of restic-sync traffic in server logs.
+//!
+//! Every request carries an `X-Request-ID` made of the run ID and a sequence
+//! number, so a server-side error can be traced back to the run, and the
+//! request, that caused it.
+
+use async_trait::async_trait;
+use http::Extensions;
+use log::{debug, warn};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{Request, Response};
+use reqwest_middleware::{Middleware, Next};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The User-Agent sent unless `--user-agent` says otherwise.
+pub const DEFAULT_USER_AGENT: &str = concat!("restic-sync/", env!("CARGO_PKG_VERSION"));
+
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// A new short random identifier for a sync run.
+pub fn new_run_id() -> String {
+    let mut id = uuid::Uuid::new_v4().simple().to_string();
+    id.truncate(12);
+    id
+}
+
+/// Tags each request to one endpoint with `<run id>-<sequence number>`.
+pub struct RequestId {
+    run_id: String,
+    seq: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new(run_id: &str) -> Self {
+        RequestId {
+            run_id: run_id.to_string(),
+            seq: AtomicU64::new(0),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for RequestId {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let id = format!(
+            "{}-{}",
+            self.run_id,
+            self.seq.fetch_add(1, Ordering::Relaxed) + 1
+        );
+        let value = HeaderValue::from_str(&id).expect("request IDs are ASCII");
+        req.headers_mut().entry(X_REQUEST_ID).or_insert(value);
+        let (method, url) = (req.method().clone(), req.url().clone());
+        debug!("{} {} (request {})", method, url, id);
+
+        let res = next.run(req, extensions).await;
+        if let Ok(resp) = &res
+            && resp.status().is_server_error()
+        {
+            warn!(
+                "{} {} returned {} (request {})",
+                method,
+                url,
+                resp.status(),
+                id
+            );
+        }
+        res
+    }
+}
@@ -0,0 +1,70 @@
+//! restic's own environment variables.
+//!
+//! Scripts and secret stores set up for restic can be reused as they are:
+//! `RESTIC_REPOSITORY`, `RESTIC_REST_USERNAME` and the like are taken as the
+//! destination, the repository `restic` itself would write to, and their
+//! `RESTIC_FROM_*` forms as the source, like `restic copy` does. They are
+//! only fallbacks: the `REST_SYNC_*` variables and flags take precedence,
+//! while the restic variables override the config file.
+
+use crate::config;
+use clap::Command;
+
+/// Our variable, and the restic variable standing in for it.
+const ALIASES: &[(&str, &str)] = &[
+    ("REST_SYNC_SOURCE", "RESTIC_FROM_REPOSITORY"),
+    ("REST_SYNC_DEST", "RESTIC_REPOSITORY"),
+    ("REST_SYNC_SOURCE_USER", "RESTIC_FROM_REST_USERNAME"),
+    ("REST_SYNC_DEST_USER", "RESTIC_REST_USERNAME"),
+    ("REST_SYNC_SOURCE_PASSWORD", "RESTIC_FROM_REST_PASSWORD"),
+    ("REST_SYNC_DEST_PASSWORD", "RESTIC_REST_PASSWORD"),
+    ("REST_SYNC_SOURCE_CACERT", "RESTIC_FROM_CACERT"),
+    ("REST_SYNC_DEST_CACERT", "RESTIC_CACERT"),
+    (
+        "REST_SYNC_SOURCE_CLIENT_CERT",
+        "RESTIC_FROM_TLS_CLIENT_CERT",
+    ),
+    ("REST_SYNC_DEST_CLIENT_CERT", "RESTIC_TLS_CLIENT_CERT"),
+    ("REST_SYNC_REPO_PASSWORD", "RESTIC_PASSWORD"),
+    ("REST_SYNC_REPO_PASSWORD_FILE", "RESTIC_PASSWORD_FILE"),
+    ("REST_SYNC_REPO_PASSWORD_COMMAND", "RESTIC_PASSWORD_COMMAND"),
+];
+
+/// Installs the restic variables that are set, and whose counterpart is not,
+/// as defaults of the options they stand in for.
+pub fn apply(mut command: Command) -> Command {
+    for &(ours, _) in ALIASES {
+        let Some(theirs) = stand_in(ours) else {
+            continue;
+        };
+        let Some(value) = std::env::var_os(theirs) else {
+            continue;
+        };
+        let targets: Vec<(String, String)> = command
+            .get_subcommands()
+            .flat_map(|subcommand| {
+                subcommand
+                    .get_arguments()
+                    .filter(|arg| arg.get_env().is_some_and(|env| env == ours))
+                    .map(|arg| (subcommand.get_name().to_string(), arg.get_id().to_string()))
+            })
+            .collect();
+        for (subcommand, id) in targets {
+            let value = value.to_string_lossy().into_owned();
+            command = config::set_default(command, &subcommand, &id, vec![value]);
+        }
+    }
+    command
+}
+
+/// The restic variable that takes the place of `ours`, if it is set and
+/// `ours` is not.
+pub fn stand_in(ours: &str) -> Option<&'static str> {
+    if std::env::var_os(ours).is_some() {
+        return None;
+    }
+    ALIASES
+        .iter()
+        .find(|&&(alias, theirs)| alias == ours && std::env::var_os(theirs).is_some())
+        .map(|&(_, theirs)| theirs)
+}
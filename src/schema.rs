@@ -0,0 +1,299 @@
+//! The JSON that restic-sync prints and reads, described as JSON Schema
+//! documents for `restic-sync schema`.
+//!
+//! [`VERSION`] is part of every event line and plan file. It goes up when a
+//! change would break readers, such as removing or renaming a field or
+//! changing what it means; fields and events may be added without it, so
+//! readers should ignore those they do not know.
+
+use serde_json::{Value, json};
+
+/// The version of the formats described here.
+pub const VERSION: u32 = restic_sync_core::FORMAT_VERSION;
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schema {
+    /// The report of a sync run, as in the `run_complete` event
+    Report,
+    /// The lines that `sync --json` prints
+    Events,
+    /// A plan of uploads and deletions
+    Plan,
+    /// What a destination has, as `plan listing` prints it
+    Listing,
+}
+
+impl Schema {
+    fn name(self) -> &'static str {
+        match self {
+            Schema::Report => "report",
+            Schema::Events => "events",
+            Schema::Plan => "plan",
+            Schema::Listing => "listing",
+        }
+    }
+}
+
+/// The JSON Schema document of `schema`.
+pub fn document(schema: Schema) -> Value {
+    let mut document = match schema {
+        Schema::Report => report(),
+        Schema::Events => events(),
+        Schema::Plan => plan(),
+        Schema::Listing => listing(),
+    };
+    document["$schema"] = "https://json-schema.org/draft/2020-12/schema".into();
+    document["$id"] = format!(
+        "{}/schemas/v{}/{}.json",
+        env!("CARGO_PKG_REPOSITORY"),
+        VERSION,
+        schema.name()
+    )
+    .into();
+    document
+}
+
+fn count() -> Value {
+    json!({ "type": "integer", "minimum": 0 })
+}
+
+fn object(description: &str, properties: Value) -> Value {
+    let required: Vec<&String> = properties
+        .as_object()
+        .map(|p| p.keys().collect())
+        .unwrap_or_default();
+    json!({
+        "description": description,
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+fn report() -> Value {
+    let mut report = report_def();
+    report["title"] = "restic-sync run report".into();
+    report["$defs"] = json!({ "type_report": type_report() });
+    report
+}
+
+/// The report, referring to `#/$defs/type_report`.
+fn report_def() -> Value {
+    object(
+        "What a sync run did.",
+        json!({
+            "uploaded_files": count(),
+            "uploaded_bytes": count(),
+            "deleted_files": count(),
+            "duration_secs": { "type": "number", "minimum": 0 },
+            "divergence": {
+                "description": "What a --monitor run found, instead of uploading anything.",
+                "oneOf": [
+                    { "type": "null" },
+                    object(
+                        "How far the destination is behind the source.",
+                        json!({
+                            "types": {
+                                "type": "array",
+                                "items": object(
+                                    "How far the destination is behind for one file type.",
+                                    json!({
+                                        "file_type": { "type": "string" },
+                                        "behind_files": count(),
+                                        "behind_bytes": count(),
+                                        "extra_files": count(),
+                                        "extra_bytes": count(),
+                                    }),
+                                ),
+                            },
+                        }),
+                    ),
+                ],
+            },
+            "types": {
+                "description": "The totals for each file type, in the order they were synced.",
+                "type": "array",
+                "items": { "$ref": "#/$defs/type_report" },
+            },
+            "errors": {
+                "description": "Problems that did not stop the run.",
+                "type": "array",
+                "items": { "type": "string" },
+            },
+            "failures": {
+                "description": "The files that failed to sync, while the run went on with the others.",
+                "type": "array",
+                "items": object(
+                    "A file that failed to upload or delete.",
+                    json!({
+                        "file_type": { "type": "string" },
+                        "name": { "type": "string" },
+                        "error": { "type": "string" },
+                    }),
+                ),
+            },
+            "remaining": {
+                "description": "What a run that spent its --max-files or --max-bytes budget left for the next one.",
+                "oneOf": [
+                    { "type": "null" },
+                    object(
+                        "The uploads and deletions left for the next run.",
+                        json!({
+                            "upload_files": count(),
+                            "upload_bytes": count(),
+                            "delete_files": count(),
+                        }),
+                    ),
+                ],
+            },
+        }),
+    )
+}
+
+fn type_report() -> Value {
+    object(
+        "What a sync did with the files of one type.",
+        json!({
+            "file_type": { "type": "string" },
+            "uploaded_files": count(),
+            "uploaded_bytes": count(),
+            "deleted_files": count(),
+            "deleted_bytes": count(),
+            "skipped_files": count(),
+            "skipped_bytes": count(),
+            "duration_secs": { "type": "number", "minimum": 0 },
+        }),
+    )
+}
+
+fn events() -> Value {
+    let file = || {
+        json!({
+            "action": { "enum": ["upload", "delete"] },
+            "file_type": { "type": "string" },
+            "name": { "type": "string" },
+            "size": count(),
+        })
+    };
+    let event = |name: &str, description: &str, fields: Value| {
+        let mut properties = json!({
+            "version": { "const": VERSION },
+            "event": { "const": name },
+            "job": {
+                "description": "The name of the job in the config file.",
+                "type": ["string", "null"],
+            },
+        });
+        if let (Some(properties), Value::Object(fields)) = (properties.as_object_mut(), fields) {
+            properties.extend(fields);
+        }
+        object(description, properties)
+    };
+    let with = |mut fields: Value, name: &str, schema: Value| {
+        fields[name] = schema;
+        fields
+    };
+    json!({
+        "title": "restic-sync progress events",
+        "description": "One line of `restic-sync sync --json`.",
+        "oneOf": [
+            event("file_start", "A file is about to be uploaded or deleted.", file()),
+            event(
+                "file_complete",
+                "A file was uploaded, `bytes` of it, or deleted.",
+                with(file(), "bytes", count()),
+            ),
+            event(
+                "file_error",
+                "Handling a file failed, which fails the run.",
+                with(file(), "error", json!({ "type": "string" })),
+            ),
+            event(
+                "type_complete",
+                "All files of a type are handled.",
+                json!({ "report": { "$ref": "#/$defs/type_report" } }),
+            ),
+            event(
+                "run_complete",
+                "The run is over, after its hooks and notifiers.",
+                json!({
+                    "ok": { "type": "boolean" },
+                    "report": {
+                        "description": "Null if the run failed, except for what a cancelled or partly failed run did.",
+                        "oneOf": [{ "type": "null" }, { "$ref": "#/$defs/report" }],
+                    },
+                    "error": { "type": ["string", "null"] },
+                }),
+            ),
+        ],
+        "$defs": {
+            "report": report_def(),
+            "type_report": type_report(),
+        },
+    })
+}
+
+fn plan() -> Value {
+    let files = |description: &str| {
+        json!({
+            "description": description,
+            "type": "array",
+            "items": { "$ref": "#/$defs/planned_file" },
+        })
+    };
+    let mut plan = object(
+        "What a sync is going to do.",
+        json!({
+            "version": { "type": "integer", "minimum": 1, "maximum": VERSION },
+            "config": {
+                "description": "Whether the config file is copied, or compared with the one the destination has.",
+                "type": "boolean",
+            },
+            "types": {
+                "description": "The directories, in the order they are synced.",
+                "type": "array",
+                "items": object(
+                    "The files of one type to upload and delete.",
+                    json!({
+                        "file_type": { "type": "string" },
+                        "uploads": files("Files the destination lacks or has with another size."),
+                        "deletes": files("Files only the destination has, sized as there."),
+                        "kept": files("Files an append-only destination has with another size, which are left alone."),
+                        "unchanged_files": count(),
+                        "unchanged_bytes": count(),
+                    }),
+                ),
+            },
+        }),
+    );
+    plan["title"] = "restic-sync plan".into();
+    plan["$defs"] = json!({
+        "planned_file": object(
+            "A file, with its size.",
+            json!({ "name": { "type": "string" }, "size": count() }),
+        ),
+    });
+    plan
+}
+
+fn listing() -> Value {
+    let mut listing = object(
+        "The files a destination has, for `plan export --dest-listing`.",
+        json!({
+            "version": { "type": "integer", "minimum": 1, "maximum": VERSION },
+            "types": {
+                "description": "The files of each type, as the REST server lists them.",
+                "type": "object",
+                "additionalProperties": {
+                    "type": "array",
+                    "items": object(
+                        "A file, with its size.",
+                        json!({ "name": { "type": "string" }, "size": count() }),
+                    ),
+                },
+            },
+        }),
+    );
+    listing["title"] = "restic-sync destination listing".into();
+    listing
+}
@@ -0,0 +1,447 @@
+//! Finding the files that one restic snapshot needs.
+//!
+//! A snapshot names the tree blob of its root directory. Trees list the
+//! data blobs of their files and the tree blobs of their subdirectories,
+//! and the index tells which pack file holds each blob. Walking the trees
+//! from the root therefore yields the set of packs to copy, together with
+//! the index entries that describe them.
+//!
+//! A sync with a [`SnapshotFilter`] mirrors part of a shared repository
+//! this way: the snapshots that match it, the packs they need and one index
+//! file of just those packs, in place of the source's own.
+
+use crate::commands::RepoKeyArgs;
+use crate::crypto::{self, Key};
+use crate::endpoint::Secret;
+use crate::redact::redact_url;
+use crate::repo::Repo;
+use crate::{Error, FileInfo, list_files, sha256_hex};
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use chrono::DateTime;
+use log::{debug, info};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Read;
+use zeroize::Zeroizing;
+
+/// The contents of an index file.
+#[derive(Serialize, Deserialize, Default)]
+pub struct IndexFile {
+    pub packs: Vec<PackEntry>,
+}
+
+/// The blobs stored in one pack file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PackEntry {
+    pub id: String,
+    pub blobs: Vec<BlobEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlobEntry {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub blob_type: String,
+    pub offset: u64,
+    pub length: u64,
+    /// Set if the blob is compressed, in version 2 repositories.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uncompressed_length: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    time: String,
+    tree: String,
+    #[serde(default)]
+    hostname: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    paths: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Tree {
+    #[serde(default)]
+    nodes: Vec<Node>,
+}
+
+#[derive(Deserialize)]
+struct Node {
+    #[serde(default)]
+    content: Option<Vec<String>>,
+    #[serde(default)]
+    subtree: Option<String>,
+}
+
+/// Where each blob of a repository is stored.
+pub struct Index {
+    packs: HashMap<String, PackEntry>,
+    /// Blob ID to the pack holding it and its position in that pack's
+    /// entry.
+    blobs: HashMap<String, (String, usize)>,
+}
+
+/// Downloads `path` from `repo` and decrypts it.
+pub async fn fetch(repo: &Repo, key: &Key, path: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let resp = repo.get(path).send().await?;
+    if !resp.status().is_success() {
+        bail!(Error::Http {
+            operation: "download",
+            url: redact_url(&repo.join(path)),
+            status: resp.status(),
+        });
+    }
+    key.decrypt_file(&resp.bytes().await?)
+        .with_context(|| format!("Failed to decrypt {path}"))
+}
+
+/// The full name of the snapshot that `id` is a prefix of, as restic
+/// accepts short IDs.
+pub async fn resolve_id(repo: &Repo, id: &str) -> Result<String> {
+    let matches: Vec<String> = list_files(repo, "snapshots")
+        .await?
+        .into_iter()
+        .map(|file| file.name)
+        .filter(|name| name.starts_with(id))
+        .collect();
+    match matches.as_slice() {
+        [name] => Ok(name.clone()),
+        [] => bail!("No snapshot {} in {}", id, redact_url(repo.url())),
+        _ => bail!(
+            "Snapshot ID {} is ambiguous in {} ({} matches)",
+            id,
+            redact_url(repo.url()),
+            matches.len()
+        ),
+    }
+}
+
+impl Index {
+    /// Reads every index file of `repo`.
+    pub async fn load(repo: &Repo, key: &Key) -> Result<Self> {
+        let mut index = Index {
+            packs: HashMap::new(),
+            blobs: HashMap::new(),
+        };
+        for file in list_files(repo, "index").await? {
+            let path = format!("index/{}", file.name);
+            let plain = fetch(repo, key, &path).await?;
+            let index_file: IndexFile = serde_json::from_slice(&plain)
+                .with_context(|| format!("Invalid index file {path}"))?;
+            for pack in index_file.packs {
+                for (i, blob) in pack.blobs.iter().enumerate() {
+                    index.blobs.insert(blob.id.clone(), (pack.id.clone(), i));
+                }
+                index.packs.insert(pack.id.clone(), pack);
+            }
+        }
+        debug!(
+            "Loaded the index of {}: {} blobs in {} packs",
+            redact_url(repo.url()),
+            index.blobs.len(),
+            index.packs.len()
+        );
+        Ok(index)
+    }
+
+    fn locate(&self, blob: &str) -> Result<(&str, &BlobEntry)> {
+        let Some((pack, i)) = self.blobs.get(blob) else {
+            bail!("Blob {blob} is not in the index");
+        };
+        Ok((pack, &self.packs[pack].blobs[*i]))
+    }
+
+    /// The index entries of `packs`, for an index file listing just them.
+    pub fn entries<'a>(&self, packs: impl IntoIterator<Item = &'a String>) -> IndexFile {
+        IndexFile {
+            packs: packs
+                .into_iter()
+                .map(|pack| self.packs[pack].clone())
+                .collect(),
+        }
+    }
+}
+
+/// Reads blob `id` of `repo` from its pack with a range request.
+async fn fetch_blob(repo: &Repo, key: &Key, index: &Index, id: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let (pack, blob) = index.locate(id)?;
+    let path = format!("data/{pack}");
+    let start = blob.offset;
+    let end = blob.offset + blob.length;
+    let resp = repo
+        .get(&path)
+        .header("Range", format!("bytes={}-{}", start, end - 1))
+        .send()
+        .await?;
+    let status = resp.status();
+    if !status.is_success() {
+        bail!(Error::Http {
+            operation: "download",
+            url: redact_url(&repo.join(&path)),
+            status,
+        });
+    }
+    let bytes = resp.bytes().await?;
+    // A server that ignores the range sends the whole pack.
+    let ciphertext = match status {
+        StatusCode::PARTIAL_CONTENT => &bytes[..],
+        _ => bytes
+            .get(start as usize..end as usize)
+            .with_context(|| format!("Blob {id} lies outside of {path}"))?,
+    };
+    let plain = key
+        .decrypt(ciphertext)
+        .with_context(|| format!("Failed to decrypt blob {id} in {path}"))?;
+    if blob.uncompressed_length.is_none() {
+        return Ok(plain);
+    }
+    let mut decoder = ruzstd::decoding::StreamingDecoder::new(&plain[..])
+        .map_err(|e| anyhow::anyhow!("Invalid compressed blob {id}: {e}"))?;
+    let mut out = Zeroizing::new(Vec::new());
+    decoder
+        .read_to_end(&mut out)
+        .with_context(|| format!("Invalid compressed blob {id}"))?;
+    Ok(out)
+}
+
+async fn load(repo: &Repo, key: &Key, name: &str) -> Result<Snapshot> {
+    serde_json::from_slice(&fetch(repo, key, &format!("snapshots/{name}")).await?)
+        .with_context(|| format!("Invalid snapshot {name}"))
+}
+
+/// The packs holding the trees and file contents of snapshot `name`.
+pub async fn packs(repo: &Repo, key: &Key, index: &Index, name: &str) -> Result<BTreeSet<String>> {
+    let snapshot = load(repo, key, name).await?;
+    let mut packs = BTreeSet::new();
+    let trees = walk(repo, key, index, snapshot.tree, &mut packs, &mut BTreeSet::new()).await?;
+    debug!("Snapshot {} has {} trees in {} packs", name, trees, packs.len());
+    Ok(packs)
+}
+
+/// Adds the packs of the tree `root` and the trees below it to `packs`,
+/// skipping the trees in `seen`, and returns how many trees it read.
+async fn walk(
+    repo: &Repo,
+    key: &Key,
+    index: &Index,
+    root: String,
+    packs: &mut BTreeSet<String>,
+    seen: &mut BTreeSet<String>,
+) -> Result<usize> {
+    let mut read = 0;
+    let mut pending = vec![root];
+    while let Some(tree_id) = pending.pop() {
+        if !seen.insert(tree_id.clone()) {
+            continue;
+        }
+        read += 1;
+        packs.insert(index.locate(&tree_id)?.0.to_string());
+        let tree: Tree = serde_json::from_slice(&fetch_blob(repo, key, index, &tree_id).await?)
+            .with_context(|| format!("Invalid tree {tree_id}"))?;
+        for node in tree.nodes {
+            for blob in node.content.into_iter().flatten() {
+                packs.insert(index.locate(&blob)?.0.to_string());
+            }
+            pending.extend(node.subtree);
+        }
+    }
+    Ok(read)
+}
+
+/// Which snapshots of the source to sync, for mirroring part of a
+/// repository that several hosts or tenants share. With none of the
+/// filters, everything is synced.
+#[derive(clap::Args, Debug, Clone)]
+pub struct SnapshotFilter {
+    /// Only sync the snapshots taken on this host, and the packs they need
+    /// (repeatable, for any of the hosts); needs the restic password
+    #[arg(long = "host", value_name = "HOST")]
+    hosts: Vec<String>,
+
+    /// Only sync the snapshots with all of these comma-separated tags, and
+    /// the packs they need (repeatable, for any of the lists); needs the
+    /// restic password
+    #[arg(long = "tag", value_name = "TAG[,TAG...]")]
+    tags: Vec<String>,
+
+    /// Only sync the snapshots of this path, and the packs they need
+    /// (repeatable, for snapshots of all of the paths); needs the restic
+    /// password
+    #[arg(long = "path", value_name = "PATH")]
+    paths: Vec<String>,
+
+    /// Only sync the newest N snapshots of each host, of those that the
+    /// other filters select, and the packs they need; needs the restic
+    /// password
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    keep_latest: Option<u32>,
+
+    #[command(flatten)]
+    key: RepoKeyArgs,
+}
+
+impl SnapshotFilter {
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+            && self.tags.is_empty()
+            && self.paths.is_empty()
+            && self.keep_latest.is_none()
+    }
+
+    /// Matches as `restic snapshots --host --tag --path` does.
+    fn matches(&self, snapshot: &Snapshot) -> bool {
+        let host = self.hosts.is_empty() || self.hosts.contains(&snapshot.hostname);
+        let tags = self.tags.is_empty()
+            || self.tags.iter().any(|list| {
+                list.split(',')
+                    .filter(|tag| !tag.is_empty())
+                    .all(|tag| snapshot.tags.iter().any(|t| t == tag))
+            });
+        let paths = self.paths.iter().all(|path| snapshot.paths.contains(path));
+        host && tags && paths
+    }
+
+    /// The selector for a sync from a source with this filter, or `None`
+    /// if it does not filter.
+    pub fn selector(&self) -> Result<Option<Selector>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Selector {
+            filter: self.clone(),
+            password: self.key.password()?,
+            selection: tokio::sync::OnceCell::new(),
+        }))
+    }
+}
+
+/// Works out the [`Selection`] of a source once a run needs it.
+pub struct Selector {
+    filter: SnapshotFilter,
+    password: Secret,
+    selection: tokio::sync::OnceCell<Selection>,
+}
+
+impl Selector {
+    /// The selection from `repo`, read from it the first time.
+    pub async fn selection(&self, repo: &Repo) -> Result<&Selection> {
+        self.selection
+            .get_or_try_init(|| Selection::load(repo, &self.filter, &self.password))
+            .await
+    }
+}
+
+/// The newest `keep` of the `snapshots` of each host.
+fn latest(snapshots: Vec<(String, Snapshot)>, keep: usize) -> Result<Vec<(String, Snapshot)>> {
+    let mut by_host: BTreeMap<String, Vec<_>> = BTreeMap::new();
+    for (name, snapshot) in snapshots {
+        let time = DateTime::parse_from_rfc3339(&snapshot.time)
+            .with_context(|| format!("Invalid time `{}` in snapshot {name}", snapshot.time))?;
+        by_host
+            .entry(snapshot.hostname.clone())
+            .or_default()
+            .push((time, name, snapshot));
+    }
+    let mut kept = Vec::new();
+    for mut snapshots in by_host.into_values() {
+        snapshots.sort_by_key(|(time, _, _)| Reverse(*time));
+        kept.extend(
+            snapshots
+                .into_iter()
+                .take(keep)
+                .map(|(_, name, snapshot)| (name, snapshot)),
+        );
+    }
+    Ok(kept)
+}
+
+/// The files of a source that a snapshot filter leaves to sync.
+pub struct Selection {
+    snapshots: HashSet<String>,
+    packs: BTreeSet<String>,
+    /// The index file of just `packs`, and its name.
+    index_name: String,
+    index_file: Bytes,
+}
+
+impl Selection {
+    async fn load(repo: &Repo, filter: &SnapshotFilter, password: &str) -> Result<Self> {
+        let key = crypto::open(repo, password).await?;
+        let mut selected = Vec::new();
+        let names = list_files(repo, "snapshots").await?;
+        for file in &names {
+            let snapshot = load(repo, &key, &file.name).await?;
+            if filter.matches(&snapshot) {
+                selected.push((file.name.clone(), snapshot));
+            }
+        }
+        if let Some(keep) = filter.keep_latest {
+            selected = latest(selected, keep as usize)?;
+        }
+        if selected.is_empty() {
+            // A typo in a filter must not prune the whole destination.
+            bail!(
+                "None of the {} snapshots in {} match --host, --tag, --path and --keep-latest",
+                names.len(),
+                redact_url(repo.url())
+            );
+        }
+
+        let index = Index::load(repo, &key).await?;
+        let mut packs = BTreeSet::new();
+        // Snapshots of the same host share most of their trees.
+        let mut seen = BTreeSet::new();
+        let mut snapshots = HashSet::new();
+        for (name, snapshot) in selected {
+            walk(repo, &key, &index, snapshot.tree, &mut packs, &mut seen).await?;
+            snapshots.insert(name);
+        }
+        let entries = serde_json::to_vec(&index.entries(&packs))?;
+        // The same packs make the same file, which is then not written
+        // again on the next run.
+        let index_file = key.encrypt_deterministic(&entries);
+        info!(
+            "Selected {} of {} snapshots, with {} of {} packs",
+            snapshots.len(),
+            names.len(),
+            packs.len(),
+            index.packs.len()
+        );
+        Ok(Selection {
+            snapshots,
+            packs,
+            index_name: sha256_hex(&index_file),
+            index_file: index_file.into(),
+        })
+    }
+
+    /// Whether the selection keeps `name` of `file_type`; `index` files
+    /// are replaced by [`Selection::index`].
+    pub fn includes(&self, file_type: &str, name: &str) -> bool {
+        match file_type {
+            "snapshots" => self.snapshots.contains(name),
+            "data" => self.packs.contains(name),
+            "index" => name == self.index_name,
+            _ => true,
+        }
+    }
+
+    /// The index file of the selected packs, as the listing of the source
+    /// would have it.
+    pub fn index(&self) -> FileInfo {
+        FileInfo {
+            name: self.index_name.clone(),
+            size: self.index_file.len() as u64,
+        }
+    }
+
+    /// The contents of the index file `name`, if it is the selection's.
+    pub fn index_file(&self, name: &str) -> Option<Bytes> {
+        (name == self.index_name).then(|| self.index_file.clone())
+    }
+}
@@ -0,0 +1,826 @@
+//! What syncs keep between runs, in a SQLite database in `--state-dir`.
+//!
+//! Each source and destination pair has a directory of its own there, which
+//! a sync locks while it runs, with a database that [`MIGRATIONS`] bring up
+//! to date with the layout this version expects.
+//!
+//! With `--cache-dest-listing`, the listing of each destination type is
+//! stored there the first time it is read, and kept up to date with the
+//! uploads and deletions of later runs, which then only list the source.
+//! rest-server answers a listing with every file of the type, so for
+//! repositories with millions of mostly unchanged packs this halves what a
+//! run has to fetch before it can copy anything. The files are stored as a
+//! [`KnownFiles`] set, 40 bytes a pack, and compared with the source listing
+//! in that form. With `--reconcile-every` or `--reconcile-after`, a listing that
+//! has been used long enough is checked against a fresh one, which replaces
+//! it, and the differences are reported.
+//!
+//! Every sync with a state directory also keeps a journal of its plan, type
+//! by type as it is worked out, and of the files it has copied and deleted.
+//! A run that succeeds clears it; after one that was interrupted,
+//! `--resume` continues with the files the journal has left instead of
+//! listing the repositories again. With `--fast-path`, it remembers what
+//! the source snapshots and index looked like when the last successful run
+//! started, and a run that finds them unchanged does not list the data.
+//!
+//! Each destination also has a watermark of its own: the source snapshots
+//! there were when its last successful run started, all of which it has. A
+//! run that fails leaves it where it was, so when one source is mirrored to
+//! several destinations, one that is offline falls behind without holding
+//! up the others, and `restic-sync history --watermarks` shows by how much.
+//!
+//! Every run with a state directory, whatever its outcome, is also added to
+//! the history that `restic-sync history` shows.
+
+use crate::{
+    Cancelled, FileAction, FileEvent, FileInfo, SyncObserver, SyncPlan, SyncReport, TypePlan,
+    format_bytes, sha256_hex,
+};
+use anyhow::{Context, Result, bail};
+use log::{info, warn};
+use restic_sync_core::KnownFiles;
+use rusqlite::{Connection, OptionalExtension, Row, Transaction, params};
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::{File, TryLockError};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many uploads and deletions are recorded for a cached listing before
+/// they are merged into it.
+const MAX_CHANGES: usize = 100_000;
+
+/// The changes to the layout of the state database, each taking it from the
+/// version before, as kept in `PRAGMA user_version`, to the next.
+const MIGRATIONS: &[&str] = &[
+    // 1: listing cache, journal, fast path fingerprints and run history.
+    "CREATE TABLE known_files (
+         repo TEXT NOT NULL,
+         file_type TEXT NOT NULL,
+         listed_at INTEGER NOT NULL,
+         uses INTEGER NOT NULL DEFAULT 0,
+         files BLOB NOT NULL,
+         PRIMARY KEY (repo, file_type)
+     );
+     CREATE TABLE known_file_changes (
+         repo TEXT NOT NULL,
+         file_type TEXT NOT NULL,
+         name TEXT NOT NULL,
+         size INTEGER,
+         PRIMARY KEY (repo, file_type, name)
+     ) WITHOUT ROWID;
+     CREATE TABLE journal_types (
+         pair TEXT NOT NULL,
+         position INTEGER NOT NULL,
+         type_plan TEXT NOT NULL,
+         PRIMARY KEY (pair, position)
+     );
+     CREATE TABLE fingerprints (
+         pair TEXT NOT NULL,
+         file_type TEXT NOT NULL,
+         fingerprint TEXT NOT NULL,
+         PRIMARY KEY (pair, file_type)
+     );
+     CREATE TABLE journal_files (
+         pair TEXT NOT NULL,
+         file_type TEXT NOT NULL,
+         action TEXT NOT NULL,
+         name TEXT NOT NULL,
+         PRIMARY KEY (pair, file_type, action, name)
+     ) WITHOUT ROWID;
+     CREATE TABLE runs (
+         run_id TEXT PRIMARY KEY,
+         job TEXT,
+         source TEXT NOT NULL,
+         dest TEXT NOT NULL,
+         started_at INTEGER NOT NULL,
+         ok INTEGER NOT NULL,
+         error TEXT,
+         uploaded_files INTEGER NOT NULL,
+         uploaded_bytes INTEGER NOT NULL,
+         deleted_files INTEGER NOT NULL,
+         report TEXT
+     );",
+    // 2: watermarks.
+    "CREATE TABLE watermarks (
+         pair TEXT PRIMARY KEY,
+         source TEXT NOT NULL,
+         dest TEXT NOT NULL,
+         run_id TEXT,
+         synced_at INTEGER,
+         snapshots BLOB
+     );",
+    // 3: how each run ended, which a report no longer tells once failed
+    // runs keep one too.
+    "ALTER TABLE runs ADD COLUMN outcome TEXT NOT NULL DEFAULT 'failed';
+     UPDATE runs SET outcome = CASE
+         WHEN ok THEN 'ok'
+         WHEN report IS NOT NULL THEN 'cancelled'
+         ELSE 'failed'
+     END;",
+];
+
+/// The state of one repository pair, in a directory of its own in the
+/// state directory, which others read and which only one process uses at a
+/// time.
+pub struct State {
+    db: Mutex<Connection>,
+    /// The lock on the pair, held as long as its state is open to sync.
+    _lock: Option<File>,
+}
+
+impl State {
+    /// Opens the state of the pair from `source` to `dest` in `dir`,
+    /// creating it if needed, and locks it against other processes.
+    pub fn open(dir: &Path, source: &str, dest: &str) -> Result<Self> {
+        let pair = format!("{source} -> {dest}");
+        let namespace = dir.join(&sha256_hex(pair.as_bytes())[..16]);
+        std::fs::create_dir_all(&namespace)
+            .with_context(|| format!("Failed to create state directory {}", namespace.display()))?;
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(namespace.join("lock"))
+            .with_context(|| format!("Failed to open the lock file in {}", namespace.display()))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                bail!("Another restic-sync is syncing {} with the state in {}", pair, namespace.display())
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {}", namespace.display()));
+            }
+        }
+        // Says which pair the directory belongs to, for people looking.
+        std::fs::write(namespace.join("pair"), format!("{pair}\n"))?;
+        Self::open_db(&namespace.join("state.db"), Some(lock))
+    }
+
+    /// Opens the state of every pair in `dir` to read it, without locking.
+    pub fn open_all(dir: &Path) -> Result<Vec<Self>> {
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read state directory {}", dir.display()))?;
+        let mut states = Vec::new();
+        for entry in entries {
+            let path = entry?.path().join("state.db");
+            if path.is_file() {
+                states.push(Self::open_db(&path, None)?);
+            }
+        }
+        Ok(states)
+    }
+
+    fn open_db(path: &Path, lock: Option<File>) -> Result<Self> {
+        let mut db = Connection::open(path)
+            .with_context(|| format!("Failed to open state database {}", path.display()))?;
+        // Readers and the sync take turns.
+        db.busy_timeout(Duration::from_secs(30))?;
+        migrate(&mut db).with_context(|| format!("Failed to set up state database {}", path.display()))?;
+        Ok(State {
+            db: Mutex::new(db),
+            _lock: lock,
+        })
+    }
+}
+
+/// Brings the layout of `db` up to date.
+fn migrate(db: &mut Connection) -> Result<()> {
+    let tx = db.transaction()?;
+    let version = tx.query_row("PRAGMA user_version", [], |row| row.get::<_, u32>(0))? as usize;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "It has version {} of the layout, which this restic-sync only knows up to {}; it was written by a newer one",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as u32 + 1)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// When a cached listing is checked against a fresh one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Reconcile {
+    /// After this many runs used it.
+    pub every: Option<u32>,
+    /// Once it is older than this.
+    pub after: Option<Duration>,
+    /// In this run.
+    pub now: bool,
+}
+
+/// The listings of one repository in the state database.
+#[derive(Clone)]
+pub struct ListingCache {
+    state: Arc<State>,
+    /// The repository URL, without credentials.
+    repo: String,
+    reconcile: Reconcile,
+    /// How the cached listings checked in this run differed from the
+    /// repository.
+    discrepancies: Arc<Mutex<Vec<String>>>,
+}
+
+impl std::fmt::Debug for ListingCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ListingCache")
+            .field("repo", &self.repo)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ListingCache {
+    pub fn new(state: Arc<State>, repo: &str) -> Self {
+        ListingCache {
+            state,
+            repo: repo.to_string(),
+            reconcile: Reconcile::default(),
+            discrepancies: Arc::default(),
+        }
+    }
+
+    pub fn with_reconcile(mut self, reconcile: Reconcile) -> Self {
+        self.reconcile = reconcile;
+        self
+    }
+
+    /// The stored listing of `file_type`, if it was ever stored.
+    pub fn listing(&self, file_type: &str) -> Result<Option<Vec<FileInfo>>> {
+        Ok(self.known(file_type)?.map(|known| known.iter().collect()))
+    }
+
+    /// The files of `file_type` as stored, with the changes recorded since.
+    pub fn known(&self, file_type: &str) -> Result<Option<KnownFiles>> {
+        let mut db = self.state.db.lock().unwrap();
+        let tx = db.transaction()?;
+        let files: Option<Vec<u8>> = tx
+            .query_row(
+                "SELECT files FROM known_files WHERE repo = ?1 AND file_type = ?2",
+                params![self.repo, file_type],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(files) = files else {
+            return Ok(None);
+        };
+        let Some(mut known) = KnownFiles::from_bytes(&files) else {
+            warn!("The cached listing of {} is damaged, listing the repository again", file_type);
+            return Ok(None);
+        };
+        let changes = {
+            let mut query = tx.prepare_cached(
+                "SELECT name, size FROM known_file_changes WHERE repo = ?1 AND file_type = ?2",
+            )?;
+            query
+                .query_map(params![self.repo, file_type], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        for (name, size) in &changes {
+            match size {
+                // SQLite integers are signed.
+                Some(size) => known.insert(name, *size as u64),
+                None => known.remove(name),
+            }
+        }
+        // Rewriting a set of millions of files for a few changes is not
+        // worth it.
+        if changes.len() > MAX_CHANGES {
+            write_known(&tx, &self.repo, file_type, &known)?;
+            tx.commit()?;
+        }
+        Ok(Some(known))
+    }
+
+    /// Why the cached listing of `file_type` is to be checked against a
+    /// fresh one in this run, if it is. Otherwise it counts as used once
+    /// more.
+    pub fn reconciliation_due(&self, file_type: &str) -> Result<Option<String>> {
+        if self.reconcile.now {
+            return Ok(Some("as requested".to_string()));
+        }
+        let db = self.state.db.lock().unwrap();
+        let (listed_at, uses): (i64, i64) = db.query_row(
+            "SELECT listed_at, uses FROM known_files WHERE repo = ?1 AND file_type = ?2",
+            params![self.repo, file_type],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        let age = Duration::from_secs(unix_time().saturating_sub(listed_at).max(0) as u64);
+        if let Some(after) = self.reconcile.after.filter(|&after| age >= after) {
+            return Ok(Some(format!("since it is older than {}", humantime::format_duration(after))));
+        }
+        if let Some(every) = self.reconcile.every.filter(|&every| uses + 1 >= i64::from(every)) {
+            return Ok(Some(format!("after {} runs", every)));
+        }
+        db.execute(
+            "UPDATE known_files SET uses = uses + 1 WHERE repo = ?1 AND file_type = ?2",
+            params![self.repo, file_type],
+        )?;
+        Ok(None)
+    }
+
+    /// Replaces the cached listing of `file_type`, `known`, with the fresh
+    /// `listed`, noting where the two differ.
+    pub fn reconcile(&self, file_type: &str, known: &KnownFiles, listed: &[FileInfo]) -> Result<()> {
+        let drift = restic_sync_core::compare_known(listed.to_vec(), known);
+        self.store(file_type, listed)?;
+        if drift.missing.is_empty() && drift.extra.is_empty() && drift.mismatched.is_empty() {
+            info!("[{}] The cached listing matched the repository", file_type);
+            return Ok(());
+        }
+        let bytes = |files: &[FileInfo]| format_bytes(files.iter().map(|file| file.size).sum());
+        let discrepancy = format!(
+            "[{}] The cached listing differed from the repository: it lacked {} files ({}), had {} files ({}) that were gone, and {} with another size",
+            file_type,
+            drift.missing.len(),
+            bytes(&drift.missing),
+            drift.extra.len(),
+            bytes(&drift.extra),
+            drift.mismatched.len()
+        );
+        warn!("{}", discrepancy);
+        self.discrepancies.lock().unwrap().push(discrepancy);
+        Ok(())
+    }
+
+    /// How the listings checked so far differed from the repository.
+    pub fn take_discrepancies(&self) -> Vec<String> {
+        std::mem::take(&mut self.discrepancies.lock().unwrap())
+    }
+
+    /// Replaces the stored listing of `file_type` with `files`.
+    pub fn store(&self, file_type: &str, files: &[FileInfo]) -> Result<()> {
+        let known: KnownFiles = files.iter().cloned().collect();
+        let mut db = self.state.db.lock().unwrap();
+        let tx = db.transaction()?;
+        tx.execute(
+            "DELETE FROM known_files WHERE repo = ?1 AND file_type = ?2",
+            params![self.repo, file_type],
+        )?;
+        write_known(&tx, &self.repo, file_type, &known)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Adds a file that was just uploaded to the stored listing.
+    pub fn record_upload(&self, file_type: &str, name: &str, size: u64) -> Result<()> {
+        self.state.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO known_file_changes (repo, file_type, name, size) VALUES (?1, ?2, ?3, ?4)",
+            params![self.repo, file_type, name, size as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Removes a file that was just deleted from the stored listing.
+    pub fn record_delete(&self, file_type: &str, name: &str) -> Result<()> {
+        self.state.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO known_file_changes (repo, file_type, name, size) VALUES (?1, ?2, ?3, NULL)",
+            params![self.repo, file_type, name],
+        )?;
+        Ok(())
+    }
+
+    /// Drops the stored listing of `file_type`, so that the next run lists
+    /// the repository again.
+    pub fn forget(&self, file_type: &str) -> Result<()> {
+        let db = self.state.db.lock().unwrap();
+        db.execute(
+            "DELETE FROM known_files WHERE repo = ?1 AND file_type = ?2",
+            params![self.repo, file_type],
+        )?;
+        db.execute(
+            "DELETE FROM known_file_changes WHERE repo = ?1 AND file_type = ?2",
+            params![self.repo, file_type],
+        )?;
+        Ok(())
+    }
+}
+
+/// Stores `known` as the files of `file_type`, including the changes
+/// recorded so far, as listed now unless it was listed before.
+fn write_known(tx: &Transaction, repo: &str, file_type: &str, known: &KnownFiles) -> Result<()> {
+    tx.execute(
+        "INSERT INTO known_files (repo, file_type, listed_at, files) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT (repo, file_type) DO UPDATE SET files = excluded.files",
+        params![repo, file_type, unix_time(), known.to_bytes()],
+    )?;
+    tx.execute(
+        "DELETE FROM known_file_changes WHERE repo = ?1 AND file_type = ?2",
+        params![repo, file_type],
+    )?;
+    Ok(())
+}
+
+/// The journal of the runs from one source to one destination.
+pub struct Journal {
+    state: Arc<State>,
+    /// The source and destination URLs, without credentials.
+    source: String,
+    dest: String,
+    pair: String,
+}
+
+impl Journal {
+    pub fn new(state: Arc<State>, source: &str, dest: &str) -> Self {
+        Journal {
+            state,
+            source: source.to_string(),
+            dest: dest.to_string(),
+            pair: format!("{source} -> {dest}"),
+        }
+    }
+
+    /// What the last run had left to do, if it did not finish.
+    pub fn interrupted(&self) -> Result<Option<SyncPlan>> {
+        let db = self.state.db.lock().unwrap();
+        let mut query = db.prepare_cached(
+            "SELECT type_plan FROM journal_types WHERE pair = ?1 ORDER BY position",
+        )?;
+        let types = query
+            .query_map(params![self.pair], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if types.is_empty() {
+            return Ok(None);
+        }
+        let mut done = db.prepare_cached(
+            "SELECT 1 FROM journal_files WHERE pair = ?1 AND file_type = ?2 AND action = ?3 AND name = ?4",
+        )?;
+        let mut plan = SyncPlan {
+            config: true,
+            types: Vec::new(),
+        };
+        for type_plan in types {
+            let mut type_plan: TypePlan = serde_json::from_str(&type_plan)
+                .context("Invalid plan in the journal of the state database")?;
+            let mut is_done = |action: &str, name: &str| {
+                done.exists(params![self.pair, type_plan.file_type, action, name])
+            };
+            let mut uploads = Vec::new();
+            for file in type_plan.uploads {
+                if !is_done("upload", &file.name)? {
+                    uploads.push(file);
+                }
+            }
+            let mut deletes = Vec::new();
+            for file in type_plan.deletes {
+                if !is_done("delete", &file.name)? {
+                    deletes.push(file);
+                }
+            }
+            type_plan.uploads = uploads;
+            type_plan.deletes = deletes;
+            plan.types.push(type_plan);
+        }
+        Ok(Some(plan))
+    }
+
+    /// Clears the journal for a new run.
+    pub fn start(&self) -> Result<()> {
+        let db = self.state.db.lock().unwrap();
+        db.execute("DELETE FROM journal_types WHERE pair = ?1", params![self.pair])?;
+        db.execute("DELETE FROM journal_files WHERE pair = ?1", params![self.pair])?;
+        Ok(())
+    }
+
+    /// Adds the plan of the next type.
+    pub fn add_type(&self, type_plan: &TypePlan) -> Result<()> {
+        self.state.db.lock().unwrap().execute(
+            "INSERT INTO journal_types (pair, position, type_plan)
+             SELECT ?1, COUNT(*), ?2 FROM journal_types WHERE pair = ?1",
+            params![self.pair, serde_json::to_string(type_plan)?],
+        )?;
+        Ok(())
+    }
+
+    /// Clears the journal of a run that finished.
+    pub fn finish(&self) -> Result<()> {
+        self.start()
+    }
+
+    /// The fingerprints of the source listings at the start of the last
+    /// successful `--fast-path` run, by file type.
+    pub fn fingerprints(&self) -> Result<Vec<(String, String)>> {
+        let db = self.state.db.lock().unwrap();
+        let mut query = db.prepare_cached(
+            "SELECT file_type, fingerprint FROM fingerprints WHERE pair = ?1 ORDER BY file_type",
+        )?;
+        let fingerprints = query
+            .query_map(params![self.pair], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(fingerprints)
+    }
+
+    pub fn set_fingerprints(&self, fingerprints: &[(String, String)]) -> Result<()> {
+        let mut db = self.state.db.lock().unwrap();
+        let tx = db.transaction()?;
+        tx.execute("DELETE FROM fingerprints WHERE pair = ?1", params![self.pair])?;
+        for (file_type, fingerprint) in fingerprints {
+            tx.execute(
+                "INSERT INTO fingerprints (pair, file_type, fingerprint) VALUES (?1, ?2, ?3)",
+                params![self.pair, file_type, fingerprint],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// The watermark of the destination, which is tracked from now on if
+    /// it was not yet.
+    pub fn watermark(&self) -> Result<Watermark> {
+        let db = self.state.db.lock().unwrap();
+        db.execute(
+            "INSERT OR IGNORE INTO watermarks (pair, source, dest) VALUES (?1, ?2, ?3)",
+            params![self.pair, self.source, self.dest],
+        )?;
+        let watermark = db.query_row(
+            "SELECT * FROM watermarks WHERE pair = ?1",
+            params![self.pair],
+            Watermark::from_row,
+        )?;
+        Ok(watermark)
+    }
+
+    /// Moves the watermark up to `snapshots`, which run `run_id` found in
+    /// the source when it started at `synced_at` and has all copied.
+    pub fn set_watermark(&self, run_id: &str, synced_at: SystemTime, snapshots: &KnownFiles) -> Result<()> {
+        self.state.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO watermarks (pair, source, dest, run_id, synced_at, snapshots)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                self.pair,
+                self.source,
+                self.dest,
+                run_id,
+                unix_secs(synced_at),
+                snapshots.to_bytes()
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// How far a destination has caught up with its source.
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    /// The repository URLs, without credentials.
+    pub source: String,
+    pub dest: String,
+    /// The last successful run, unless there was none yet.
+    pub run_id: Option<String>,
+    /// When that run started, in unix seconds.
+    pub synced_at: Option<i64>,
+    /// The source snapshots there were then.
+    pub snapshots: KnownFiles,
+}
+
+impl Watermark {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let snapshots: Option<Vec<u8>> = row.get("snapshots")?;
+        Ok(Watermark {
+            source: row.get("source")?,
+            dest: row.get("dest")?,
+            run_id: row.get("run_id")?,
+            synced_at: row.get("synced_at")?,
+            // A damaged set only makes the destination look further behind.
+            snapshots: snapshots
+                .and_then(|bytes| KnownFiles::from_bytes(&bytes))
+                .unwrap_or_default(),
+        })
+    }
+}
+
+impl SyncObserver for Journal {
+    fn on_file_complete(&self, file: &FileEvent, _bytes: u64) {
+        let action = match file.action {
+            FileAction::Upload => "upload",
+            FileAction::Delete => "delete",
+        };
+        let recorded = self.state.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO journal_files (pair, file_type, action, name) VALUES (?1, ?2, ?3, ?4)",
+            params![self.pair, file.file_type, action, file.name],
+        );
+        // Without it, a resumed run copies the file again.
+        if let Err(e) = recorded {
+            warn!("Failed to record {}/{} in the journal: {:#}", file.file_type, file.name, e);
+        }
+    }
+}
+
+/// A run in the history of the state database.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunRecord {
+    pub run_id: String,
+    /// The name of the job in the config file.
+    pub job: Option<String>,
+    /// The repository URLs, without credentials.
+    pub source: String,
+    pub dest: String,
+    /// When the run started, in unix seconds.
+    pub started_at: i64,
+    pub ok: bool,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    /// The report of the run, as `restic-sync schema report` describes it,
+    /// or what a cancelled or partly failed run did until it stopped.
+    pub report: Option<Value>,
+}
+
+/// How a run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Ok,
+    Failed,
+    Cancelled,
+}
+
+impl Outcome {
+    fn of(result: &Result<SyncReport>) -> Self {
+        match result {
+            Ok(_) => Outcome::Ok,
+            Err(e) if e.downcast_ref::<Cancelled>().is_some() => Outcome::Cancelled,
+            Err(_) => Outcome::Failed,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Failed => "failed",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
+
+    fn parse(outcome: &str) -> Self {
+        match outcome {
+            "ok" => Outcome::Ok,
+            "cancelled" => Outcome::Cancelled,
+            _ => Outcome::Failed,
+        }
+    }
+}
+
+impl RunRecord {
+    /// The record of a run of `job` that started at `started_at`.
+    pub fn new(
+        run_id: &str,
+        job: Option<&str>,
+        (source, dest): (&str, &str),
+        started_at: SystemTime,
+        result: &Result<SyncReport>,
+    ) -> Self {
+        let report = match result {
+            Ok(report) => Some(report),
+            Err(e) => crate::partial_report(e),
+        };
+        RunRecord {
+            run_id: run_id.to_string(),
+            job: job.map(str::to_string),
+            source: source.to_string(),
+            dest: dest.to_string(),
+            started_at: unix_secs(started_at),
+            ok: result.is_ok(),
+            outcome: Outcome::of(result),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            report: report.and_then(|report| serde_json::to_value(report).ok()),
+        }
+    }
+
+    fn count(&self, field: &str) -> u64 {
+        self.report
+            .as_ref()
+            .and_then(|report| report[field].as_u64())
+            .unwrap_or_default()
+    }
+
+    pub fn uploaded_files(&self) -> u64 {
+        self.count("uploaded_files")
+    }
+
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.count("uploaded_bytes")
+    }
+
+    pub fn deleted_files(&self) -> u64 {
+        self.count("deleted_files")
+    }
+
+    /// How long the run took, if it got as far as a report.
+    pub fn duration_secs(&self) -> Option<f64> {
+        self.report.as_ref()?["duration_secs"].as_f64()
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let report: Option<String> = row.get("report")?;
+        Ok(RunRecord {
+            run_id: row.get("run_id")?,
+            job: row.get("job")?,
+            source: row.get("source")?,
+            dest: row.get("dest")?,
+            started_at: row.get("started_at")?,
+            ok: row.get("ok")?,
+            outcome: Outcome::parse(&row.get::<_, String>("outcome")?),
+            error: row.get("error")?,
+            report: report.and_then(|report| serde_json::from_str(&report).ok()),
+        })
+    }
+}
+
+/// Which runs [`History::runs`] returns.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryQuery {
+    pub job: Option<String>,
+    /// Only runs that uploaded or deleted files.
+    pub changed: bool,
+    pub limit: usize,
+}
+
+/// The runs recorded in the state database.
+pub struct History {
+    state: Arc<State>,
+}
+
+impl History {
+    pub fn new(state: Arc<State>) -> Self {
+        History { state }
+    }
+
+    pub fn record(&self, run: &RunRecord) -> Result<()> {
+        self.state.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO runs (run_id, job, source, dest, started_at, ok, error,
+                 uploaded_files, uploaded_bytes, deleted_files, report, outcome)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                run.run_id,
+                run.job,
+                run.source,
+                run.dest,
+                run.started_at,
+                run.ok,
+                run.error,
+                run.uploaded_files() as i64,
+                run.uploaded_bytes() as i64,
+                run.deleted_files() as i64,
+                run.report.as_ref().map(Value::to_string),
+                run.outcome.as_str(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The latest runs that match `query`, newest first.
+    pub fn runs(&self, query: &HistoryQuery) -> Result<Vec<RunRecord>> {
+        let db = self.state.db.lock().unwrap();
+        let mut select = db.prepare_cached(
+            "SELECT * FROM runs
+             WHERE (?1 IS NULL OR job = ?1) AND (NOT ?2 OR uploaded_files + deleted_files > 0)
+             ORDER BY started_at DESC, rowid DESC LIMIT ?3",
+        )?;
+        let runs = select
+            .query_map(
+                params![query.job, query.changed, query.limit as i64],
+                RunRecord::from_row,
+            )?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(runs)
+    }
+
+    /// The watermarks of the destinations, of which each state has one.
+    pub fn watermarks(&self) -> Result<Vec<Watermark>> {
+        let db = self.state.db.lock().unwrap();
+        let mut select = db.prepare_cached("SELECT * FROM watermarks ORDER BY source, dest")?;
+        let watermarks = select
+            .query_map([], Watermark::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(watermarks)
+    }
+
+    /// The run with the ID `run_id`, as it appears in the log.
+    pub fn run(&self, run_id: &str) -> Result<Option<RunRecord>> {
+        let db = self.state.db.lock().unwrap();
+        let run = db
+            .query_row(
+                "SELECT * FROM runs WHERE run_id = ?1",
+                params![run_id],
+                RunRecord::from_row,
+            )
+            .optional()?;
+        Ok(run)
+    }
+}
+
+fn unix_time() -> i64 {
+    unix_secs(SystemTime::now())
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64)
+}
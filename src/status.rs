@@ -0,0 +1,180 @@
+//! Status file for external liveness checks.
+//!
+//! A scheduled sync periodically records a scheduler heartbeat and the
+//! outcome of each run in a small JSON file. `restic-sync healthcheck` reads
+//! that file, which makes it usable as a Docker `HEALTHCHECK` that notices a
+//! wedged scheduler or a mirror that has stopped making progress rather than
+//! only checking that the process exists.
+
+use crate::SyncReport;
+use anyhow::{Context, Result, bail};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often the scheduler heartbeat is refreshed.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Status {
+    pid: u32,
+    /// When the scheduler was started (unix seconds).
+    started_at: u64,
+    /// Last time the scheduler loop was seen alive (unix seconds).
+    heartbeat_at: u64,
+    /// Whether a sync is currently in progress.
+    running: bool,
+    last_run: Option<LastRun>,
+    /// Finish time of the last successful run (unix seconds).
+    last_success_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LastRun {
+    finished_at: u64,
+    success: bool,
+    summary: String,
+}
+
+/// Handle to the status file of a running process.
+#[derive(Debug, Clone)]
+pub struct StatusFile {
+    path: PathBuf,
+    status: Arc<Mutex<Status>>,
+}
+
+impl StatusFile {
+    pub fn new(path: &Path) -> Self {
+        let now = unix_now();
+        StatusFile {
+            path: path.to_path_buf(),
+            status: Arc::new(Mutex::new(Status {
+                pid: std::process::id(),
+                started_at: now,
+                heartbeat_at: now,
+                ..Status::default()
+            })),
+        }
+    }
+
+    pub fn heartbeat(&self) {
+        self.update(|status| status.heartbeat_at = unix_now());
+    }
+
+    pub fn run_started(&self) {
+        self.update(|status| status.running = true);
+    }
+
+    pub fn run_finished(&self, result: &Result<SyncReport>) {
+        self.update(|status| {
+            let now = unix_now();
+            status.running = false;
+            status.last_run = Some(LastRun {
+                finished_at: now,
+                success: result.is_ok(),
+                summary: match result {
+                    Ok(report) => report.to_string(),
+                    Err(e) => format!("Sync failed: {:#}", e),
+                },
+            });
+            if result.is_ok() {
+                status.last_success_at = Some(now);
+            }
+        });
+    }
+
+    /// Spawns a task that keeps the heartbeat fresh for as long as the
+    /// runtime is alive.
+    pub fn spawn_heartbeat(&self) {
+        let status = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                status.heartbeat();
+            }
+        });
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Status)) {
+        let snapshot = {
+            let mut status = self.status.lock().unwrap();
+            f(&mut status);
+            status.clone()
+        };
+        if let Err(e) = write_atomic(&self.path, &snapshot) {
+            warn!(
+                "Failed to write status file {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn write_atomic(path: &Path, status: &Status) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(status)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Checks the status file, returning a description of the healthy state or
+/// an error explaining why the process is considered unhealthy.
+///
+/// `max_age` bounds how long ago the last successful run may have finished;
+/// until the first run succeeds it is measured from scheduler start-up.
+pub fn check(
+    path: &Path,
+    max_heartbeat_age: Duration,
+    max_age: Option<Duration>,
+) -> Result<String> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("Failed to read status file {}", path.display()))?;
+    let status: Status = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse status file {}", path.display()))?;
+    let now = unix_now();
+
+    let heartbeat_age = now.saturating_sub(status.heartbeat_at);
+    if heartbeat_age > max_heartbeat_age.as_secs() {
+        bail!(
+            "Scheduler heartbeat is stale: last seen {}s ago (pid {})",
+            heartbeat_age,
+            status.pid
+        );
+    }
+
+    if let Some(max_age) = max_age {
+        let reference = status.last_success_at.unwrap_or(status.started_at);
+        let age = now.saturating_sub(reference);
+        if age > max_age.as_secs() && !status.running {
+            let last = status
+                .last_run
+                .as_ref()
+                .map(|run| run.summary.as_str())
+                .unwrap_or("no run has finished yet");
+            bail!(
+                "No successful sync in the last {}s (limit {}): {}",
+                age,
+                humantime::format_duration(max_age),
+                last
+            );
+        }
+    }
+
+    Ok(match &status.last_run {
+        Some(run) if run.success => format!("Healthy. Last run: {}", run.summary),
+        Some(run) => format!("Healthy, but the last run failed: {}", run.summary),
+        None if status.running => "Healthy. First sync in progress.".to_string(),
+        None => "Healthy. Waiting for the first scheduled sync.".to_string(),
+    })
+}
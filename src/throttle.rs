@@ -0,0 +1,153 @@
+//! Cooperative handling of rate limiting.
+//!
+//! A `429 Too Many Requests` or `503 Service Unavailable` from an endpoint is
+//! taken as a request to slow down: every request to that endpoint waits
+//! until the server's `Retry-After` (or an increasing default delay) has
+//! passed, and the throttled request is then sent again. The transient-error
+//! retry middleware leaves these responses alone, so a throttling server is
+//! not hammered by independent exponential backoffs.
+
+use async_trait::async_trait;
+use http::Extensions;
+use log::warn;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Request, Response, StatusCode};
+use reqwest_middleware::{Middleware, Next};
+use reqwest_retry::{
+    Retryable, RetryableStrategy, default_on_request_failure, default_on_request_success,
+};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Throttled responses tolerated for a single request before giving up.
+const MAX_THROTTLED_ATTEMPTS: u32 = 10;
+/// Delay before the first retry when the server gives no `Retry-After`.
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound for a single pause, whatever the server asks for.
+const MAX_DELAY: Duration = Duration::from_secs(300);
+
+/// Pauses all requests to one endpoint while it is throttling us.
+#[derive(Default)]
+pub struct Throttle {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    paused_until: Option<Instant>,
+    /// Throttled responses since the last normal one.
+    consecutive: u32,
+}
+
+impl Throttle {
+    async fn wait(&self) {
+        loop {
+            let paused_until = self.state.lock().unwrap().paused_until;
+            match paused_until {
+                Some(until) if until > Instant::now() => {
+                    tokio::time::sleep_until(until.into()).await;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Records a throttled response and returns how long requests pause.
+    fn throttled(&self, retry_after: Option<Duration>) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let backoff = INITIAL_DELAY.saturating_mul(1 << state.consecutive.min(16));
+        let delay = retry_after.unwrap_or(backoff).min(MAX_DELAY);
+        let until = Instant::now() + delay;
+        state.paused_until = Some(
+            state
+                .paused_until
+                .map_or(until, |current| current.max(until)),
+        );
+        state.consecutive += 1;
+        delay
+    }
+
+    fn recovered(&self) {
+        self.state.lock().unwrap().consecutive = 0;
+    }
+}
+
+#[async_trait]
+impl Middleware for Throttle {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let mut attempts = 0;
+        loop {
+            self.wait().await;
+            // Streaming bodies cannot be replayed; send those only once.
+            let Some(attempt) = req.try_clone() else {
+                return next.run(req, extensions).await;
+            };
+            let res = next.clone().run(attempt, extensions).await;
+            match &res {
+                Ok(resp) if is_throttled(resp.status()) && attempts < MAX_THROTTLED_ATTEMPTS => {
+                    attempts += 1;
+                    let delay = self.throttled(retry_after(resp));
+                    warn!(
+                        "{} responded {}; pausing requests to it for {}",
+                        req.url().host_str().unwrap_or_default(),
+                        resp.status(),
+                        humantime::format_duration(delay)
+                    );
+                }
+                Ok(resp) if !is_throttled(resp.status()) => {
+                    self.recovered();
+                    return res;
+                }
+                _ => return res,
+            }
+        }
+    }
+}
+
+/// Retries transient failures like the default strategy, except for the
+/// throttling responses that [`Throttle`] already handled and the
+/// [permanent](crate::error::is_permanent) ones.
+pub struct RetryStrategy;
+
+impl RetryableStrategy for RetryStrategy {
+    fn handle(&self, res: &reqwest_middleware::Result<Response>) -> Option<Retryable> {
+        match res {
+            Ok(resp) if is_throttled(resp.status()) => None,
+            Ok(resp) if crate::error::is_permanent(resp.status()) => Some(Retryable::Fatal),
+            // A full destination stays full for the next try.
+            Ok(resp) if resp.status() == StatusCode::INSUFFICIENT_STORAGE => Some(Retryable::Fatal),
+            Ok(resp) => default_on_request_success(resp),
+            // Unlike other errors of the middleware, a stall is worth
+            // another try.
+            Err(reqwest_middleware::Error::Middleware(error))
+                if matches!(error.downcast_ref(), Some(crate::Error::Stalled { .. })) =>
+            {
+                Some(Retryable::Transient)
+            }
+            Err(error) => default_on_request_failure(error),
+        }
+    }
+}
+
+fn is_throttled(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE
+}
+
+/// Parses `Retry-After` as either delay seconds or an HTTP date.
+fn retry_after(resp: &Response) -> Option<Duration> {
+    let value = resp.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        SystemTime::from(date)
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
@@ -0,0 +1,516 @@
+//! TLS configuration for repository endpoints.
+//!
+//! Each endpoint gets its own rustls configuration built from its options:
+//! extra CA roots, a client certificate for mutual TLS, optional pins on
+//! the server certificate, restrictions on protocol versions and cipher
+//! suites, and the escape hatch of skipping verification entirely. Certificates are otherwise verified by the platform verifier,
+//! the same one reqwest uses by default.
+
+use crate::endpoint::{EndpointOptions, read_secret_file};
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use clap::ValueEnum;
+use p12_keystore::{KeyStore, Pkcs12ImportPolicy};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, SignatureScheme, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// An expected server certificate, identified by a SHA-256 hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pin {
+    /// Hash of the DER-encoded SubjectPublicKeyInfo, written as
+    /// `sha256/<base64>` (the HPKP format, which survives certificate renewal
+    /// as long as the key is kept).
+    Spki([u8; 32]),
+    /// Fingerprint of the whole DER certificate, written as hex with optional
+    /// colons (as printed by `openssl x509 -fingerprint -sha256`).
+    Certificate([u8; 32]),
+}
+
+impl Pin {
+    fn matches(&self, cert: &CertificateDer<'_>) -> bool {
+        match self {
+            Pin::Spki(hash) => x509_parser::parse_x509_certificate(cert)
+                .is_ok_and(|(_, cert)| Sha256::digest(cert.public_key().raw)[..] == hash[..]),
+            Pin::Certificate(hash) => Sha256::digest(cert)[..] == hash[..],
+        }
+    }
+}
+
+impl FromStr for Pin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(encoded) = s.strip_prefix("sha256/") {
+            let hash = BASE64
+                .decode(encoded)
+                .map_err(|e| format!("invalid base64 in SPKI pin: {e}"))?;
+            return hash
+                .try_into()
+                .map(Pin::Spki)
+                .map_err(|_| "SPKI pin must be a SHA-256 hash".to_string());
+        }
+
+        let hex: String = s.chars().filter(|&c| c != ':').collect();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err("expected sha256/<base64> or a hex SHA-256 fingerprint".to_string());
+        }
+        let mut hash = [0; 32];
+        for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).expect("checked ASCII");
+            *byte = u8::from_str_radix(pair, 16)
+                .map_err(|_| format!("invalid hex in certificate fingerprint: {s}"))?;
+        }
+        Ok(Pin::Certificate(hash))
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pin::Spki(hash) => write!(f, "sha256/{}", BASE64.encode(hash)),
+            Pin::Certificate(hash) => {
+                for (i, byte) in hash.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(":")?;
+                    }
+                    write!(f, "{byte:02X}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A TLS protocol version; rustls does not implement anything older than 1.2.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[value(name = "1.2")]
+    Tls12,
+    #[value(name = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    fn rustls(self) -> &'static SupportedProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+/// Builds the rustls configuration for an endpoint.
+pub fn client_config(options: &EndpointOptions) -> Result<ClientConfig> {
+    let mut provider = rustls::crypto::aws_lc_rs::default_provider();
+    if !options.tls_ciphers.is_empty() {
+        provider.cipher_suites =
+            select_cipher_suites(&provider.cipher_suites, &options.tls_ciphers)?;
+    }
+    let provider = Arc::new(provider);
+
+    let versions: Vec<_> = [TlsVersion::Tls12, TlsVersion::Tls13]
+        .into_iter()
+        .filter(|&version| options.tls_min_version.is_none_or(|min| version >= min))
+        .filter(|&version| options.tls_max_version.is_none_or(|max| version <= max))
+        .map(TlsVersion::rustls)
+        .collect();
+    if versions.is_empty() {
+        bail!("The minimum TLS version is higher than the maximum");
+    }
+
+    let inner: Option<Arc<dyn ServerCertVerifier>> = if options.insecure_tls {
+        None
+    } else {
+        let roots = match &options.cacert {
+            Some(path) => load_cacerts(path)?,
+            None => Vec::new(),
+        };
+        let verifier = if roots.is_empty() {
+            rustls_platform_verifier::Verifier::new(provider.clone())
+        } else {
+            rustls_platform_verifier::Verifier::new_with_extra_roots(roots, provider.clone())
+        }
+        .context("Failed to set up TLS certificate verification")?;
+        Some(Arc::new(verifier))
+    };
+    let verifier = PinnedVerifier {
+        inner,
+        pins: options.pins.clone(),
+        provider: provider.clone(),
+    };
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&versions)
+        .context("None of the allowed TLS cipher suites works with the allowed TLS versions")?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let mut config = match &options.client_cert {
+        Some(path) => {
+            let (certs, key) = client_identity(options, path)?;
+            builder.with_client_auth_cert(certs, key).with_context(|| {
+                format!("Invalid client certificate or key in {}", path.display())
+            })?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Restricts the provider's cipher suites to `names`, keeping its order of
+/// preference.
+fn select_cipher_suites(
+    available: &[SupportedCipherSuite],
+    names: &[String],
+) -> Result<Vec<SupportedCipherSuite>> {
+    let name_of = |suite: &SupportedCipherSuite| suite.suite().as_str().unwrap_or_default();
+    if let Some(unknown) = names.iter().find(|name| {
+        !available
+            .iter()
+            .any(|suite| name_of(suite).eq_ignore_ascii_case(name))
+    }) {
+        let supported: Vec<_> = available.iter().map(name_of).collect();
+        bail!(
+            "Unsupported TLS cipher suite {unknown}; supported: {}",
+            supported.join(", ")
+        );
+    }
+    Ok(available
+        .iter()
+        .filter(|suite| {
+            names
+                .iter()
+                .any(|name| name_of(suite).eq_ignore_ascii_case(name))
+        })
+        .copied()
+        .collect())
+}
+
+fn load_cacerts(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Failed to read CA certificates from {}", path.display()))?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid CA certificate bundle {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+type Identity = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>);
+
+/// Loads the client certificate chain and its private key for mutual TLS.
+fn client_identity(options: &EndpointOptions, cert_path: &Path) -> Result<Identity> {
+    let cert = std::fs::read(cert_path)
+        .with_context(|| format!("Failed to read client certificate {}", cert_path.display()))?;
+    let is_pkcs12 = cert_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("p12") || ext.eq_ignore_ascii_case("pfx"));
+
+    if is_pkcs12 {
+        if options.client_key.is_some() {
+            bail!("A PKCS#12 client certificate already contains its key; drop the key file");
+        }
+        let password = options
+            .client_cert_password_file
+            .as_deref()
+            .map(read_secret_file)
+            .transpose()?
+            .unwrap_or_default();
+        return load_pkcs12(&cert, &password)
+            .with_context(|| format!("Invalid PKCS#12 bundle {}", cert_path.display()));
+    }
+
+    if options.client_cert_password_file.is_some() {
+        bail!("A client certificate password is only supported for PKCS#12 bundles");
+    }
+    let certs = CertificateDer::pem_slice_iter(&cert)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid client certificate {}", cert_path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found in {}", cert_path.display());
+    }
+    let key = match &options.client_key {
+        Some(key_path) => {
+            let key = std::fs::read(key_path)
+                .with_context(|| format!("Failed to read client key {}", key_path.display()))?;
+            PrivateKeyDer::from_pem_slice(&key)
+                .with_context(|| format!("No private key found in {}", key_path.display()))?
+        }
+        None => PrivateKeyDer::from_pem_slice(&cert).with_context(|| {
+            format!(
+                "No private key found in {}; pass the key file separately",
+                cert_path.display()
+            )
+        })?,
+    };
+    Ok((certs, key))
+}
+
+fn load_pkcs12(data: &[u8], password: &str) -> Result<Identity> {
+    let store = KeyStore::from_pkcs12(data, password, Pkcs12ImportPolicy::Strict)
+        .map_err(|e| anyhow!("{e}"))?;
+    let (_, chain) = store
+        .private_key_chain()
+        .context("No private key with a certificate found")?;
+    let certs = chain
+        .certs()
+        .iter()
+        .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+        .collect();
+    let key = PrivatePkcs8KeyDer::from(chain.key().as_der().to_vec()).into();
+    Ok((certs, key))
+}
+
+/// Verifies the server certificate with the platform verifier (unless
+/// verification is disabled) and then checks it against the pins, which
+/// may name an intermediate only when the chain is verified.
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Option<Arc<dyn ServerCertVerifier>>,
+    pins: Vec<Pin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        // Only a verified chain ties the intermediates to the server
+        // certificate; without one, anyone can send the pinned intermediate
+        // along with a certificate of their own.
+        let chain = match &self.inner {
+            Some(inner) => {
+                inner.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    ocsp_response,
+                    now,
+                )?;
+                intermediates
+            }
+            None => &[],
+        };
+        let pinned = self.pins.is_empty()
+            || std::iter::once(end_entity)
+                .chain(chain)
+                .any(|cert| self.pins.iter().any(|pin| pin.matches(cert)));
+        if !pinned {
+            let actual = x509_parser::parse_x509_certificate(end_entity)
+                .map(|(_, cert)| Pin::Spki(Sha256::digest(cert.public_key().raw).into()))
+                .unwrap_or(Pin::Certificate(Sha256::digest(end_entity).into()));
+            return Err(rustls::Error::General(format!(
+                "server certificate does not match any configured pin (server presented {actual})"
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // Handshake signatures are always checked, so that a pin still proves
+    // possession of the key when chain verification is disabled.
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        match &self.inner {
+            Some(inner) => inner.verify_tls12_signature(message, cert, dss),
+            None => verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.provider.signature_verification_algorithms,
+            ),
+        }
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        match &self.inner {
+            Some(inner) => inner.verify_tls13_signature(message, cert, dss),
+            None => verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.provider.signature_verification_algorithms,
+            ),
+        }
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        match &self.inner {
+            Some(inner) => inner.supported_verify_schemes(),
+            None => self
+                .provider
+                .signature_verification_algorithms
+                .supported_schemes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(pem: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(pem.as_bytes()).unwrap()
+    }
+
+    fn leaf() -> CertificateDer<'static> {
+        cert(include_str!("../tests/fixtures/tls/leaf.pem"))
+    }
+
+    fn intermediate() -> CertificateDer<'static> {
+        cert(include_str!("../tests/fixtures/tls/intermediate.pem"))
+    }
+
+    /// A self-signed certificate for the same name as `leaf`, as a
+    /// man-in-the-middle would present.
+    fn forged() -> CertificateDer<'static> {
+        cert(include_str!("../tests/fixtures/tls/forged.pem"))
+    }
+
+    fn spki_pin(cert: &CertificateDer<'_>) -> Pin {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).unwrap();
+        Pin::Spki(Sha256::digest(cert.public_key().raw).into())
+    }
+
+    /// Stands in for the platform verifier, accepting every chain or none.
+    #[derive(Debug)]
+    struct Chain(bool);
+
+    impl ServerCertVerifier for Chain {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            if self.0 {
+                Ok(ServerCertVerified::assertion())
+            } else {
+                Err(rustls::Error::General("untrusted chain".to_string()))
+            }
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            unreachable!()
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            unreachable!()
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            Vec::new()
+        }
+    }
+
+    fn verify(
+        chain: Option<bool>,
+        pins: Vec<Pin>,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verifier = PinnedVerifier {
+            inner: chain.map(|trusted| Arc::new(Chain(trusted)) as Arc<dyn ServerCertVerifier>),
+            pins,
+            provider: Arc::new(rustls::crypto::aws_lc_rs::default_provider()),
+        };
+        let name = ServerName::try_from("repo.example").unwrap();
+        verifier.verify_server_cert(end_entity, intermediates, &name, &[], UnixTime::now())
+    }
+
+    #[test]
+    fn pins_parse_and_display() {
+        let spki: Pin = "sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+            .parse()
+            .unwrap();
+        assert!(matches!(spki, Pin::Spki(_)));
+        assert_eq!(
+            spki.to_string(),
+            "sha256/47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+
+        let colons = "4B:F0:90:38:5D:FE:B1:21:B0:30:08:B4:DB:72:1B:43:6F:59:5A:D0:4E:09:26:00:86:E0:42:97:58:29:6C:3D";
+        let fingerprint: Pin = colons.parse().unwrap();
+        assert_eq!(
+            fingerprint,
+            colons.replace(':', "").to_lowercase().parse().unwrap()
+        );
+        assert_eq!(fingerprint.to_string(), colons);
+        assert_eq!(fingerprint, Pin::Certificate(Sha256::digest(leaf()).into()));
+    }
+
+    #[test]
+    fn malformed_pins_are_refused() {
+        assert!("sha256/not base64".parse::<Pin>().is_err());
+        assert!("sha256/AAAA".parse::<Pin>().is_err());
+        assert!("4BF090".parse::<Pin>().is_err());
+        assert!("zz".repeat(32).parse::<Pin>().is_err());
+        assert!("é".repeat(32).parse::<Pin>().is_err());
+    }
+
+    #[test]
+    fn a_verified_chain_may_be_pinned_anywhere() {
+        let chain = [intermediate()];
+        assert!(verify(Some(true), vec![spki_pin(&leaf())], &leaf(), &chain).is_ok());
+        assert!(verify(Some(true), vec![spki_pin(&intermediate())], &leaf(), &chain).is_ok());
+        assert!(verify(Some(true), vec![spki_pin(&forged())], &leaf(), &chain).is_err());
+        assert!(verify(Some(true), Vec::new(), &leaf(), &chain).is_ok());
+    }
+
+    #[test]
+    fn a_matching_pin_does_not_rescue_an_untrusted_chain() {
+        let pins = vec![spki_pin(&leaf())];
+        assert!(verify(Some(false), pins, &leaf(), &[intermediate()]).is_err());
+    }
+
+    #[test]
+    fn without_chain_verification_only_the_server_certificate_counts() {
+        let pins = vec![spki_pin(&intermediate())];
+        assert!(verify(None, pins.clone(), &forged(), &[intermediate()]).is_err());
+        assert!(verify(None, pins, &leaf(), &[intermediate()]).is_err());
+
+        let pins = vec![Pin::Certificate(Sha256::digest(leaf()).into())];
+        assert!(verify(None, pins.clone(), &leaf(), &[intermediate()]).is_ok());
+        assert!(verify(None, pins, &forged(), &[intermediate()]).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! The full-screen dashboard of `--tui`.
+//!
+//! While it runs, the sync reports into a shared [`Dashboard`] instead of
+//! printing: each file type's queue, the transfer in progress, the bytes
+//! downloaded per second and the log messages, which would otherwise tear
+//! up the screen. A task redraws the screen a few times per second and
+//! watches for `q` or Ctrl-C, which raw mode no longer turns into a signal.
+
+use crate::format_bytes;
+use anyhow::{Result, bail};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Write};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Log lines and throughput samples kept for display.
+const MAX_MESSAGES: usize = 200;
+const MAX_SAMPLES: usize = 600;
+
+static DASHBOARD: Mutex<Option<Dashboard>> = Mutex::new(None);
+
+struct Dashboard {
+    started: Instant,
+    queues: Vec<Queue>,
+    transfers: Vec<Transfer>,
+    next_transfer: u64,
+    /// Bytes downloaded in each past second, the latest last.
+    samples: VecDeque<u64>,
+    this_second: u64,
+    second_started: Instant,
+    messages: VecDeque<String>,
+    /// Summaries and errors, printed again once the screen is restored.
+    afterwards: Vec<String>,
+}
+
+/// All transfers of one file type, over all jobs and runs.
+struct Queue {
+    file_type: String,
+    files: usize,
+    bytes: u64,
+    done_files: usize,
+    done_bytes: u64,
+}
+
+struct Transfer {
+    id: u64,
+    file_type: String,
+    name: String,
+    size: u64,
+    done: u64,
+}
+
+fn dashboard() -> MutexGuard<'static, Option<Dashboard>> {
+    DASHBOARD.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Runs `update` on the dashboard, if it is shown.
+fn update(update: impl FnOnce(&mut Dashboard)) {
+    if let Some(dashboard) = dashboard().as_mut() {
+        update(dashboard);
+    }
+}
+
+/// Adds `files` transfers of `file_type`, totalling `bytes`, to its queue.
+pub fn queue(file_type: &str, files: usize, bytes: u64) {
+    update(|dashboard| {
+        let queue = match dashboard
+            .queues
+            .iter_mut()
+            .position(|queue| queue.file_type == file_type)
+        {
+            Some(i) => &mut dashboard.queues[i],
+            None => {
+                dashboard.queues.push(Queue {
+                    file_type: file_type.to_string(),
+                    files: 0,
+                    bytes: 0,
+                    done_files: 0,
+                    done_bytes: 0,
+                });
+                dashboard.queues.last_mut().unwrap()
+            }
+        };
+        queue.files += files;
+        queue.bytes += bytes;
+    });
+}
+
+/// Records a finished transfer of `bytes` from the queue of `file_type`.
+pub fn dequeue(file_type: &str, bytes: u64) {
+    update(|dashboard| {
+        if let Some(queue) = dashboard
+            .queues
+            .iter_mut()
+            .find(|queue| queue.file_type == file_type)
+        {
+            queue.done_files += 1;
+            queue.done_bytes += bytes;
+        }
+    });
+}
+
+/// Shows a transfer in progress, returning its ID for [`progress`] and
+/// [`finish`].
+pub fn start(file_type: &str, name: &str, size: u64) -> u64 {
+    let mut id = 0;
+    update(|dashboard| {
+        dashboard.next_transfer += 1;
+        id = dashboard.next_transfer;
+        dashboard.transfers.push(Transfer {
+            id,
+            file_type: file_type.to_string(),
+            name: name.to_string(),
+            size,
+            done: 0,
+        });
+    });
+    id
+}
+
+pub fn progress(id: u64, bytes: u64) {
+    update(|dashboard| {
+        dashboard.this_second += bytes;
+        if let Some(transfer) = dashboard.transfers.iter_mut().find(|t| t.id == id) {
+            transfer.done += bytes;
+        }
+    });
+}
+
+pub fn finish(id: u64) {
+    update(|dashboard| dashboard.transfers.retain(|t| t.id != id));
+}
+
+/// Keeps `summary` to print after the dashboard is closed.
+pub fn summary(summary: &str) {
+    update(|dashboard| {
+        dashboard.messages.push_back(summary.to_string());
+        dashboard.afterwards.push(summary.to_string());
+    });
+}
+
+/// The log target while the dashboard is shown, feeding the messages pane.
+#[derive(Default)]
+pub struct LogPane {
+    line: Vec<u8>,
+}
+
+impl LogPane {
+    pub fn new() -> Self {
+        LogPane { line: Vec::new() }
+    }
+}
+
+impl Write for LogPane {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for &byte in buf {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = String::from_utf8_lossy(&self.line).into_owned();
+            self.line.clear();
+            let mut guard = dashboard();
+            let Some(dashboard) = guard.as_mut() else {
+                // The dashboard is closed already.
+                eprintln!("{}", line);
+                continue;
+            };
+            if line.contains("ERROR") {
+                dashboard.afterwards.push(line.clone());
+            }
+            dashboard.messages.push_back(line);
+            if dashboard.messages.len() > MAX_MESSAGES {
+                dashboard.messages.pop_front();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The dashboard on the screen; dropping it restores the terminal.
+pub struct Tui {
+    task: JoinHandle<()>,
+}
+
+impl Tui {
+    pub fn start() -> Result<Self> {
+        if !std::io::stdout().is_terminal() {
+            bail!("--tui needs a terminal");
+        }
+        let now = Instant::now();
+        *dashboard() = Some(Dashboard {
+            started: now,
+            queues: Vec::new(),
+            transfers: Vec::new(),
+            next_transfer: 0,
+            samples: VecDeque::new(),
+            this_second: 0,
+            second_started: now,
+            messages: VecDeque::new(),
+            afterwards: Vec::new(),
+        });
+        let terminal = ratatui::try_init()?;
+        let task = tokio::task::spawn_blocking(move || run(terminal));
+        Ok(Tui { task })
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        // Taking the dashboard waits for a redraw in progress and ends the
+        // redraw loop.
+        let dashboard = dashboard().take();
+        self.task.abort();
+        ratatui::restore();
+        for line in dashboard.into_iter().flat_map(|d| d.afterwards) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Redraws the dashboard until it is closed or the user quits.
+fn run(mut terminal: DefaultTerminal) {
+    loop {
+        {
+            let mut guard = dashboard();
+            let Some(dashboard) = guard.as_mut() else {
+                return;
+            };
+            if dashboard.second_started.elapsed() >= Duration::from_secs(1) {
+                dashboard.samples.push_back(dashboard.this_second);
+                if dashboard.samples.len() > MAX_SAMPLES {
+                    dashboard.samples.pop_front();
+                }
+                dashboard.this_second = 0;
+                dashboard.second_started = Instant::now();
+            }
+            if terminal.draw(|frame| draw(frame, dashboard)).is_err() {
+                return;
+            }
+        }
+        if let Ok(true) = event::poll(Duration::from_millis(250))
+            && let Ok(Event::Key(key)) = event::read()
+            && key.kind == KeyEventKind::Press
+            && (key.code == KeyCode::Char('q')
+                || key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+        {
+            // Stops the sync like Ctrl-C would without the dashboard.
+            ratatui::restore();
+            std::process::exit(130);
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard) {
+    let [header, queues, transfers, throughput, messages] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(dashboard.queues.len().max(1) as u16 + 3),
+        Constraint::Length(dashboard.transfers.len().max(1) as u16 + 2),
+        Constraint::Length(8),
+        Constraint::Min(3),
+    ])
+    .areas(frame.area());
+
+    let elapsed = dashboard.started.elapsed().as_secs();
+    let uploaded: u64 = dashboard.queues.iter().map(|queue| queue.done_bytes).sum();
+    frame.render_widget(
+        Line::from(format!(
+            " restic-sync  {:02}:{:02}:{:02}  {} uploaded   q: quit",
+            elapsed / 3600,
+            elapsed / 60 % 60,
+            elapsed % 60,
+            format_bytes(uploaded)
+        ))
+        .bold(),
+        header,
+    );
+
+    let rows = dashboard.queues.iter().map(|queue| {
+        Row::new(vec![
+            queue.file_type.clone(),
+            format!("{}/{}", queue.done_files, queue.files),
+            format!(
+                "{} of {}",
+                format_bytes(queue.done_bytes),
+                format_bytes(queue.bytes)
+            ),
+            bar(queue.done_bytes, queue.bytes, 30),
+        ])
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Length(10),
+                Constraint::Length(15),
+                Constraint::Length(25),
+                Constraint::Min(10),
+            ],
+        )
+        .header(Row::new(vec!["Type", "Files", "Bytes", "Progress"]).bold())
+        .block(Block::bordered().title(" Queues ")),
+        queues,
+    );
+
+    let rows = dashboard.transfers.iter().map(|transfer| {
+        Row::new(vec![
+            transfer.file_type.clone(),
+            transfer.name.clone(),
+            format!(
+                "{} of {}",
+                format_bytes(transfer.done),
+                format_bytes(transfer.size)
+            ),
+            bar(transfer.done, transfer.size, 20),
+        ])
+    });
+    frame.render_widget(
+        Table::new(
+            rows,
+            [
+                Constraint::Length(10),
+                Constraint::Length(66),
+                Constraint::Length(25),
+                Constraint::Min(10),
+            ],
+        )
+        .block(Block::bordered().title(" Active transfers ")),
+        transfers,
+    );
+
+    let width = throughput.width.saturating_sub(2) as usize;
+    let samples: Vec<u64> = dashboard
+        .samples
+        .iter()
+        .rev()
+        .take(width)
+        .rev()
+        .copied()
+        .collect();
+    let current = samples.last().copied().unwrap_or_default();
+    let peak = samples.iter().max().copied().unwrap_or_default();
+    frame.render_widget(
+        Sparkline::default()
+            .data(&samples)
+            .style(Style::new().fg(Color::Cyan))
+            .block(Block::bordered().title(format!(
+                " Throughput: {}/s, peak {}/s ",
+                format_bytes(current),
+                format_bytes(peak)
+            ))),
+        throughput,
+    );
+
+    let shown = messages.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = dashboard
+        .messages
+        .iter()
+        .skip(dashboard.messages.len().saturating_sub(shown))
+        .map(|message| {
+            let line = Line::from(message.as_str());
+            if message.contains("ERROR") {
+                line.red()
+            } else if message.contains("WARN") {
+                line.yellow()
+            } else {
+                line
+            }
+        })
+        .collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(" Messages ")),
+        messages,
+    );
+}
+
+/// A text progress bar `width` characters wide.
+fn bar(done: u64, total: u64, width: usize) -> String {
+    let fraction = if total == 0 {
+        1.0
+    } else {
+        done as f64 / total as f64
+    };
+    let filled = ((fraction * width as f64) as usize).min(width);
+    format!(
+        "{}{} {:>3.0}%",
+        "█".repeat(filled),
+        "░".repeat(width - filled),
+        fraction * 100.0
+    )
+}
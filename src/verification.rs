@@ -0,0 +1,176 @@
+//! How a sync checks the files it copies.
+//!
+//! Restic names every file after the SHA-256 of its contents, so by default
+//! each file is hashed after the download and only uploaded if it matches
+//! its name. `--verification` trades that for speed, or adds reading the
+//! file back from the destination, and `--type-verification` picks another
+//! strategy for some file types, e.g. hashing the small metadata files while
+//! only checking the sizes of the packs.
+//!
+//! A download that fails its check is more often garbled on the way, e.g.
+//! by a proxy, than corrupt in the source, so it is downloaded again past
+//! any caches, `--redownloads` times, before the file is taken as corrupt.
+//!
+//! A file whose upload fails after it passed its check is kept in
+//! [`VerifiedDownloads`], so that trying it again in the same run only
+//! repeats the upload, neither the download nor the hashing.
+
+use crate::{Error, FILE_TYPES, sha256_hex};
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// How much of the verified downloads waiting for their upload is kept.
+const MAX_VERIFIED_BYTES: usize = 256 * 1024 * 1024;
+
+/// How the files of a type are checked before and after their upload.
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verification {
+    /// Upload what was downloaded without checking it
+    None,
+    /// Check that the download has the size listed by the source
+    SizeOnly,
+    /// Check that the download hashes to its name
+    #[default]
+    Sha256,
+    /// Also download the file from the destination after the upload and
+    /// hash it again
+    Sha256PlusReadback,
+}
+
+impl Verification {
+    /// Checks the downloaded contents of `name`, listed by the source with
+    /// `size` if that is known.
+    pub fn check_download(self, name: &str, size: Option<u64>, bytes: &[u8]) -> Result<()> {
+        match self {
+            Verification::None => {}
+            Verification::SizeOnly => {
+                if let Some(size) = size.filter(|&size| size != bytes.len() as u64) {
+                    bail!(Error::VerificationFailed {
+                        name: name.to_string(),
+                        expected: size.to_string(),
+                        actual: bytes.len().to_string(),
+                    });
+                }
+            }
+            Verification::Sha256 | Verification::Sha256PlusReadback => {
+                let hash_hex = sha256_hex(bytes);
+                if hash_hex != name {
+                    bail!(Error::VerificationFailed {
+                        name: name.to_string(),
+                        expected: name.to_string(),
+                        actual: hash_hex,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn reads_back(self) -> bool {
+        self == Verification::Sha256PlusReadback
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct VerificationArgs {
+    /// How to check each copied file
+    #[arg(long, env = "REST_SYNC_VERIFICATION", value_name = "STRATEGY", value_enum, default_value_t = Verification::Sha256)]
+    verification: Verification,
+
+    /// Check the files of one type differently, as TYPE=STRATEGY, e.g.
+    /// "data=size-only" (repeatable; comma-separated in the env var)
+    #[arg(long, env = "REST_SYNC_TYPE_VERIFICATION", value_name = "TYPE=STRATEGY", value_delimiter = ',')]
+    type_verification: Vec<TypeVerification>,
+
+    /// How many more times to download a file that fails its check, asking
+    /// any caches on the way for a fresh copy, before taking it as corrupt
+    #[arg(long, env = "REST_SYNC_REDOWNLOADS", value_name = "N", default_value_t = 2)]
+    redownloads: u32,
+}
+
+impl VerificationArgs {
+    /// The strategy for `file_type`: its own, or that of the run.
+    pub fn for_type(&self, file_type: &str) -> Verification {
+        self.type_verification
+            .iter()
+            .rev()
+            .find(|t| t.file_type == file_type)
+            .map_or(self.verification, |t| t.verification)
+    }
+
+    pub fn redownloads(&self) -> u32 {
+        self.redownloads
+    }
+
+    pub fn set(&mut self, verification: Verification) {
+        self.verification = verification;
+    }
+
+    pub fn set_for_type(&mut self, file_type: &str, verification: Verification) {
+        self.type_verification.push(TypeVerification {
+            file_type: file_type.to_string(),
+            verification,
+        });
+    }
+}
+
+/// A `--type-verification` value.
+#[derive(Debug, Clone)]
+struct TypeVerification {
+    file_type: String,
+    verification: Verification,
+}
+
+impl FromStr for TypeVerification {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((file_type, verification)) = s.split_once('=') else {
+            return Err("expected TYPE=STRATEGY".to_string());
+        };
+        if !FILE_TYPES.contains(&file_type) {
+            return Err(format!(
+                "unknown file type `{file_type}` (expected one of {})",
+                FILE_TYPES.join(", ")
+            ));
+        }
+        Ok(TypeVerification {
+            file_type: file_type.to_string(),
+            verification: <Verification as ValueEnum>::from_str(verification, false)?,
+        })
+    }
+}
+
+/// The contents of files that passed their check but are not uploaded yet,
+/// by path, for a run that reads one source repository. The oldest are dropped
+/// beyond [`MAX_VERIFIED_BYTES`].
+#[derive(Debug, Default)]
+pub struct VerifiedDownloads {
+    files: Mutex<VecDeque<(String, Bytes)>>,
+}
+
+impl VerifiedDownloads {
+    /// The verified contents of `path`, if they are kept.
+    pub fn take(&self, path: &str) -> Option<Bytes> {
+        let mut files = self.files.lock().unwrap();
+        let i = files.iter().position(|(kept, _)| kept == path)?;
+        files.remove(i).map(|(_, bytes)| bytes)
+    }
+
+    /// Keeps the verified contents of `path` for another try at its upload.
+    pub fn keep(&self, path: &str, bytes: Bytes) {
+        let mut files = self.files.lock().unwrap();
+        files.retain(|(kept, _)| kept != path);
+        files.push_back((path.to_string(), bytes));
+        let mut total: usize = files.iter().map(|(_, bytes)| bytes.len()).sum();
+        while total > MAX_VERIFIED_BYTES
+            && let Some((_, dropped)) = files.pop_front()
+        {
+            total -= dropped.len();
+        }
+    }
+}
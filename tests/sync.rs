@@ -0,0 +1,1777 @@
+//! Syncs between repositories held in memory, through a [`Transport`] that
+//! answers like a REST server.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use reqwest::{Method, Request, Response, StatusCode};
+use restic_sync::schema::Schema;
+use restic_sync::{
+    CancellationToken, Cancelled, Error, Failed, FileAction, FileEvent, PlannedFile,
+    SharedDownloads, SyncClient, SyncJobBuilder, SyncObserver, SyncOptions, SyncPlan, SyncReport,
+    Transport, TypeReport, Verification, exit_code,
+};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SOURCE: &str = "http://source.test/";
+const DEST: &str = "http://dest.test/";
+
+/// A REST server with a single repository, as rest-server behaves.
+#[derive(Default)]
+struct Server {
+    files: Mutex<BTreeMap<String, Vec<u8>>>,
+    /// Responses to give to the next requests of `METHOD path` instead of
+    /// handling them.
+    failures: Mutex<HashMap<String, VecDeque<Response>>>,
+    /// How many of the next requests of `METHOD path` to leave unanswered.
+    unanswered: Mutex<HashMap<String, usize>>,
+    /// Every request received, as `METHOD path`.
+    requests: Mutex<Vec<String>>,
+}
+
+impl Server {
+    /// Adds a file named by the SHA-256 of `contents`, returning the name.
+    fn add(&self, file_type: &str, contents: &str) -> String {
+        let name = format!("{:x}", Sha256::digest(contents));
+        self.add_named(&format!("{file_type}/{name}"), contents);
+        name
+    }
+
+    fn add_named(&self, path: &str, contents: &str) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), contents.as_bytes().to_vec());
+    }
+
+    fn paths(&self) -> Vec<String> {
+        self.files.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn fail(&self, request: &str, status: StatusCode, retry_after: Option<&str>) {
+        let mut response = http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            response = response.header("Retry-After", retry_after);
+        }
+        self.failures
+            .lock()
+            .unwrap()
+            .entry(request.to_string())
+            .or_default()
+            .push_back(response.body(Vec::new()).unwrap().into());
+    }
+
+    /// Answers the next `request` with the start of `contents`, and then
+    /// nothing more, as over a connection that went dead.
+    fn stall(&self, request: &str, contents: &str) {
+        let start = contents.as_bytes()[..contents.len() / 2].to_vec();
+        let body = futures_util::stream::once(async { Ok::<_, std::io::Error>(start) })
+            .chain(futures_util::stream::pending());
+        let response = http::Response::new(reqwest::Body::wrap_stream(body));
+        self.failures
+            .lock()
+            .unwrap()
+            .entry(request.to_string())
+            .or_default()
+            .push_back(response.into());
+    }
+
+    /// Answers the next `request` with `contents` in a few pieces, each
+    /// `pause` after the one before, as a slow connection does.
+    fn trickle(&self, request: &str, contents: &str, pause: Duration) {
+        let pieces: Vec<Vec<u8>> = contents
+            .as_bytes()
+            .chunks(contents.len().div_ceil(5))
+            .map(<[u8]>::to_vec)
+            .collect();
+        let body = futures_util::stream::iter(pieces).then(move |piece| async move {
+            tokio::time::sleep(pause).await;
+            Ok::<_, std::io::Error>(piece)
+        });
+        let response = http::Response::new(reqwest::Body::wrap_stream(body));
+        self.failures
+            .lock()
+            .unwrap()
+            .entry(request.to_string())
+            .or_default()
+            .push_back(response.into());
+    }
+
+    /// Leaves the next `request` unanswered, as a server that hangs.
+    fn hang(&self, request: &str) {
+        *self
+            .unanswered
+            .lock()
+            .unwrap()
+            .entry(request.to_string())
+            .or_default() += 1;
+    }
+
+    /// Answers the next `request` with a web page, as a proxy in front of
+    /// the server may.
+    fn intercept(&self, request: &str, page: &str) {
+        let response = http::Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(page.as_bytes().to_vec())
+            .unwrap();
+        self.failures
+            .lock()
+            .unwrap()
+            .entry(request.to_string())
+            .or_default()
+            .push_back(response.into());
+    }
+
+    /// Answers the next `request` with `body` as a listing, as a proxy that
+    /// cut it short may.
+    fn truncate(&self, request: &str, body: &str) {
+        let response = http::Response::builder()
+            .header("Content-Type", "application/vnd.x.restic.rest.v2")
+            .body(body.as_bytes().to_vec())
+            .unwrap();
+        self.failures
+            .lock()
+            .unwrap()
+            .entry(request.to_string())
+            .or_default()
+            .push_back(response.into());
+    }
+
+    fn count(&self, request: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| *r == request)
+            .count()
+    }
+
+    fn writes(&self) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| (r.starts_with("POST") || r.starts_with("DELETE")) && *r != "POST ")
+            .count()
+    }
+}
+
+fn respond(status: StatusCode, body: Vec<u8>) -> Response {
+    http::Response::builder()
+        .status(status)
+        .body(body)
+        .unwrap()
+        .into()
+}
+
+#[async_trait]
+impl Transport for Server {
+    async fn send(&self, request: Request) -> Result<Response> {
+        let path = request.url().path().trim_start_matches('/').to_string();
+        let key = format!("{} {}", request.method(), path);
+        self.requests.lock().unwrap().push(key.clone());
+        let hangs = self
+            .unanswered
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .filter(|count| **count > 0)
+            .map(|count| *count -= 1)
+            .is_some();
+        if hangs {
+            std::future::pending::<()>().await;
+        }
+        if let Some(response) = self
+            .failures
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .and_then(VecDeque::pop_front)
+        {
+            return Ok(response);
+        }
+
+        let mut files = self.files.lock().unwrap();
+        let response = match (request.method().clone(), path.as_str()) {
+            // Creating the repository.
+            (Method::POST, "") => respond(StatusCode::OK, Vec::new()),
+            (Method::GET, dir) if dir.ends_with('/') => {
+                let list: Vec<_> = files
+                    .iter()
+                    .filter_map(|(path, contents)| {
+                        let name = path.strip_prefix(dir)?;
+                        Some(serde_json::json!({"name": name, "size": contents.len()}))
+                    })
+                    .collect();
+                respond(StatusCode::OK, serde_json::to_vec(&list)?)
+            }
+            (Method::GET, path) => match files.get(path) {
+                Some(contents) => respond(StatusCode::OK, contents.clone()),
+                None => respond(StatusCode::NOT_FOUND, Vec::new()),
+            },
+            (Method::HEAD, path) => match files.get(path) {
+                Some(contents) => http::Response::builder()
+                    .header("Content-Length", contents.len())
+                    .body(Vec::new())
+                    .unwrap()
+                    .into(),
+                None => respond(StatusCode::NOT_FOUND, Vec::new()),
+            },
+            (Method::POST, "config") if files.contains_key("config") => {
+                respond(StatusCode::FORBIDDEN, Vec::new())
+            }
+            (Method::POST, path) => {
+                let body = request.body().and_then(|body| body.as_bytes());
+                files.insert(path.to_string(), body.unwrap_or_default().to_vec());
+                respond(StatusCode::OK, Vec::new())
+            }
+            (Method::DELETE, path) => match files.remove(path) {
+                Some(_) => respond(StatusCode::OK, Vec::new()),
+                None => respond(StatusCode::NOT_FOUND, Vec::new()),
+            },
+            _ => respond(StatusCode::METHOD_NOT_ALLOWED, Vec::new()),
+        };
+        Ok(response)
+    }
+}
+
+/// A server that panics on the first upload, as a bug in the stack might.
+struct Panicking(Arc<Server>);
+
+#[async_trait]
+impl Transport for Panicking {
+    async fn send(&self, request: Request) -> Result<Response> {
+        if request.method() == Method::POST && request.url().path().starts_with("/data/") {
+            panic!("bug in the transport");
+        }
+        self.0.send(request).await
+    }
+}
+
+/// Records the callbacks of a sync, as `event type/name`.
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<String>>,
+}
+
+impl Recorder {
+    fn record(&self, event: &str, file: &FileEvent) {
+        let action = match file.action {
+            FileAction::Upload => "upload",
+            FileAction::Delete => "delete",
+        };
+        self.events.lock().unwrap().push(format!(
+            "{event} {action} {}/{}",
+            file.file_type,
+            &file.name[..8]
+        ));
+    }
+
+    fn events(&self) -> Vec<String> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl SyncObserver for Recorder {
+    fn on_file_start(&self, file: &FileEvent) {
+        self.record("start", file);
+    }
+
+    fn on_file_complete(&self, file: &FileEvent, _bytes: u64) {
+        self.record("complete", file);
+    }
+
+    fn on_file_error(&self, file: &FileEvent, _error: &anyhow::Error) {
+        self.record("error", file);
+    }
+
+    fn on_type_complete(&self, report: &TypeReport) {
+        self.events.lock().unwrap().push(format!(
+            "done {} {}/{}/{}",
+            report.file_type, report.uploaded_files, report.uploaded_bytes, report.deleted_files
+        ));
+    }
+}
+
+/// A source repository with a config file and one file of each type.
+fn source() -> Arc<Server> {
+    let server = Arc::new(Server::default());
+    server.add_named("config", "source config");
+    server.add("data", "pack 1");
+    server.add("keys", "key");
+    server.add("snapshots", "snapshot");
+    server.add("index", "index");
+    server
+}
+
+async fn sync(source: &Arc<Server>, dest: &Arc<Server>, flags: &[&str]) -> Result<SyncReport> {
+    let options = SyncOptions::from_args(["--source", SOURCE, "--dest", DEST].iter().chain(flags))?;
+    SyncClient::new(options)?
+        .with_transport(source.clone(), dest.clone())
+        .run()
+        .await
+}
+
+#[tokio::test]
+async fn sync_copies_every_file() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+
+    let report = sync(&source, &dest, &[]).await.unwrap();
+
+    assert_eq!(dest.paths(), source.paths());
+    assert_eq!(report.uploaded_files, 4);
+    assert_eq!(report.uploaded_bytes, 6 + 3 + 8 + 5);
+    assert_eq!(report.deleted_files, 0);
+}
+
+#[tokio::test]
+async fn second_sync_uploads_nothing() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    sync(&source, &dest, &[]).await.unwrap();
+    source.add("data", "pack 2");
+
+    let report = sync(&source, &dest, &[]).await.unwrap();
+
+    assert_eq!(report.uploaded_files, 1);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn report_counts_each_type() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    sync(&source, &dest, &[]).await.unwrap();
+    source.add("data", "pack 2");
+    let extra = dest.add("snapshots", "forgotten snapshot");
+
+    let report = sync(&source, &dest, &["--prune"]).await.unwrap();
+
+    let data = report.types.iter().find(|t| t.file_type == "data").unwrap();
+    assert_eq!(
+        (
+            data.uploaded_files,
+            data.uploaded_bytes,
+            data.skipped_files,
+            data.skipped_bytes
+        ),
+        (1, 6, 1, 6)
+    );
+    let snapshots = report
+        .types
+        .iter()
+        .find(|t| t.file_type == "snapshots")
+        .unwrap();
+    assert_eq!(
+        (
+            snapshots.deleted_files,
+            snapshots.deleted_bytes,
+            snapshots.skipped_files
+        ),
+        (1, "forgotten snapshot".len() as u64, 1)
+    );
+    assert!(!dest.paths().contains(&format!("snapshots/{extra}")));
+    assert!(report.errors.is_empty());
+}
+
+#[tokio::test]
+async fn monitor_reports_the_difference_without_writing() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    dest.add_named("config", "source config");
+    dest.add("keys", "key");
+    let extra = dest.add("data", "pack that was pruned");
+
+    let report = sync(&source, &dest, &["--monitor"]).await.unwrap();
+
+    let divergence = report.divergence.unwrap();
+    assert_eq!(divergence.behind_files(), 3);
+    assert_eq!(divergence.behind_bytes(), 6 + 8 + 5);
+    assert_eq!(divergence.extra_files(), 1);
+    let data = divergence
+        .types
+        .iter()
+        .find(|t| t.file_type == "data")
+        .unwrap();
+    assert_eq!((data.behind_files, data.extra_files), (1, 1));
+    assert_eq!(dest.writes(), 0);
+    assert!(dest.paths().contains(&format!("data/{extra}")));
+}
+
+#[tokio::test]
+async fn extra_files_are_kept_without_prune() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let extra = dest.add("data", "pack that was pruned");
+
+    let report = sync(&source, &dest, &[]).await.unwrap();
+
+    assert_eq!(report.deleted_files, 0);
+    assert!(dest.paths().contains(&format!("data/{extra}")));
+}
+
+#[tokio::test]
+async fn prune_deletes_extra_files() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let extra = dest.add("data", "pack that was pruned");
+    let lock = dest.add("locks", "stale lock");
+
+    let report = sync(&source, &dest, &["--prune"]).await.unwrap();
+
+    assert_eq!(report.deleted_files, 2);
+    assert_eq!(dest.count(&format!("DELETE data/{extra}")), 1);
+    assert_eq!(dest.count(&format!("DELETE locks/{lock}")), 1);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn size_filters_leave_the_other_files_for_another_run() {
+    let source = source();
+    let large = source.add("data", "a much larger pack");
+    let dest = Arc::new(Server::default());
+
+    sync(&source, &dest, &["--max-size", "10"]).await.unwrap();
+    assert!(!dest.paths().contains(&format!("data/{large}")));
+
+    let report = sync(&source, &dest, &["--min-size", "11", "--prune"])
+        .await
+        .unwrap();
+    assert_eq!((report.uploaded_files, report.deleted_files), (1, 0));
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn modes_either_fill_in_or_repair() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let name = source.add("data", "pack 2");
+    dest.add_named(&format!("data/{name}"), "truncated");
+
+    let report = sync(&source, &dest, &["--mode", "missing-only"])
+        .await
+        .unwrap();
+    assert_eq!(report.uploaded_files, 4);
+    assert_eq!(dest.count(&format!("POST data/{name}")), 0);
+
+    let report = sync(&source, &dest, &["--mode", "size-mismatch-only"])
+        .await
+        .unwrap();
+    assert_eq!(report.uploaded_files, 1);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn a_spent_budget_leaves_the_rest_for_the_next_run() {
+    let source = source();
+    source.add("data", "pack 2");
+    let dest = Arc::new(Server::default());
+    let extra = dest.add("snapshots", "forgotten snapshot");
+
+    let report = sync(&source, &dest, &["--max-bytes", "10", "--prune"])
+        .await
+        .unwrap();
+    assert_eq!(report.uploaded_files, 1);
+    let remaining = report.remaining.unwrap();
+    assert_eq!(
+        (
+            remaining.upload_files,
+            remaining.upload_bytes,
+            remaining.delete_files
+        ),
+        (4, 6 + 3 + 8 + 5, 1)
+    );
+    assert!(dest.paths().contains(&format!("snapshots/{extra}")));
+
+    let report = sync(&source, &dest, &["--max-files", "2", "--prune"])
+        .await
+        .unwrap();
+    assert_eq!((report.uploaded_files, report.deleted_files), (2, 0));
+
+    let report = sync(&source, &dest, &["--max-files", "2", "--prune"])
+        .await
+        .unwrap();
+    assert!(report.remaining.is_none());
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn type_order_copies_the_keys_first() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+
+    sync(&source, &dest, &["--type-order", "keys,config"])
+        .await
+        .unwrap();
+
+    let uploads: Vec<String> = dest
+        .requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|r| r.starts_with("POST ") && *r != "POST ")
+        .map(|r| r["POST ".len()..].split('/').next().unwrap().to_string())
+        .collect();
+    assert_eq!(uploads, ["keys", "config", "data", "snapshots", "index"]);
+
+    let unsafe_order = sync(&source, &dest, &["--type-order", "config,index"]).await;
+    assert!(
+        unsafe_order
+            .unwrap_err()
+            .to_string()
+            .contains("`data` has to come before `index`")
+    );
+}
+
+#[tokio::test]
+async fn shards_split_the_files_between_instances() {
+    let source = source();
+    for i in 2..10 {
+        source.add("data", &format!("pack {i}"));
+    }
+    let mut synced = Vec::new();
+    for shard in ["1/3", "2/3", "3/3"] {
+        let dest = Arc::new(Server::default());
+        sync(
+            &source,
+            &dest,
+            &["--shard", shard, "--exclude-types", "config"],
+        )
+        .await
+        .unwrap();
+        assert!(!dest.paths().is_empty());
+        synced.extend(dest.paths());
+    }
+
+    synced.sort();
+    let mut files = source.paths();
+    files.retain(|path| path != "config");
+    assert_eq!(synced, files);
+
+    let options = SyncOptions::from_args(["--source", SOURCE, "--dest", DEST, "--shard", "4/3"]);
+    assert!(options.is_err());
+}
+
+#[tokio::test]
+async fn append_only_destination_is_never_deleted_from() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let extra = dest.add("data", "pack that was pruned");
+
+    sync(&source, &dest, &["--dest-append-only"]).await.unwrap();
+
+    assert!(dest.paths().contains(&format!("data/{extra}")));
+    assert!(
+        !dest
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|r| r.starts_with("DELETE"))
+    );
+}
+
+#[tokio::test]
+async fn append_only_mismatches_are_reported_as_errors() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let name = source.add("data", "pack 2");
+    dest.add_named(&format!("data/{name}"), "truncated");
+
+    let report = sync(&source, &dest, &["--dest-append-only"]).await.unwrap();
+
+    let data = report.types.iter().find(|t| t.file_type == "data").unwrap();
+    assert_eq!((data.uploaded_files, data.skipped_files), (1, 1));
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].contains(&format!("data/{name}")));
+}
+
+/// An empty directory of its own for `test`.
+fn temp_dir(test: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("restic-sync-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[tokio::test]
+async fn skip_listed_files_are_left_out_and_reported() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let corrupt = source.add("data", "corrupt pack");
+    dest.add_named(&format!("data/{corrupt}"), "good copy");
+    let dir = temp_dir("skip-list");
+    std::fs::create_dir_all(&dir).unwrap();
+    let skip_list = dir.join("skip");
+    std::fs::write(
+        &skip_list,
+        format!("# awaiting repair\ndata/{corrupt}  # fails its hash\n"),
+    )
+    .unwrap();
+
+    let report = sync(
+        &source,
+        &dest,
+        &["--prune", "--skip-list", skip_list.to_str().unwrap()],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(report.uploaded_files, 4);
+    assert_eq!(dest.count(&format!("POST data/{corrupt}")), 0);
+    assert_eq!(dest.count(&format!("DELETE data/{corrupt}")), 0);
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].contains(&corrupt));
+}
+
+#[tokio::test]
+async fn cached_destination_listing_is_not_fetched_again() {
+    let state_dir = temp_dir("listing-cache");
+    let flags = [
+        "--prune",
+        "--cache-dest-listing",
+        "--state-dir",
+        state_dir.to_str().unwrap(),
+    ];
+    let source = source();
+    let dest = Arc::new(Server::default());
+    sync(&source, &dest, &flags).await.unwrap();
+    let pack = source.add("data", "pack 2");
+    let pruned = source
+        .paths()
+        .into_iter()
+        .find(|p| p.starts_with("keys/"))
+        .unwrap();
+    source.files.lock().unwrap().remove(&pruned);
+
+    let report = sync(&source, &dest, &flags).await.unwrap();
+
+    assert_eq!((report.uploaded_files, report.deleted_files), (1, 1));
+    assert_eq!(dest.count("GET data/"), 1);
+    assert_eq!(source.count("GET data/"), 2);
+    assert!(dest.paths().contains(&format!("data/{pack}")));
+    assert!(!dest.paths().contains(&pruned));
+    // The cache knows about both changes.
+    let report = sync(&source, &dest, &flags).await.unwrap();
+    assert_eq!((report.uploaded_files, report.deleted_files), (0, 0));
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn reconciling_the_cached_listing_finds_what_changed_behind_its_back() {
+    let state_dir = temp_dir("reconcile");
+    let flags = [
+        "--cache-dest-listing",
+        "--reconcile-every",
+        "2",
+        "--state-dir",
+        state_dir.to_str().unwrap(),
+    ];
+    let source = source();
+    let dest = Arc::new(Server::default());
+    sync(&source, &dest, &flags).await.unwrap();
+    let lost = dest
+        .paths()
+        .into_iter()
+        .find(|p| p.starts_with("data/"))
+        .unwrap();
+    dest.files.lock().unwrap().remove(&lost);
+
+    let report = sync(&source, &dest, &flags).await.unwrap();
+    assert_eq!(report.uploaded_files, 0);
+    assert!(report.errors.is_empty());
+
+    let report = sync(&source, &dest, &flags).await.unwrap();
+    assert_eq!(dest.count("GET data/"), 2);
+    assert_eq!(report.uploaded_files, 1);
+    assert!(dest.paths().contains(&lost));
+    assert_eq!(report.errors.len(), 1);
+    assert!(report.errors[0].contains("had 1 files (6 B) that were gone"));
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn resumed_run_continues_with_the_files_left() {
+    let state_dir = temp_dir("resume");
+    let flags = ["--state-dir", state_dir.to_str().unwrap()];
+    let resume = [flags[0], flags[1], "--resume"];
+    let source = source();
+    source.add("data", "pack 2");
+    let packs: Vec<String> = source
+        .paths()
+        .into_iter()
+        .filter(|path| path.starts_with("data/"))
+        .collect();
+    let dest = Arc::new(Server::default());
+    dest.fail(&format!("POST {}", packs[1]), StatusCode::BAD_REQUEST, None);
+    sync(&source, &dest, &[flags[0], flags[1], "--file-retries", "0"])
+        .await
+        .unwrap_err();
+
+    let report = sync(&source, &dest, &resume).await.unwrap();
+
+    // The first run went on with the others after the failed pack, apart
+    // from the snapshot and index held back for it.
+    assert_eq!(report.uploaded_files, 3);
+    assert_eq!(source.count("GET data/"), 1);
+    assert_eq!(dest.paths(), source.paths());
+    // A run that finished leaves nothing to resume.
+    sync(&source, &dest, &resume).await.unwrap();
+    assert_eq!(source.count("GET data/"), 2);
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn fast_path_lists_data_only_after_the_index_changed() {
+    let state_dir = temp_dir("fast-path");
+    let flags = ["--fast-path", "--state-dir", state_dir.to_str().unwrap()];
+    let source = source();
+    let dest = Arc::new(Server::default());
+    sync(&source, &dest, &flags).await.unwrap();
+
+    sync(&source, &dest, &flags).await.unwrap();
+    assert_eq!(source.count("GET data/"), 1);
+
+    source.add("data", "pack 2");
+    source.add("index", "index 2");
+    let report = sync(&source, &dest, &flags).await.unwrap();
+    assert_eq!(source.count("GET data/"), 2);
+    assert_eq!(report.uploaded_files, 2);
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_pair_in_use_by_another_sync_is_refused() {
+    let state_dir = temp_dir("state-lock");
+    let flags = ["--state-dir", state_dir.to_str().unwrap()];
+    let source = source();
+    let dest = Arc::new(Server::default());
+    sync(&source, &dest, &flags).await.unwrap();
+    let pairs: Vec<_> = std::fs::read_dir(&state_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(pairs.len(), 1);
+    let pair = std::fs::read_to_string(pairs[0].join("pair")).unwrap();
+    assert_eq!(pair, format!("{SOURCE} -> {DEST}\n"));
+
+    let lock = std::fs::File::open(pairs[0].join("lock")).unwrap();
+    lock.lock().unwrap();
+    let e = sync(&source, &dest, &flags).await.unwrap_err();
+    assert!(e.to_string().contains("Another restic-sync"), "{e}");
+    lock.unlock().unwrap();
+    sync(&source, &dest, &flags).await.unwrap();
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn history_lists_the_runs_newest_first() {
+    let state_dir = temp_dir("history");
+    let flags = ["--state-dir", state_dir.to_str().unwrap()];
+    let source = source();
+    let dest = Arc::new(Server::default());
+    sync(&source, &dest, &flags).await.unwrap();
+    let pack = source.add("data", "pack 2");
+    dest.fail(&format!("POST data/{pack}"), StatusCode::BAD_REQUEST, None);
+    sync(&source, &dest, &[flags[0], flags[1], "--file-retries", "0"])
+        .await
+        .unwrap_err();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_restic-sync"))
+        .args(["history", "--json", flags[0], flags[1]])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let runs: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(runs[0]["ok"], false);
+    // A failed run keeps the report of what it copied, which still does
+    // not make it a cancelled one.
+    assert_eq!(runs[0]["outcome"], "failed");
+    assert!(runs[0]["report"].is_object());
+    assert!(runs[0]["error"].as_str().unwrap().contains("400"));
+    assert_eq!(runs[1]["ok"], true);
+    assert_eq!(runs[1]["outcome"], "ok");
+    assert_eq!(runs[1]["report"]["uploaded_files"], 4);
+    assert_eq!(runs[1]["dest"], DEST);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_restic-sync"))
+        .args(["history", flags[0], flags[1]])
+        .output()
+        .unwrap();
+    let lines: Vec<String> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            line.split_whitespace()
+                .nth(3)
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect();
+    assert_eq!(lines, ["failed", "ok"]);
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn history_tells_cancelled_runs_apart() {
+    let state_dir = temp_dir("history-cancelled");
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let cancel = CancellationToken::new();
+    let options = SyncOptions::from_args([
+        "--source",
+        SOURCE,
+        "--dest",
+        DEST,
+        "--state-dir",
+        state_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), dest.clone())
+        .with_observer(Arc::new(CancelAfterFirst(cancel.clone())))
+        .with_cancellation(cancel)
+        .run()
+        .await
+        .unwrap_err();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_restic-sync"))
+        .args([
+            "history",
+            "--json",
+            "--state-dir",
+            state_dir.to_str().unwrap(),
+        ])
+        .output()
+        .unwrap();
+    let runs: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(runs[0]["outcome"], "cancelled");
+    assert_eq!(runs[0]["report"]["uploaded_files"], 1);
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn jobs_from_one_source_download_each_file_once() {
+    let source = source();
+    let pack = source.add("data", "pack 2");
+    let shared = Arc::new(SharedDownloads::new());
+    let job = |dest: &Arc<Server>| {
+        let options = SyncOptions::from_args(["--source", SOURCE, "--dest", DEST]).unwrap();
+        SyncClient::new(options)
+            .unwrap()
+            .with_transport(source.clone(), dest.clone())
+            .with_shared_downloads(shared.clone())
+    };
+    let (first, second) = (Arc::new(Server::default()), Arc::new(Server::default()));
+    let (first_job, second_job) = (job(&first), job(&second));
+
+    let (first_run, second_run) = tokio::join!(first_job.run(), second_job.run());
+
+    assert_eq!(first_run.unwrap().uploaded_files, 5);
+    assert_eq!(second_run.unwrap().uploaded_files, 5);
+    assert_eq!(first.paths(), source.paths());
+    assert_eq!(second.paths(), source.paths());
+    assert_eq!(source.count(&format!("GET data/{pack}")), 1);
+}
+
+#[tokio::test]
+async fn a_destination_that_fails_keeps_its_watermark_while_the_other_moves_on() {
+    let state_dir = temp_dir("watermarks");
+    let source = source();
+    let (mirror, offsite) = (Arc::new(Server::default()), Arc::new(Server::default()));
+    let run = |dest_url: &str, dest: &Arc<Server>| {
+        let options = SyncOptions::from_args([
+            "--source",
+            SOURCE,
+            "--dest",
+            dest_url,
+            "--state-dir",
+            state_dir.to_str().unwrap(),
+            "--file-retries",
+            "0",
+        ])
+        .unwrap();
+        let job = SyncClient::new(options)
+            .unwrap()
+            .with_transport(source.clone(), dest.clone());
+        async move { job.run().await }
+    };
+    run(DEST, &mirror).await.unwrap();
+    run("http://offsite.test/", &offsite).await.unwrap();
+    let snapshot = source.add("snapshots", "snapshot 2");
+    offsite.fail(
+        &format!("POST snapshots/{snapshot}"),
+        StatusCode::BAD_REQUEST,
+        None,
+    );
+    run(DEST, &mirror).await.unwrap();
+    run("http://offsite.test/", &offsite).await.unwrap_err();
+
+    let watermarks = || {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_restic-sync"))
+            .args(["history", "--watermarks", "--json", "--state-dir"])
+            .arg(&state_dir)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+    let behind = watermarks();
+    assert_eq!(behind[0]["dest"], DEST);
+    assert_eq!(behind[0]["snapshots"], 2);
+    assert_eq!(behind[0]["behind_snapshots"], 0);
+    assert_eq!(behind[1]["dest"], "http://offsite.test/");
+    assert_eq!(behind[1]["snapshots"], 1);
+    assert_eq!(behind[1]["behind_snapshots"], 1);
+
+    run("http://offsite.test/", &offsite).await.unwrap();
+    assert_eq!(watermarks()[1]["behind_snapshots"], 0);
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+#[tokio::test]
+async fn matching_config_is_accepted() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    dest.add_named("config", "source config");
+
+    sync(&source, &dest, &[]).await.unwrap();
+
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn later_runs_of_a_job_only_check_the_config_with_head_requests() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let options = SyncOptions::from_args(["--source", SOURCE, "--dest", DEST]).unwrap();
+    let job = SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), dest.clone());
+    job.run().await.unwrap();
+    job.run().await.unwrap();
+    assert_eq!(source.count("GET config"), 1);
+    assert_eq!(source.count("HEAD config"), 1);
+
+    dest.add_named("config", "other config");
+    let error = job.run().await.unwrap_err();
+
+    assert!(matches!(
+        error.downcast_ref::<Error>(),
+        Some(Error::ConfigMismatch)
+    ));
+}
+
+#[tokio::test]
+async fn mismatched_config_aborts_before_copying() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    dest.add_named("config", "another repository");
+
+    let error = sync(&source, &dest, &[]).await.unwrap_err();
+
+    assert!(
+        matches!(error.downcast_ref(), Some(Error::ConfigMismatch)),
+        "{error:?}"
+    );
+    assert_eq!(exit_code(&error), 3);
+    assert_eq!(dest.paths(), ["config"]);
+}
+
+#[tokio::test]
+async fn missing_destination_fails_with_no_create_dest() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    dest.fail("GET keys/", StatusCode::NOT_FOUND, None);
+
+    let error = sync(&source, &dest, &["--no-create-dest"])
+        .await
+        .unwrap_err();
+
+    assert!(
+        matches!(error.downcast_ref(), Some(Error::DestinationMissing { .. })),
+        "{error:?}"
+    );
+    assert_eq!(dest.count("POST "), 0);
+    assert!(dest.paths().is_empty());
+}
+
+#[tokio::test]
+async fn server_errors_are_retried() {
+    let source = source();
+    let name = source.add("data", "pack 2");
+    let dest = Arc::new(Server::default());
+    let download = format!("GET data/{name}");
+    source.fail(&download, StatusCode::INTERNAL_SERVER_ERROR, None);
+
+    let report = sync(&source, &dest, &[]).await.unwrap();
+
+    assert_eq!(source.count(&download), 2);
+    assert_eq!(report.uploaded_files, 5);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn throttled_requests_are_retried_after_the_pause() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    dest.fail("POST config", StatusCode::TOO_MANY_REQUESTS, Some("0"));
+    dest.fail("POST config", StatusCode::SERVICE_UNAVAILABLE, Some("0"));
+
+    sync(&source, &dest, &[]).await.unwrap();
+
+    assert_eq!(dest.count("POST config"), 3);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn persistent_errors_fail_the_sync() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    dest.fail("GET data/", StatusCode::BAD_REQUEST, None);
+
+    let error = sync(&source, &dest, &[]).await.unwrap_err();
+
+    assert!(
+        matches!(
+            error.downcast_ref(),
+            Some(Error::Http { status: StatusCode::BAD_REQUEST, url, .. }) if url == "http://dest.test/data/"
+        ),
+        "{error:?}"
+    );
+    // Client errors are not retried.
+    assert_eq!(dest.count("GET data/"), 1);
+}
+
+#[tokio::test]
+async fn builder_sets_up_the_job() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let extra = dest.add("snapshots", "forgotten snapshot");
+
+    let report = SyncJobBuilder::new(SOURCE, DEST)
+        .only_types(["config", "snapshots"])
+        .prune(true)
+        .build()
+        .unwrap()
+        .with_transport(source.clone(), dest.clone())
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!((report.uploaded_files, report.deleted_files), (1, 1));
+    assert!(!dest.paths().contains(&format!("snapshots/{extra}")));
+    assert!(
+        dest.paths()
+            .iter()
+            .all(|p| p == "config" || p.starts_with("snapshots/"))
+    );
+}
+
+#[test]
+fn builder_rejects_what_the_command_line_does() {
+    let pruning_append_only = SyncJobBuilder::new(SOURCE, DEST)
+        .prune(true)
+        .dest_append_only(true)
+        .build();
+    assert!(pruning_append_only.is_err());
+
+    let unknown_type = SyncJobBuilder::new(SOURCE, DEST).include("packs/0").build();
+    assert!(unknown_type.unwrap_err().to_string().contains("packs"));
+
+    let uncached_reconcile = SyncJobBuilder::new(SOURCE, DEST).reconcile(true).build();
+    assert!(uncached_reconcile.is_err());
+}
+
+#[tokio::test]
+async fn plan_lists_without_writing() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    dest.add("keys", "key");
+    let extra = dest.add("data", "pack that was pruned");
+    let options = SyncOptions::from_args(["--source", SOURCE, "--dest", DEST, "--prune"]).unwrap();
+    let client = SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), dest.clone());
+
+    let plan = client.plan().await.unwrap();
+
+    assert_eq!(dest.writes(), 0);
+    assert_eq!(dest.count("POST "), 0);
+    let data = plan.types.iter().find(|t| t.file_type == "data").unwrap();
+    assert_eq!(data.uploads.len(), 1);
+    assert_eq!(
+        data.deletes,
+        [PlannedFile {
+            name: extra,
+            size: 20
+        }]
+    );
+    let keys = plan.types.iter().find(|t| t.file_type == "keys").unwrap();
+    assert_eq!((keys.uploads.len(), keys.unchanged_files), (0, 1));
+    assert_eq!((plan.upload_files(), plan.delete_files()), (3, 1));
+}
+
+#[tokio::test]
+async fn plan_stream_yields_each_type_before_listing_the_next() {
+    let source = source();
+    let pack = source.add("data", "pack 2");
+    let dest = Arc::new(Server::default());
+    let client = SyncClient::new(SyncOptions::new(SOURCE, DEST))
+        .unwrap()
+        .with_transport(source.clone(), dest.clone());
+    let mut actions = pin!(client.plan_stream());
+
+    let first = actions.next().await.unwrap().unwrap();
+
+    assert_eq!(
+        (first.action, first.file_type.as_str()),
+        (FileAction::Upload, "data")
+    );
+    assert_eq!(source.count("GET keys/"), 0);
+    let mut names = vec![first.name];
+    while let Some(action) = actions.next().await {
+        names.push(action.unwrap().name);
+    }
+    assert_eq!(names.len(), 5);
+    assert!(names.contains(&pack));
+}
+
+#[tokio::test]
+async fn executing_a_plan_applies_only_the_plan() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let client = SyncClient::new(SyncOptions::new(SOURCE, DEST))
+        .unwrap()
+        .with_transport(source.clone(), dest.clone());
+    let plan = client.plan().await.unwrap();
+    // A round trip, as for a plan that waited for approval.
+    let plan: SyncPlan = serde_json::from_str(&serde_json::to_string(&plan).unwrap()).unwrap();
+    let later = source.add("data", "pack 2");
+
+    let report = client.execute(&plan).await.unwrap();
+
+    assert_eq!(report.uploaded_files, 4);
+    assert!(!dest.paths().contains(&format!("data/{later}")));
+    assert_eq!(dest.paths().len(), source.paths().len() - 1);
+}
+
+/// The names of the fields that `schema` requires at its top level.
+fn required_fields(schema: Schema) -> Vec<String> {
+    let document = restic_sync::schema::document(schema);
+    serde_json::from_value(document["required"].clone()).unwrap()
+}
+
+fn field_names(value: &serde_json::Value) -> Vec<String> {
+    value.as_object().unwrap().keys().cloned().collect()
+}
+
+#[tokio::test]
+async fn reports_serialize_as_their_schema_describes() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+
+    let report = sync(&source, &dest, &[]).await.unwrap();
+
+    let report = serde_json::to_value(&report).unwrap();
+    assert_eq!(field_names(&report), required_fields(Schema::Report));
+    assert_eq!(report["types"][0]["file_type"], "data");
+}
+
+#[tokio::test]
+async fn plans_carry_the_version_of_their_format() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let client = SyncClient::new(SyncOptions::new(SOURCE, DEST))
+        .unwrap()
+        .with_transport(source.clone(), dest.clone());
+
+    let mut plan = serde_json::to_value(client.plan().await.unwrap()).unwrap();
+
+    assert_eq!(field_names(&plan), required_fields(Schema::Plan));
+    assert_eq!(plan["version"], restic_sync::schema::VERSION);
+    plan["version"] = (restic_sync::schema::VERSION + 1).into();
+    let newer = serde_json::from_value::<SyncPlan>(plan).unwrap_err();
+    assert!(newer.to_string().contains("newer"));
+}
+
+#[test]
+fn blocking_client_needs_no_runtime() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let client = SyncClient::new(SyncOptions::new(SOURCE, DEST))
+        .unwrap()
+        .with_transport(source.clone(), dest.clone());
+    let client = restic_sync::blocking::SyncClient::new(client).unwrap();
+
+    assert_eq!(client.plan().unwrap().upload_files(), 4);
+    assert_eq!(client.run().unwrap().uploaded_files, 4);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn corrupt_files_are_not_uploaded() {
+    let source = source();
+    let name = format!("{:x}", Sha256::digest("pack 2"));
+    source.add_named(&format!("data/{name}"), "pack X");
+    let dest = Arc::new(Server::default());
+
+    let error = sync(&source, &dest, &["--file-retry-delay", "0s"])
+        .await
+        .unwrap_err();
+
+    assert_eq!(exit_code(&error), 4);
+    // Downloaded again twice past the caches, but not retried after that.
+    assert_eq!(source.count(&format!("GET data/{name}")), 3);
+    let Some(Failed(report)) = error.downcast_ref() else {
+        panic!("{error:?}");
+    };
+    match report.failures[0].error.downcast_ref() {
+        Some(Error::VerificationFailed {
+            expected, actual, ..
+        }) => {
+            assert_eq!(expected, &name);
+            assert_eq!(actual, &format!("{:x}", Sha256::digest("pack X")));
+        }
+        _ => panic!("{error:?}"),
+    }
+    assert!(!dest.paths().contains(&format!("data/{name}")));
+}
+
+#[tokio::test]
+async fn garbled_downloads_are_downloaded_again() {
+    let source = source();
+    let pack = source.add("data", "pack 2");
+    // An empty body, as a proxy may answer.
+    source.fail(&format!("GET data/{pack}"), StatusCode::OK, None);
+    let dest = Arc::new(Server::default());
+
+    let report = sync(&source, &dest, &["--file-retries", "0"])
+        .await
+        .unwrap();
+
+    assert_eq!(report.uploaded_files, 5);
+    assert_eq!(source.count(&format!("GET data/{pack}")), 2);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn stalled_downloads_are_tried_again() {
+    let source = source();
+    let pack = source.add("data", "pack 2");
+    source.stall(&format!("GET data/{pack}"), "pack 2");
+    let dest = Arc::new(Server::default());
+
+    let flags = ["--stall-timeout", "100ms", "--file-retry-delay", "0s"];
+    let report = sync(&source, &dest, &flags).await.unwrap();
+
+    assert_eq!(report.uploaded_files, 5);
+    assert_eq!(source.count(&format!("GET data/{pack}")), 2);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn downloads_that_never_answer_are_tried_again() {
+    let source = source();
+    let pack = source.add("data", "pack 2");
+    source.hang(&format!("GET data/{pack}"));
+    let dest = Arc::new(Server::default());
+
+    let flags = ["--stall-timeout", "100ms", "--file-retry-delay", "0s"];
+    let report = sync(&source, &dest, &flags).await.unwrap();
+
+    assert_eq!(report.uploaded_files, 5);
+    assert_eq!(source.count(&format!("GET data/{pack}")), 2);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn listings_that_never_answer_are_requested_again() {
+    let source = source();
+    source.hang("GET keys/");
+    let dest = Arc::new(Server::default());
+
+    let report = sync(&source, &dest, &["--stall-timeout", "100ms"])
+        .await
+        .unwrap();
+
+    assert_eq!(report.uploaded_files, 4);
+    assert_eq!(source.count("GET keys/"), 2);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn head_requests_that_never_answer_are_sent_again() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let pack = source.add("data", "pack 2");
+    dest.add_named(&format!("data/{pack}"), "pac");
+    dest.fail(&format!("POST data/{pack}"), StatusCode::FORBIDDEN, None);
+    dest.hang(&format!("HEAD data/{pack}"));
+
+    let flags = ["--stall-timeout", "100ms", "--file-retries", "0"];
+    let report = sync(&source, &dest, &flags).await.unwrap();
+
+    assert_eq!(report.uploaded_files, 5);
+    assert_eq!(dest.count(&format!("HEAD data/{pack}")), 2);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn stalled_listings_are_requested_again() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    source.stall("GET data/", r#"[{"name":"ab","size":1}]"#);
+
+    let report = sync(&source, &dest, &["--stall-timeout", "100ms"])
+        .await
+        .unwrap();
+
+    assert_eq!(source.count("GET data/"), 2);
+    assert_eq!(report.uploaded_files, 4);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn slow_listings_are_not_taken_as_stalled() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let pack = format!("{:x}", Sha256::digest("pack 1"));
+    let listing = serde_json::json!([{"name": pack, "size": 6}]).to_string();
+    // Five pieces 100ms apart, so more than the stall timeout as a whole.
+    source.trickle("GET data/", &listing, Duration::from_millis(100));
+
+    let report = sync(&source, &dest, &["--stall-timeout", "300ms"])
+        .await
+        .unwrap();
+
+    assert_eq!(source.count("GET data/"), 1);
+    assert_eq!(report.uploaded_files, 4);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn truncated_listings_are_requested_again() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    source.truncate("GET data/", r#"[{"name":"ab"#);
+
+    let report = sync(&source, &dest, &[]).await.unwrap();
+
+    assert_eq!(source.count("GET data/"), 2);
+    assert_eq!(report.uploaded_files, 4);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn listings_that_stay_truncated_fail_the_run() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    for _ in 0..3 {
+        source.truncate("GET data/", r#"[{"name":"ab"#);
+    }
+
+    let error = sync(&source, &dest, &[]).await.unwrap_err();
+    let message = format!("{error:#}");
+    assert!(message.contains("after 3 tries"), "{message}");
+    assert!(
+        message.contains(r#"12 bytes, "[{\"name\":\"ab""#),
+        "{message}"
+    );
+    assert_eq!(source.count("GET data/"), 3);
+}
+
+#[tokio::test]
+async fn web_pages_in_place_of_the_api_are_named() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    source.intercept(
+        "GET data/",
+        "<!DOCTYPE html>\n<html><head><title>Guest Wi-Fi</title></head><body>Please log in</body></html>",
+    );
+    let error = sync(&source, &dest, &[]).await.unwrap_err();
+    let message = format!("{error:#}");
+    assert!(
+        message.contains("answered with a captive portal or login page (\"Guest Wi-Fi\")"),
+        "{message}"
+    );
+
+    let pack = source.add("data", "pack 2");
+    source.intercept(
+        &format!("GET data/{pack}"),
+        "<html><head><title>502 Bad Gateway</title></head><body><center>nginx</center></body></html>",
+    );
+    let error = sync(&source, &dest, &["--file-retries", "0"])
+        .await
+        .unwrap_err();
+    let Some(Failed(report)) = error.downcast_ref() else {
+        panic!("{error:?}");
+    };
+    let failure = report.failures[0].error.to_string();
+    assert!(
+        failure.contains("answered with the error page of a proxy (\"502 Bad Gateway\")"),
+        "{failure}"
+    );
+}
+
+#[tokio::test]
+async fn size_only_verification_does_not_hash() {
+    let source = source();
+    let name = format!("{:x}", Sha256::digest("pack 2"));
+    source.add_named(&format!("data/{name}"), "pack X");
+    let dest = Arc::new(Server::default());
+
+    sync(&source, &dest, &["--type-verification", "data=size-only"])
+        .await
+        .unwrap();
+
+    assert!(dest.paths().contains(&format!("data/{name}")));
+}
+
+#[tokio::test]
+async fn readback_downloads_each_upload_again() {
+    let source = source();
+    let data = source.add("data", "pack 2");
+    let key = format!("keys/{:x}", Sha256::digest("key"));
+    let dest = Arc::new(Server::default());
+    let options = SyncOptions::new(SOURCE, DEST)
+        .with_verification(Verification::None)
+        .with_type_verification("data", Verification::Sha256PlusReadback);
+
+    SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), dest.clone())
+        .run()
+        .await
+        .unwrap();
+
+    assert_eq!(dest.count(&format!("GET data/{data}")), 1);
+    assert_eq!(dest.count(&format!("GET {key}")), 0);
+}
+
+#[tokio::test]
+async fn observers_hear_about_every_file() {
+    let source = Arc::new(Server::default());
+    let data = source.add("data", "pack 1");
+    let dest = Arc::new(Server::default());
+    let extra = dest.add("data", "pack that was pruned");
+    let recorder = Arc::new(Recorder::default());
+    let options = SyncOptions::from_args(["--source", SOURCE, "--dest", DEST, "--prune"]).unwrap();
+
+    SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), dest.clone())
+        .with_observer(recorder.clone())
+        .run()
+        .await
+        .unwrap();
+
+    let events = recorder.events();
+    assert_eq!(
+        events[..5],
+        [
+            format!("start upload data/{}", &data[..8]),
+            format!("complete upload data/{}", &data[..8]),
+            format!("start delete data/{}", &extra[..8]),
+            format!("complete delete data/{}", &extra[..8]),
+            "done data 1/6/1".to_string(),
+        ]
+    );
+    // The other types had nothing to do.
+    assert_eq!(
+        events[5..],
+        [
+            "done keys 0/0/0",
+            "done locks 0/0/0",
+            "done snapshots 0/0/0",
+            "done index 0/0/0"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn observers_hear_about_failed_files() {
+    let source = Arc::new(Server::default());
+    let data = source.add("data", "pack 1");
+    source.fail(&format!("GET data/{data}"), StatusCode::NOT_FOUND, None);
+    let dest = Arc::new(Server::default());
+    let recorder = Arc::new(Recorder::default());
+    let options =
+        SyncOptions::from_args(["--source", SOURCE, "--dest", DEST, "--file-retries", "0"])
+            .unwrap();
+
+    SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), dest.clone())
+        .with_observer(recorder.clone())
+        .run()
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        recorder.events()[..2],
+        [
+            format!("start upload data/{}", &data[..8]),
+            format!("error upload data/{}", &data[..8]),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn a_failed_file_does_not_stop_the_others() {
+    let source = source();
+    let pack = source.add("data", "pack 2");
+    let dest = Arc::new(Server::default());
+    dest.fail(&format!("POST data/{pack}"), StatusCode::BAD_REQUEST, None);
+
+    let error = sync(&source, &dest, &["--file-retries", "0"])
+        .await
+        .unwrap_err();
+
+    let Some(Failed(report)) = error.downcast_ref() else {
+        panic!("{error:?}");
+    };
+    assert_eq!(report.uploaded_files, 2);
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].file_type, "data");
+    assert_eq!(report.failures[0].name, pack);
+    assert_eq!(exit_code(&error), 1);
+    assert!(!dest.paths().contains(&format!("data/{pack}")));
+    // The other types go on, but the snapshot and index that may refer to
+    // the missing pack are held back.
+    assert!(dest.paths().iter().any(|path| path.starts_with("keys/")));
+    assert!(
+        !dest
+            .paths()
+            .iter()
+            .any(|path| path.starts_with("snapshots/") || path.starts_with("index/"))
+    );
+    assert_eq!(report.errors.len(), 2, "{:?}", report.errors);
+}
+
+#[tokio::test]
+async fn failed_files_are_tried_again() {
+    let source = source();
+    let pack = source.add("data", "pack 2");
+    let dest = Arc::new(Server::default());
+    dest.fail(&format!("POST data/{pack}"), StatusCode::BAD_REQUEST, None);
+    dest.fail(&format!("POST data/{pack}"), StatusCode::BAD_REQUEST, None);
+
+    let report = sync(&source, &dest, &["--file-retry-delay", "0s"])
+        .await
+        .unwrap();
+
+    assert_eq!(report.uploaded_files, 5);
+    assert_eq!(dest.count(&format!("POST data/{pack}")), 3);
+    // The verified download is uploaded again rather than downloaded again.
+    assert_eq!(source.count(&format!("GET data/{pack}")), 1);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn permanent_errors_are_not_tried_again() {
+    let source = source();
+    let pack = source.add("data", "pack 2");
+    let dest = Arc::new(Server::default());
+    dest.fail(&format!("POST data/{pack}"), StatusCode::FORBIDDEN, None);
+
+    let error = sync(&source, &dest, &["--file-retry-delay", "0s"])
+        .await
+        .unwrap_err();
+
+    let Some(Failed(report)) = error.downcast_ref() else {
+        panic!("{error:?}");
+    };
+    let failure = report.failures[0].error.to_string();
+    assert!(
+        failure.contains("403 Forbidden (permanent, not retried)"),
+        "{failure}"
+    );
+    assert_eq!(dest.count(&format!("POST data/{pack}")), 1);
+}
+
+#[tokio::test]
+async fn partial_uploads_that_cannot_be_overwritten_are_deleted_first() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let pack = source.add("data", "pack 2");
+    dest.add_named(&format!("data/{pack}"), "pac");
+    // As rest-server refuses to overwrite files.
+    dest.fail(&format!("POST data/{pack}"), StatusCode::FORBIDDEN, None);
+
+    let report = sync(&source, &dest, &["--file-retries", "0"])
+        .await
+        .unwrap();
+
+    assert_eq!(report.uploaded_files, 5);
+    assert_eq!(dest.count(&format!("DELETE data/{pack}")), 1);
+    assert_eq!(dest.count(&format!("POST data/{pack}")), 2);
+    assert_eq!(dest.paths(), source.paths());
+}
+
+#[tokio::test]
+async fn too_many_failures_abort_the_run() {
+    let source = source();
+    let packs = [source.add("data", "pack 2"), source.add("data", "pack 3")];
+    let dest = Arc::new(Server::default());
+    for pack in &packs {
+        dest.fail(&format!("POST data/{pack}"), StatusCode::BAD_REQUEST, None);
+    }
+
+    let error = sync(
+        &source,
+        &dest,
+        &["--file-retries", "0", "--max-failures", "2"],
+    )
+    .await
+    .unwrap_err();
+
+    assert!(error.to_string().contains("--max-failures"), "{error}");
+    let Some(Failed(report)) = error.downcast_ref() else {
+        panic!("{error:?}");
+    };
+    assert_eq!(report.failures.len(), 2);
+    assert!(
+        dest.paths()
+            .iter()
+            .all(|path| !path.starts_with("snapshots/"))
+    );
+}
+
+#[tokio::test]
+async fn a_full_destination_stops_the_run() {
+    let source = source();
+    source.add("data", "pack 2");
+    let dest = Arc::new(Server::default());
+    let packs: Vec<String> = source
+        .paths()
+        .into_iter()
+        .filter(|path| path.starts_with("data/"))
+        .collect();
+    for pack in &packs {
+        dest.fail(
+            &format!("POST {pack}"),
+            StatusCode::INSUFFICIENT_STORAGE,
+            None,
+        );
+    }
+
+    let error = sync(&source, &dest, &[]).await.unwrap_err();
+
+    assert_eq!(exit_code(&error), 6);
+    assert!(error.to_string().contains("out of space"), "{error}");
+    let Some(Failed(report)) = error.downcast_ref() else {
+        panic!("{error:?}");
+    };
+    assert_eq!(report.failures.len(), 1);
+    let posts = packs.iter().map(|pack| dest.count(&format!("POST {pack}")));
+    assert_eq!(posts.sum::<usize>(), 1);
+    assert!(dest.paths().iter().all(|path| !path.starts_with("keys/")));
+}
+
+#[tokio::test]
+async fn a_panic_fails_the_run_it_happened_in() {
+    let state_dir = temp_dir("panic");
+    let flags = ["--state-dir", state_dir.to_str().unwrap()];
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let options =
+        SyncOptions::from_args(["--source", SOURCE, "--dest", DEST].iter().chain(&flags)).unwrap();
+
+    let error = SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), Arc::new(Panicking(dest.clone())))
+        .run()
+        .await
+        .unwrap_err();
+
+    assert_eq!(error.to_string(), "The sync panicked: bug in the transport");
+    // The run let go of the state directory.
+    sync(&source, &dest, &flags).await.unwrap();
+    assert_eq!(dest.paths(), source.paths());
+    std::fs::remove_dir_all(&state_dir).unwrap();
+}
+
+/// Cancels a sync once it has uploaded a file.
+struct CancelAfterFirst(CancellationToken);
+
+impl SyncObserver for CancelAfterFirst {
+    fn on_file_complete(&self, _file: &FileEvent, _bytes: u64) {
+        self.0.cancel();
+    }
+}
+
+#[tokio::test]
+async fn cancelled_sync_returns_what_it_copied() {
+    let source = source();
+    source.add("data", "pack 2");
+    source.add("data", "pack 3");
+    let dest = Arc::new(Server::default());
+    let cancel = CancellationToken::new();
+    let options = SyncOptions::from_args(["--source", SOURCE, "--dest", DEST]).unwrap();
+
+    let error = SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), dest.clone())
+        .with_observer(Arc::new(CancelAfterFirst(cancel.clone())))
+        .with_cancellation(cancel)
+        .run()
+        .await
+        .unwrap_err();
+
+    let Cancelled(report) = error.downcast_ref::<Cancelled>().unwrap();
+    assert_eq!(report.uploaded_files, 1);
+    assert_eq!(dest.paths().len(), 2, "{:?}", dest.paths());
+}
+
+/// Takes its time over the keys, and cancels the sync once it has uploaded
+/// a data file.
+struct CancelInData(CancellationToken);
+
+impl SyncObserver for CancelInData {
+    fn on_file_complete(&self, file: &FileEvent, _bytes: u64) {
+        match file.file_type {
+            "keys" => std::thread::sleep(Duration::from_millis(200)),
+            "data" => self.0.cancel(),
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_cancelled_report_covers_the_whole_run() {
+    let source = source();
+    let dest = Arc::new(Server::default());
+    let cancel = CancellationToken::new();
+    let options = SyncOptions::from_args([
+        "--source",
+        SOURCE,
+        "--dest",
+        DEST,
+        "--type-order",
+        "keys,config",
+    ])
+    .unwrap();
+
+    let error = SyncClient::new(options)
+        .unwrap()
+        .with_transport(source.clone(), dest.clone())
+        .with_observer(Arc::new(CancelInData(cancel.clone())))
+        .with_cancellation(cancel)
+        .run()
+        .await
+        .unwrap_err();
+
+    let Cancelled(report) = error.downcast_ref::<Cancelled>().unwrap();
+    assert_eq!(report.types[0].file_type, "keys");
+    assert!(
+        report.duration >= Duration::from_millis(200),
+        "{:?}",
+        report.duration
+    );
+}